@@ -0,0 +1,76 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Used if config.json does not exist, so that a fresh checkout builds
+// and works out of the box as a plain DoH proxy
+const DEFAULT_CONFIG: &str = r#"{
+    "upstream_urls": [
+        "https://cloudflare-dns.com/dns-query"
+    ],
+    "retries": 3
+}
+"#;
+
+fn main() {
+    println!("cargo:rerun-if-changed=config.json");
+
+    let out_dir = env::var("OUT_DIR").unwrap();
+    generate_blocklists(Path::new(&out_dir));
+    copy_hosts(Path::new(&out_dir));
+    let dest = Path::new(&out_dir).join("config.json");
+    let config = match fs::read_to_string("config.json") {
+        Ok(config) => config,
+        Err(_) => {
+            println!("cargo:warning=config.json not found, using the default config");
+            DEFAULT_CONFIG.to_string()
+        }
+    };
+    fs::write(dest, config).unwrap();
+}
+
+// Blocklists are compiled in by category: blocklist.txt is "default", and
+// every blocklists/<category>.txt is its own category (e.g. ads, trackers),
+// all of them optional
+// This generates the list of (category, content) pairs for override.rs
+fn generate_blocklists(out_dir: &Path) {
+    println!("cargo:rerun-if-changed=blocklist.txt");
+    println!("cargo:rerun-if-changed=blocklists");
+
+    let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let mut sources = Vec::new();
+    if Path::new("blocklist.txt").exists() {
+        sources.push(("default".to_string(), manifest_dir.join("blocklist.txt")));
+    }
+    if let Ok(entries) = fs::read_dir("blocklists") {
+        for entry in entries {
+            let path = entry.unwrap().path();
+            if path.extension().map_or(false, |ext| ext == "txt") {
+                let category = path.file_stem().unwrap().to_string_lossy().to_string();
+                sources.push((category, manifest_dir.join(&path)));
+            }
+        }
+    }
+    // Keep the output stable across builds
+    sources.sort();
+
+    let mut code = "&[\n".to_string();
+    for (category, path) in sources {
+        println!("cargo:rerun-if-changed={}", path.display());
+        code += &format!(
+            "    ({:?}, include_str!({:?})),\n",
+            category,
+            path.to_str().unwrap()
+        );
+    }
+    code += "]\n";
+    fs::write(out_dir.join("blocklists.rs"), code).unwrap();
+}
+
+// hosts.txt is optional as well; without it, there are no static hosts
+fn copy_hosts(out_dir: &Path) {
+    println!("cargo:rerun-if-changed=hosts.txt");
+
+    let hosts = fs::read_to_string("hosts.txt").unwrap_or_default();
+    fs::write(out_dir.join("hosts.txt"), hosts).unwrap();
+}
@@ -14,8 +14,11 @@ function import_hosts() {
 #)
 . ./blocklist_config.sh
 
-echo "" > blocklist.txt
+# Pass e.g. blocklists/ads.txt to generate a categorized list instead
+OUTPUT=${1:-blocklist.txt}
+
+echo "" > "$OUTPUT"
 for url in ${URLS[@]}; do
     echo "Importing $url"
-    import_hosts $url >> blocklist.txt
+    import_hosts $url >> "$OUTPUT"
 done
\ No newline at end of file
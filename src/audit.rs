@@ -0,0 +1,87 @@
+use crate::client::QueryResult;
+use crate::kv;
+use domain::base::{Dname, Question};
+use js_sys::Date;
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::JsValue;
+use wasm_bindgen_futures::future_to_promise;
+use web_sys::{ExtendableEvent, Request};
+
+// Options for the audit log
+// PRIVACY NOTE: when enabled, every query is persisted to the AUDIT_LOG KV
+// namespace together with the client's IP address, i.e. what each client
+// looks up is recorded for up to `ttl` seconds. Only enable this where
+// users are aware of it and it is permitted by applicable regulations.
+#[derive(Deserialize, Serialize)]
+pub struct AuditLogOptions {
+    // How long to keep each log entry, in seconds (KV requires >= 60)
+    ttl: u64,
+}
+
+#[derive(Serialize)]
+struct AuditLogQuestion {
+    qname: String,
+    qtype: String,
+}
+
+#[derive(Serialize)]
+struct AuditLogEntry {
+    ts: u64, // milliseconds
+    client_ip: Option<String>,
+    questions: Vec<AuditLogQuestion>,
+    upstream: Option<String>,
+    rcode: String,
+    cache_hit: bool,
+}
+
+pub struct AuditLog {
+    ttl: u64,
+}
+
+impl AuditLog {
+    pub fn new(options: AuditLogOptions) -> AuditLog {
+        AuditLog { ttl: options.ttl }
+    }
+
+    // Write an entry for a query to KV in the background (via waitUntil),
+    // so that logging doesn't slow down the response
+    pub fn log(
+        &self,
+        ev: &ExtendableEvent,
+        req: &Request,
+        questions: &[Question<Dname<Vec<u8>>>],
+        result: &QueryResult,
+    ) {
+        let ts = Date::now() as u64;
+        let entry = AuditLogEntry {
+            ts,
+            client_ip: req.headers().get("CF-Connecting-IP").unwrap_or(None),
+            questions: questions
+                .iter()
+                .map(|q| AuditLogQuestion {
+                    qname: q.qname().to_string(),
+                    qtype: crate::util::rtype_to_string(q.qtype()),
+                })
+                .collect(),
+            upstream: result.upstream.clone(),
+            rcode: result.rcode.to_string(),
+            cache_hit: result.cache_hit,
+        };
+        let value = match serde_json::to_vec(&entry) {
+            Ok(v) => v,
+            Err(_) => return,
+        };
+        // Keys are ordered by timestamp; the random suffix avoids collisions
+        let key = format!("{};{}", ts, crate::util::random_range(0, u32::MAX));
+        let ttl = self.ttl;
+
+        let promise = future_to_promise(async move {
+            // Ignore error -- logging is best-effort
+            let _ = kv::get_audit_log()
+                .put_buf_ttl_metadata(&key, &value, ttl, ())
+                .await;
+            Ok(JsValue::UNDEFINED)
+        });
+        let _ = ev.wait_until(&promise);
+    }
+}
@@ -1,8 +1,9 @@
 use crate::kv;
-use crate::util::OwnedRecordData;
+use crate::util::{OwnedRecordData, TtlClamp};
 use domain::base::{Dname, Question, Record};
 use js_sys::Date;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
 #[derive(Deserialize, Serialize)]
 struct DnsCacheMetadata {
@@ -10,28 +11,190 @@ struct DnsCacheMetadata {
     ttl: u32,
 }
 
+// Metadata of a batched entry; ttls[i] belongs to the i-th record in the value
+#[derive(Deserialize, Serialize)]
+struct DnsCacheBatchMetadata {
+    created_ts: u64, // seconds
+    ttls: Vec<u32>,
+}
+
+// How records are laid out in KV
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CacheMode {
+    // One KV entry per record (the original layout)
+    PerRecord,
+    // One KV entry per question holding all of its records, which costs
+    // only one KV write (and no list operation on read) per question
+    PerQuestion,
+}
+
+impl Default for CacheMode {
+    fn default() -> CacheMode {
+        CacheMode::PerRecord
+    }
+}
+
+// Result of a cache lookup
+pub enum CacheLookup {
+    // The records, and how long ago (in seconds) the oldest was stored
+    Hit(Vec<Record<Dname<Vec<u8>>, OwnedRecordData>>, u64),
+    Miss,
+    // The KV store itself failed, e.g. during a KV outage
+    Unavailable,
+}
+
+// TTL of stale records served when upstream is failing (RFC 8767)
+const STALE_SERVE_TTL: u32 = 30;
+
+// The TTL a record is cached with: the global clamp is applied first,
+// and then the cap for its type (if any), which is more specific
+fn stored_ttl(
+    ttl_clamp: &TtlClamp,
+    ttl_by_type: &HashMap<u16, u32>,
+    record: &Record<Dname<Vec<u8>>, OwnedRecordData>,
+) -> u32 {
+    let ttl = ttl_clamp.apply(record.ttl());
+    match ttl_by_type.get(&record.rtype().to_int()) {
+        Some(cap) => ttl.min(*cap),
+        None => ttl,
+    }
+}
+
 pub struct DnsCache {
     store: kv::KvNamespace,
+    ttl_clamp: TtlClamp,
+    // Per-type TTL caps, keyed by the numeric record type
+    ttl_by_type: HashMap<u16, u32>,
+    // How long records are kept past their TTL for stale-if-error (seconds)
+    stale_ttl: u64,
+    mode: CacheMode,
+    // Maximum number of KV writes in flight at once
+    write_concurrency: usize,
 }
 
 impl DnsCache {
-    pub fn new() -> DnsCache {
-        DnsCache {
+    // Prepended to all keys as `v<N>;`, so that entries written in an older
+    // format are never read back: they simply miss, and expire on their own
+    // Bump this whenever the keys or the stored values (including metadata)
+    // change incompatibly; there is no need to clear the KV namespace then
+    // Entries from before versioning (no prefix at all) count as version 1
+    const VERSION: u32 = 2;
+
+    pub fn new(
+        ttl_clamp: TtlClamp,
+        ttl_by_type: HashMap<String, u32>,
+        stale_ttl: u64,
+        mode: CacheMode,
+        write_concurrency: usize,
+    ) -> Result<DnsCache, String> {
+        let mut ttl_caps = HashMap::new();
+        for (k, v) in ttl_by_type.into_iter() {
+            ttl_caps.insert(crate::util::parse_qtype(&k)?.to_int(), v);
+        }
+        Ok(DnsCache {
             store: kv::get_dns_cache(),
+            ttl_clamp,
+            ttl_by_type: ttl_caps,
+            stale_ttl,
+            mode,
+            write_concurrency,
+        })
+    }
+
+    // Cache all answers of a query, in the layout selected by `mode`
+    // Writes run concurrently, but at most `write_concurrency` at once
+    // so that we don't run into the subrequest limits of Workers
+    #[allow(unused_must_use)]
+    pub async fn put_answers(&self, answers: &[Record<Dname<Vec<u8>>, OwnedRecordData>]) {
+        match self.mode {
+            CacheMode::PerRecord => {
+                crate::util::for_each_concurrent(answers, self.write_concurrency, |a| async move {
+                    // Ignore error -- we don't really care
+                    self.put_cache(a).await;
+                })
+                .await;
+            }
+            CacheMode::PerQuestion => {
+                // Group records by the question they answer, keeping the order
+                let mut keys: Vec<String> = Vec::new();
+                let mut groups: HashMap<String, Vec<&Record<Dname<Vec<u8>>, OwnedRecordData>>> =
+                    HashMap::new();
+                for a in answers {
+                    let key = Self::record_to_batch_key(a);
+                    if !groups.contains_key(&key) {
+                        keys.push(key.clone());
+                    }
+                    groups.entry(key).or_insert_with(Vec::new).push(a);
+                }
+                let groups = &groups;
+                crate::util::for_each_concurrent(keys, self.write_concurrency, |k| async move {
+                    self.put_cache_batch(&k, &groups[&k]).await;
+                })
+                .await;
+            }
         }
     }
 
+    // Store all records of one question in a single KV value
+    // The value is a list of (u16 length, record data) pairs, and the
+    // TTL of each record is kept in the metadata
+    async fn put_cache_batch(
+        &self,
+        key: &str,
+        records: &[&Record<Dname<Vec<u8>>, OwnedRecordData>],
+    ) -> Result<(), String> {
+        let mut buf = Vec::new();
+        let mut ttls = Vec::new();
+        for r in records {
+            let data = crate::util::owned_record_data_to_buffer(r.data())?;
+            if data.len() > u16::MAX as usize {
+                return Err("Record data too long".to_string());
+            }
+            buf.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            buf.extend_from_slice(&data);
+            ttls.push(self.stored_ttl(r));
+        }
+        // Same jitter as put_cache, but shared by the whole batch
+        let min_ttl = ttls.iter().cloned().min().unwrap_or(0);
+        let jitter = crate::util::random_range(0, min_ttl / 10);
+        let ttls: Vec<u32> = ttls.into_iter().map(|ttl| ttl - jitter).collect();
+        // The entry lives as long as its longest-living record
+        let max_ttl = ttls.iter().cloned().max().unwrap_or(0);
+        self.store
+            .put_buf_ttl_metadata(
+                key,
+                &buf,
+                max_ttl as u64 + self.stale_ttl,
+                DnsCacheBatchMetadata {
+                    created_ts: (Date::now() / 1000f64) as u64,
+                    ttls,
+                },
+            )
+            .await
+    }
+
+    fn stored_ttl(&self, record: &Record<Dname<Vec<u8>>, OwnedRecordData>) -> u32 {
+        stored_ttl(&self.ttl_clamp, &self.ttl_by_type, record)
+    }
+
     pub async fn put_cache(
         &self,
         record: &Record<Dname<Vec<u8>>, OwnedRecordData>,
     ) -> Result<(), String> {
-        let ttl = record.ttl();
+        // Subtract a random jitter of up to 10% from the TTL, so that records
+        // cached at the same time (e.g. right after a deploy) don't all expire
+        // at once and cause a synchronized stampede to upstream
+        let ttl = self.stored_ttl(record);
+        let ttl = ttl - crate::util::random_range(0, ttl / 10);
         let data = crate::util::owned_record_data_to_buffer(record.data())?;
         self.store
             .put_buf_ttl_metadata(
                 &Self::record_to_key(record, &data),
                 &data,
-                ttl as u64,
+                // Keep the record around for a grace period after expiry
+                // so that it can be served if all upstreams are failing
+                ttl as u64 + self.stale_ttl,
                 DnsCacheMetadata {
                     created_ts: (Date::now() / 1000f64) as u64,
                     ttl,
@@ -40,26 +203,37 @@ impl DnsCache {
             .await
     }
 
+    // Look up cached records for a question
+    // Expired records (still kept for stale-if-error) are only returned
+    // if `allow_stale` is set
     pub async fn get_cache(
         &self,
         question: &Question<Dname<Vec<u8>>>,
-    ) -> Option<Vec<Record<Dname<Vec<u8>>, OwnedRecordData>>> {
+        allow_stale: bool,
+    ) -> CacheLookup {
+        if self.mode == CacheMode::PerQuestion {
+            return self.get_cache_batch(question, allow_stale).await;
+        }
+
         // One question can have multiple cached records; so we list by prefix
         // Note that list_prefix returns 1000 records at maximum by default
         // We don't expect one question to have that many answers, so it
         // should be fine
-        let keys = self
+        let keys = match self
             .store
             .list_prefix(&Self::question_to_key_prefix(question))
             .await
-            .ok()?
-            .keys;
+        {
+            Ok(res) => res.keys,
+            Err(_) => return CacheLookup::Unavailable,
+        };
         if keys.len() == 0 {
-            return None;
+            return CacheLookup::Miss;
         }
 
         // If there are keys available, then return all of the cached records
         let mut ret = Vec::new();
+        let mut age = 0;
 
         for k in keys {
             let (value, metadata): (Option<Vec<u8>>, Option<DnsCacheMetadata>) =
@@ -73,16 +247,25 @@ impl DnsCache {
             // Calculate the remaining TTL correctly
             // don't just return the original TTL blindly
             let remaining_ttl = if elapsed_since_creation > metadata.ttl as u64 {
-                0
+                if !allow_stale {
+                    continue;
+                }
+                STALE_SERVE_TTL as u64
             } else {
                 metadata.ttl as u64 - elapsed_since_creation
             };
+            age = age.max(elapsed_since_creation);
 
+            let data = match crate::util::octets_to_owned_record_data(question.qtype(), &value) {
+                Ok(data) => data,
+                // Treat corrupted entries as a miss
+                Err(_) => return CacheLookup::Miss,
+            };
             ret.push(Record::new(
                 question.qname().to_owned(),
                 question.qclass(),
                 remaining_ttl as u32,
-                crate::util::octets_to_owned_record_data(question.qtype(), &value).ok()?,
+                data,
             ));
         }
 
@@ -91,17 +274,77 @@ impl DnsCache {
             // are no longer valid, resulting in we skipping over them in
             // the main loop above. This could result in a non-empty
             // keys array but an empty return value.
-            None
+            CacheLookup::Miss
+        } else {
+            CacheLookup::Hit(ret, age)
+        }
+    }
+
+    async fn get_cache_batch(
+        &self,
+        question: &Question<Dname<Vec<u8>>>,
+        allow_stale: bool,
+    ) -> CacheLookup {
+        let (value, metadata): (Option<Vec<u8>>, Option<DnsCacheBatchMetadata>) = self
+            .store
+            .get_buf_metadata(&Self::question_to_batch_key(question))
+            .await;
+        if value.is_none() || metadata.is_none() {
+            return CacheLookup::Miss;
+        }
+
+        let (value, metadata) = (value.unwrap(), metadata.unwrap());
+        let elapsed_since_creation = (Date::now() / 1000f64) as u64 - metadata.created_ts;
+        let mut ret = Vec::new();
+        let mut pos = 0;
+        for ttl in metadata.ttls {
+            if pos + 2 > value.len() {
+                // Truncated entry
+                return CacheLookup::Miss;
+            }
+            let len = u16::from_be_bytes([value[pos], value[pos + 1]]) as usize;
+            pos += 2;
+            if pos + len > value.len() {
+                return CacheLookup::Miss;
+            }
+            let octets = &value[pos..pos + len];
+            pos += len;
+
+            let remaining_ttl = if elapsed_since_creation > ttl as u64 {
+                if !allow_stale {
+                    continue;
+                }
+                STALE_SERVE_TTL as u64
+            } else {
+                ttl as u64 - elapsed_since_creation
+            };
+
+            let data = match crate::util::octets_to_owned_record_data(question.qtype(), octets) {
+                Ok(data) => data,
+                // Treat corrupted entries as a miss
+                Err(_) => return CacheLookup::Miss,
+            };
+            ret.push(Record::new(
+                question.qname().to_owned(),
+                question.qclass(),
+                remaining_ttl as u32,
+                data,
+            ));
+        }
+
+        if ret.len() == 0 {
+            CacheLookup::Miss
         } else {
-            Some(ret)
+            CacheLookup::Hit(ret, elapsed_since_creation)
         }
     }
 
     fn record_to_key(record: &Record<Dname<Vec<u8>>, OwnedRecordData>, buf: &[u8]) -> String {
         format!(
-            "{};{};{};{}",
-            record.owner(),
-            record.rtype(),
+            "v{};{};{};{};{}",
+            Self::VERSION,
+            crate::util::normalize_name(&record.owner().to_string()),
+            crate::util::rtype_to_string(record.rtype()),
             record.class(),
             // We need to append the hash of the record data to the key
             // because one question might have multiple answers
@@ -112,10 +355,102 @@ impl DnsCache {
 
     fn question_to_key_prefix(question: &Question<Dname<Vec<u8>>>) -> String {
         format!(
-            "{};{};{};",
-            question.qname(),
-            question.qtype(),
+            "v{};{};{};{};",
+            Self::VERSION,
+            crate::util::normalize_name(&question.qname().to_string()),
+            crate::util::rtype_to_string(question.qtype()),
             question.qclass()
         )
     }
+
+    // Batched entries live under a different key space (prefixed with `batch;`)
+    // so that they never collide with entries written in per-record mode
+    fn record_to_batch_key(record: &Record<Dname<Vec<u8>>, OwnedRecordData>) -> String {
+        format!(
+            "v{};batch;{};{};{}",
+            Self::VERSION,
+            crate::util::normalize_name(&record.owner().to_string()),
+            crate::util::rtype_to_string(record.rtype()),
+            record.class()
+        )
+    }
+
+    fn question_to_batch_key(question: &Question<Dname<Vec<u8>>>) -> String {
+        format!(
+            "v{};batch;{};{};{}",
+            Self::VERSION,
+            crate::util::normalize_name(&question.qname().to_string()),
+            crate::util::rtype_to_string(question.qtype()),
+            question.qclass()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use domain::base::{iana::Class, Rtype};
+    use domain::rdata::{AllRecordData, Txt, A};
+
+    fn question(name: &str) -> Question<Dname<Vec<u8>>> {
+        Question::new(name.parse().unwrap(), Rtype::A, Class::In)
+    }
+
+    #[test]
+    fn keys_ignore_trailing_dot_and_case() {
+        let absolute = question("example.com.");
+        let relative = question("Example.COM");
+        assert_eq!(
+            DnsCache::question_to_key_prefix(&absolute),
+            DnsCache::question_to_key_prefix(&relative)
+        );
+        assert_eq!(
+            DnsCache::question_to_batch_key(&absolute),
+            DnsCache::question_to_batch_key(&relative)
+        );
+    }
+
+    #[test]
+    fn record_keys_are_found_by_question_prefix() {
+        let record: Record<Dname<Vec<u8>>, OwnedRecordData> = Record::new(
+            "example.com.".parse().unwrap(),
+            Class::In,
+            300,
+            AllRecordData::A(A::new("192.0.2.1".parse().unwrap())),
+        );
+        let data = crate::util::owned_record_data_to_buffer(record.data()).unwrap();
+        let key = DnsCache::record_to_key(&record, &data);
+        assert!(key.starts_with(&DnsCache::question_to_key_prefix(&question("example.com"))));
+        assert_eq!(
+            DnsCache::record_to_batch_key(&record),
+            DnsCache::question_to_batch_key(&question("example.com"))
+        );
+    }
+
+    #[test]
+    fn type_caps_apply_after_the_clamp() {
+        let clamp = TtlClamp {
+            min: 60,
+            max: Some(86400),
+        };
+        let mut caps = HashMap::new();
+        caps.insert(Rtype::Txt.to_int(), 300);
+        let record = |ttl, data| -> Record<Dname<Vec<u8>>, OwnedRecordData> {
+            Record::new("example.com".parse().unwrap(), Class::In, ttl, data)
+        };
+        let a = |ttl| record(ttl, AllRecordData::A(A::new("192.0.2.1".parse().unwrap())));
+        let txt = |ttl| {
+            record(
+                ttl,
+                AllRecordData::Txt(Txt::from_slice(b"\x05hello").unwrap()),
+            )
+        };
+
+        assert_eq!(stored_ttl(&clamp, &caps, &txt(3600)), 300);
+        assert_eq!(stored_ttl(&clamp, &caps, &a(3600)), 3600);
+        // The clamp still applies to both
+        assert_eq!(stored_ttl(&clamp, &caps, &txt(10)), 60);
+        assert_eq!(stored_ttl(&clamp, &caps, &a(10)), 60);
+        assert_eq!(stored_ttl(&clamp, &caps, &a(604800)), 86400);
+    }
 }
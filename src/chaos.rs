@@ -0,0 +1,107 @@
+use crate::client::QueryResult;
+use domain::base::{
+    iana::{Class, Rcode},
+    Dname, Question, Record, Rtype,
+};
+use domain::rdata::AllRecordData;
+use serde::{Deserialize, Serialize};
+
+// How CHAOS queries for the well-known identification names are handled
+#[derive(Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChaosMode {
+    // Answer with the configured strings
+    Answer,
+    // Respond with REFUSED, for those who'd rather not identify themselves
+    Refuse,
+    // Send them upstream like any other query
+    Forward,
+}
+
+impl Default for ChaosMode {
+    fn default() -> ChaosMode {
+        ChaosMode::Answer
+    }
+}
+
+fn default_identity() -> String {
+    format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct ChaosOptions {
+    #[serde(default)]
+    mode: ChaosMode,
+    // Answered for version.bind / version.server
+    #[serde(default = "default_identity")]
+    version: String,
+    // Answered for hostname.bind / id.server
+    #[serde(default = "default_identity")]
+    hostname: String,
+}
+
+impl Default for ChaosOptions {
+    fn default() -> ChaosOptions {
+        ChaosOptions {
+            mode: ChaosMode::default(),
+            version: default_identity(),
+            hostname: default_identity(),
+        }
+    }
+}
+
+// Answers the conventional CHAOS class TXT queries used by diagnostic tools
+// to identify a resolver (`dig CH TXT version.bind`, RFC 4892), which
+// upstreams would most likely refuse anyway
+pub struct ChaosResponder {
+    mode: ChaosMode,
+    version: String,
+    hostname: String,
+}
+
+impl ChaosResponder {
+    pub fn new(options: ChaosOptions) -> ChaosResponder {
+        ChaosResponder {
+            mode: options.mode,
+            version: options.version,
+            hostname: options.hostname,
+        }
+    }
+
+    fn value_for(&self, q: &Question<Dname<Vec<u8>>>) -> Option<&str> {
+        if q.qclass() != Class::Ch {
+            return None;
+        }
+        match crate::util::normalize_name(&q.qname().to_string()).as_str() {
+            "version.bind" | "version.server" => Some(&self.version),
+            "hostname.bind" | "id.server" => Some(&self.hostname),
+            _ => None,
+        }
+    }
+
+    // Answer the questions if all of them are for the well-known names
+    // Other types than TXT (and ANY) get an empty answer
+    pub fn try_answer(&self, questions: &[Question<Dname<Vec<u8>>>]) -> Option<QueryResult> {
+        if self.mode == ChaosMode::Forward {
+            return None;
+        }
+        let mut answers = Vec::new();
+        for q in questions {
+            let value = self.value_for(q)?;
+            if q.qtype() != Rtype::Txt && q.qtype() != Rtype::Any {
+                continue;
+            }
+            let txt = crate::util::text_to_txt(value).ok()?;
+            answers.push(Record::new(
+                q.qname().clone(),
+                q.qclass(),
+                0,
+                AllRecordData::Txt(txt),
+            ));
+        }
+        if self.mode == ChaosMode::Refuse {
+            return Some(QueryResult::new(Rcode::Refused, Vec::new(), Vec::new()));
+        }
+        Some(QueryResult::new(Rcode::NoError, answers, Vec::new()))
+    }
+}
@@ -1,92 +1,885 @@
-use crate::cache::DnsCache;
+use crate::cache::{CacheLookup, DnsCache};
+use crate::dns64::Dns64;
+use crate::edns::Opt;
+use crate::json::JsonUpstreamResponse;
 use crate::r#override::OverrideResolver;
-use crate::util::OwnedRecordData;
+use crate::trie_map::TrieMap;
+use crate::util::{OwnedRecordData, Subnet};
+use crate::zone::LocalZones;
 use domain::base::{
-    iana::{Opcode, Rcode},
-    Dname, Message, MessageBuilder, ParsedDname, Question, Record, ToDname,
+    iana::{Class, Opcode, Rcode},
+    message::RecordSection,
+    rdata::UnknownRecordData,
+    Dname, Message, MessageBuilder, ParsedDname, Question, Record, Rtype, ToDname,
 };
 use domain::rdata::AllRecordData;
-use js_sys::{ArrayBuffer, Uint8Array};
+use js_sys::{ArrayBuffer, Reflect, Uint8Array};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::future::Future;
+use std::net::{IpAddr, Ipv6Addr};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use wasm_bindgen::JsValue;
 use wasm_bindgen_futures::JsFuture;
 use web_sys::{Headers, Request, RequestInit, Response};
 
+// Record types to query for when expanding ANY questions
+const ANY_EXPANSION_TYPES: [Rtype; 4] = [Rtype::A, Rtype::Aaaa, Rtype::Mx, Rtype::Txt];
+
+// How ANY questions that cannot be answered locally are handled
+#[derive(Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AnyMode {
+    // Forward ANY to upstream as-is
+    Forward,
+    // Fan out into separate A / AAAA / MX / TXT queries
+    // Note that this multiplies upstream traffic
+    Expand,
+    // Answer with a synthetic HINFO record without contacting upstream,
+    // as described in RFC 8482
+    Refuse,
+}
+
+// Upper bound of the configured retries, so that a typo in the config can't
+// make one request hammer upstreams (or exhaust the subrequest limit)
+const MAX_RETRIES: usize = 10;
+
+// Error returned when there is no upstream to send a query to
+pub const NO_UPSTREAM_ERROR: &str = "No healthy upstream available";
+
+// TTL of the synthetic HINFO records answering refused ANY questions
+const REFUSED_ANY_TTL: u32 = 3600;
+
+impl Default for AnyMode {
+    fn default() -> AnyMode {
+        AnyMode::Forward
+    }
+}
+
+// How an upstream is picked from a group
+#[derive(Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum QueryStrategy {
+    // At random, by weight, for every attempt
+    Random,
+    // Strictly in the configured order, falling back to the next upstream
+    // only when the previous one fails
+    Priority,
+}
+
+impl Default for QueryStrategy {
+    fn default() -> QueryStrategy {
+        QueryStrategy::Random
+    }
+}
+
+// With the priority strategy, upstreams that failed within this period
+// are tried last, so that a known-down primary doesn't slow down every query
+const PRIORITY_FAILURE_BACKOFF_MS: f64 = 30_000f64;
+
+// How much of the body of upstream error responses ends up in error messages
+const MAX_ERROR_BODY_CHARS: usize = 200;
+
+// An HTTP request to an upstream, independent of the JS request types so
+// that tests can answer it without a runtime
+pub struct UpstreamRequest<'a> {
+    pub url: &'a str,
+    pub method: &'a str,
+    pub headers: Vec<(&'a str, &'a str)>,
+    pub body: Option<&'a [u8]>,
+    // Cloudflare-specific fetch options passed as-is in `RequestInit.cf`
+    pub cf_options: Option<&'a serde_json::Value>,
+    // Larger response bodies are rejected without being read completely
+    pub max_response_bytes: usize,
+}
+
+pub struct UpstreamResponse {
+    pub status: u16,
+    pub body: Vec<u8>,
+}
+
+// How requests to upstreams are sent; `fetch_http` (i.e. the global fetch)
+// unless replaced by tests with canned upstream responses
+pub type FetchFn =
+    for<'a> fn(
+        UpstreamRequest<'a>,
+    ) -> Pin<Box<dyn Future<Output = Result<UpstreamResponse, String>> + 'a>>;
+
+// Send a request to an upstream via `util::fetch_rs`
+fn fetch_http(
+    req: UpstreamRequest<'_>,
+) -> Pin<Box<dyn Future<Output = Result<UpstreamResponse, String>> + '_>> {
+    Box::pin(async move {
+        let headers = Headers::new().map_err(|_| "Could not create headers".to_string())?;
+        for (name, value) in req.headers.iter() {
+            headers
+                .append(name, value)
+                .map_err(|_| "Could not append header".to_string())?;
+        }
+        let mut request_init = RequestInit::new();
+        request_init.method(req.method).headers(&headers);
+        let body = req.body.map(Uint8Array::from);
+        if let Some(body) = &body {
+            request_init.body(Some(body));
+        }
+        if let Some(cf) = req.cf_options {
+            // web_sys doesn't know about the Workers-specific `cf` property
+            let cf = JsValue::from_serde(cf).map_err(|_| "Invalid cf options".to_string())?;
+            Reflect::set(&request_init, &JsValue::from_str("cf"), &cf)
+                .map_err(|_| "Could not set cf options".to_string())?;
+        }
+
+        let request = Request::new_with_str_and_init(req.url, &request_init)
+            .map_err(|_| "Failed to create Request object".to_string())?;
+        let resp: Response = crate::util::fetch_rs(&request)
+            .await
+            .map_err(|_| "Upstream request error".to_string())?
+            .into();
+        let body = match read_body(&resp, req.max_response_bytes).await {
+            Ok(body) => body,
+            // The body of error responses is only informational
+            Err(_) if resp.status() != 200 => Vec::new(),
+            Err(err) => return Err(err),
+        };
+        Ok(UpstreamResponse {
+            status: resp.status(),
+            body,
+        })
+    })
+}
+
+// The response body, bounded by max_bytes
+async fn read_body(resp: &Response, max_bytes: usize) -> Result<Vec<u8>, String> {
+    // Check the announced size first so that we don't even start reading
+    // huge bodies; the actual size is checked below as well, since the
+    // header may be missing (or lying)
+    let content_length = resp
+        .headers()
+        .get("Content-Length")
+        .ok()
+        .flatten()
+        .and_then(|len| len.parse::<usize>().ok());
+    if content_length.unwrap_or(0) > max_bytes {
+        return Err("Upstream response too large".to_string());
+    }
+
+    let resp_body = resp
+        .array_buffer()
+        .map_err(|_| "Cannot get body".to_string())?;
+    let resp_body: ArrayBuffer = JsFuture::from(resp_body)
+        .await
+        .map_err(|_| "Failure receiving response body".to_string())?
+        .into();
+    if resp_body.byte_length() as usize > max_bytes {
+        return Err("Upstream response too large".to_string());
+    }
+    Ok(Uint8Array::new(&resp_body).to_vec())
+}
+
+// The result of resolving a batch of questions
+pub struct QueryResult {
+    // NOERROR with empty answers means NODATA, which is distinct from NXDOMAIN
+    pub rcode: Rcode,
+    pub answers: Vec<Record<Dname<Vec<u8>>, OwnedRecordData>>,
+    // Authority records from upstream, e.g. the SOA for negative answers
+    pub authority: Vec<Record<Dname<Vec<u8>>, OwnedRecordData>>,
+    // The upstream queried for this result, if any
+    pub upstream: Option<String>,
+    // Whether any of the answers came from cache
+    pub cache_hit: bool,
+    // Seconds since the oldest cached answer was fetched, 0 if none
+    pub age: u64,
+    // Extended DNS Error info code explaining the result (RFC 8914)
+    pub ede: Option<u16>,
+    // Set if answers were dropped, e.g. by max_answer_records
+    pub truncated: bool,
+}
+
+impl QueryResult {
+    pub fn new(
+        rcode: Rcode,
+        answers: Vec<Record<Dname<Vec<u8>>, OwnedRecordData>>,
+        authority: Vec<Record<Dname<Vec<u8>>, OwnedRecordData>>,
+    ) -> QueryResult {
+        QueryResult {
+            rcode,
+            answers,
+            authority,
+            upstream: None,
+            cache_hit: false,
+            age: 0,
+            ede: None,
+            truncated: false,
+        }
+    }
+
+    // Merge the result of another part of the same batch into this one
+    // Any non-NOERROR response code takes precedence over NOERROR
+    fn merge(&mut self, mut other: QueryResult) {
+        if other.rcode != Rcode::NoError {
+            self.rcode = other.rcode;
+        }
+        self.answers.append(&mut other.answers);
+        self.authority.append(&mut other.authority);
+        if other.upstream.is_some() {
+            self.upstream = other.upstream;
+        }
+        self.cache_hit |= other.cache_hit;
+        self.age = self.age.max(other.age);
+        if other.ede.is_some() {
+            self.ede = other.ede;
+        }
+        self.truncated |= other.truncated;
+    }
+}
+
+// An upstream is configured either as a plain URL, or as
+// `{"url": "...", "weight": 3}` to receive a larger share of the traffic
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum UpstreamConfig {
+    Url(String),
+    Weighted {
+        url: String,
+        #[serde(default = "default_weight")]
+        weight: u32,
+        #[serde(default)]
+        format: UpstreamFormat,
+    },
+}
+
+// Wire format is what DoH (RFC 8484) specifies, but some upstreams only
+// offer the JSON API (`?name=...&type=...` with `application/dns-json`)
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum UpstreamFormat {
+    Wireformat,
+    Json,
+}
+
+impl Default for UpstreamFormat {
+    fn default() -> UpstreamFormat {
+        UpstreamFormat::Wireformat
+    }
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+// Always serialized in the `{"url": ..., "weight": ..., "format": ...}` form
+#[derive(Deserialize, Serialize)]
+#[serde(try_from = "UpstreamConfig")]
+pub struct Upstream {
+    url: String,
+    // Relative share of queries sent to this upstream within its group
+    // 0 effectively disables the upstream
+    weight: u32,
+    // "wireformat" (default) or "json"
+    format: UpstreamFormat,
+}
+
+impl TryFrom<UpstreamConfig> for Upstream {
+    type Error = String;
+
+    fn try_from(config: UpstreamConfig) -> Result<Upstream, String> {
+        let (url, weight, format) = match config {
+            UpstreamConfig::Url(url) => (url, default_weight(), UpstreamFormat::default()),
+            UpstreamConfig::Weighted {
+                url,
+                weight,
+                format,
+            } => (url, weight, format),
+        };
+        Self::validate_url(&url)?;
+        Ok(Upstream {
+            url,
+            weight,
+            format,
+        })
+    }
+}
+
+impl Upstream {
+    // A single upstream given by URL, with the default weight
+    pub fn from_url(url: String) -> Result<Upstream, String> {
+        Upstream::try_from(UpstreamConfig::Url(url))
+    }
+
+    // Upstreams may be given by IP, including bracketed IPv6 literals
+    // (`https://[2606:4700:4700::1111]/dns-query`); check the URL here to
+    // fail early with a clear message instead of on the first request
+    // This only looks at the parts we care about, the runtime parses the rest
+    fn validate_url(url: &str) -> Result<(), String> {
+        let mut parts = url.splitn(2, "://");
+        let scheme = parts.next().unwrap();
+        let rest = match parts.next() {
+            Some(rest)
+                if scheme.eq_ignore_ascii_case("https") || scheme.eq_ignore_ascii_case("http") =>
+            {
+                rest
+            }
+            _ => return Err(format!("Upstream URL {} is not HTTP(S)", url)),
+        };
+        // Host and port, without any user info
+        let authority = rest
+            .split(|c: char| c == '/' || c == '?' || c == '#')
+            .next()
+            .unwrap();
+        let host_port = authority.rsplit('@').next().unwrap();
+        let port = if host_port.starts_with('[') {
+            let end = host_port
+                .find(']')
+                .ok_or(format!("Invalid IPv6 host in upstream URL {}", url))?;
+            if host_port[1..end].parse::<Ipv6Addr>().is_err() {
+                return Err(format!("Invalid IPv6 host in upstream URL {}", url));
+            }
+            match &host_port[end + 1..] {
+                "" => None,
+                port => Some(
+                    port.strip_prefix(':')
+                        .ok_or(format!("Invalid IPv6 host in upstream URL {}", url))?,
+                ),
+            }
+        } else {
+            let mut parts = host_port.splitn(2, ':');
+            if parts.next().unwrap().is_empty() {
+                return Err(format!("Upstream URL {} has no host", url));
+            }
+            parts.next()
+        };
+        match port {
+            Some(port) if !port.is_empty() && port.parse::<u16>().is_err() => {
+                Err(format!("Invalid port in upstream URL {}", url))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+// Per-request state that influences how questions are answered
+#[derive(Default)]
+pub struct QueryContext {
+    // Address of the client, if known (for overrides scoped by subnet)
+    pub client_ip: Option<IpAddr>,
+    // Send everything to this upstream instead of the configured ones,
+    // bypassing the cache in both directions (for debugging)
+    pub upstream: Option<Upstream>,
+    // The client set the DO bit, i.e. wants DNSSEC records; this is
+    // forwarded to upstreams so that they include them
+    pub dnssec_ok: bool,
+}
+
+// Everything a Client is built from; see the struct below for details
+pub struct ClientOptions {
+    pub upstream_urls: Vec<Upstream>,
+    // Keyed by domain suffix
+    pub forwarders: HashMap<String, Vec<Upstream>>,
+    // Keyed by record type, as in the config
+    pub upstreams_by_type: HashMap<String, Vec<Upstream>>,
+    pub cache: Option<DnsCache>,
+    pub override_resolver: OverrideResolver,
+    pub rulesets: HashMap<String, OverrideResolver>,
+    pub ruleset_by_subnet: Vec<(Subnet, String)>,
+    pub local_zones: LocalZones,
+    pub dns64: Option<Dns64>,
+    pub any_mode: AnyMode,
+    pub stale_if_error: bool,
+    pub user_agent: String,
+    pub randomize_case: bool,
+    pub cf_options: Option<serde_json::Value>,
+    pub max_upstream_response_bytes: usize,
+    pub upstream_udp_size: u16,
+    pub upstream_error_details: bool,
+    pub query_strategy: QueryStrategy,
+    pub retry_backoff_ms: u32,
+}
+
 // The DNS client implementation
 pub struct Client {
-    upstream_urls: Vec<String>,
-    cache: DnsCache,
+    upstream_urls: Vec<Upstream>,
+    forwarders: TrieMap<Vec<Upstream>>,
+    // Upstream groups pinned to record types, keyed by the numeric type
+    upstreams_by_type: HashMap<u16, Vec<Upstream>>,
+    // None if caching is disabled
+    cache: Option<DnsCache>,
+    // The default set of overrides / blocklists
     override_resolver: OverrideResolver,
+    // Alternative sets by name, applied to clients by subnet; sorted by
+    // descending prefix length so that the first match is the most specific
+    rulesets: HashMap<String, OverrideResolver>,
+    ruleset_by_subnet: Vec<(Subnet, String)>,
+    local_zones: LocalZones,
+    dns64: Option<Dns64>,
+    any_mode: AnyMode,
+    // Number of cache lookups that failed due to KV errors
+    cache_errors: AtomicU64,
+    // Number of failed requests (including server errors) per upstream URL
+    upstream_errors: Mutex<HashMap<String, u64>>,
+    // Time of the last failure per upstream URL (ms since epoch)
+    upstream_last_failure: Mutex<HashMap<String, f64>>,
+    query_strategy: QueryStrategy,
+    // Delay before the first retry, doubled for every further one (0 = none)
+    retry_backoff_ms: u32,
+    // Serve expired cache records when all upstream attempts fail
+    stale_if_error: bool,
+    user_agent: String,
+    // Randomize the casing of names in upstream queries (0x20 encoding)
+    randomize_case: bool,
+    // Cloudflare-specific fetch options passed as-is in `RequestInit.cf`
+    cf_options: Option<serde_json::Value>,
+    // Larger upstream responses are rejected without being parsed
+    max_upstream_response_bytes: usize,
+    // UDP payload size advertised in the OPT records of upstream queries
+    upstream_udp_size: u16,
+    // Include (an excerpt of) the body of upstream error responses in errors
+    upstream_error_details: bool,
+    fetch: FetchFn,
 }
 
 impl Client {
-    pub fn new(upstream_urls: Vec<String>, override_resolver: OverrideResolver) -> Client {
+    pub fn new(options: ClientOptions) -> Client {
         Client {
-            upstream_urls,
-            cache: DnsCache::new(),
-            override_resolver,
+            upstream_urls: options.upstream_urls,
+            forwarders: Self::build_forwarders(options.forwarders),
+            upstreams_by_type: Self::build_upstreams_by_type(options.upstreams_by_type),
+            cache: options.cache,
+            override_resolver: options.override_resolver,
+            rulesets: options.rulesets,
+            ruleset_by_subnet: options.ruleset_by_subnet,
+            local_zones: options.local_zones,
+            dns64: options.dns64,
+            any_mode: options.any_mode,
+            cache_errors: AtomicU64::new(0),
+            upstream_errors: Mutex::new(HashMap::new()),
+            upstream_last_failure: Mutex::new(HashMap::new()),
+            query_strategy: options.query_strategy,
+            retry_backoff_ms: options.retry_backoff_ms,
+            stale_if_error: options.stale_if_error,
+            user_agent: options.user_agent,
+            randomize_case: options.randomize_case,
+            cf_options: options.cf_options,
+            max_upstream_response_bytes: options.max_upstream_response_bytes,
+            upstream_udp_size: options.upstream_udp_size,
+            upstream_error_details: options.upstream_error_details,
+            fetch: fetch_http,
         }
     }
 
+    // Answer upstream requests with something other than the global fetch
+    #[cfg(test)]
+    pub fn set_fetch(&mut self, fetch: FetchFn) {
+        self.fetch = fetch;
+    }
+
+    // Cache lookups are allowed to fail silently (falling back to upstream),
+    // so this is the only way to tell if KV itself is failing
+    pub fn cache_errors(&self) -> u64 {
+        self.cache_errors.load(Ordering::Relaxed)
+    }
+
+    pub fn upstream_errors(&self) -> HashMap<String, u64> {
+        self.upstream_errors.lock().unwrap().clone()
+    }
+
     pub async fn query(
         &self,
         questions: Vec<Question<Dname<Vec<u8>>>>,
-    ) -> Result<Vec<Record<Dname<Vec<u8>>, OwnedRecordData>>, String> {
-        // Attempt to answer locally first
-        let (mut local_answers, questions) = self.try_answer_from_local(questions).await;
-        if questions.len() == 0 {
-            // No remaining questions to be handled. Return directly.
-            return Ok(local_answers);
+        ctx: &QueryContext,
+    ) -> Result<QueryResult, String> {
+        let mut ret = self.resolve(questions.clone(), ctx).await?;
+
+        if let Some(dns64) = &self.dns64 {
+            // AAAA questions without any AAAA answers of their own: issue
+            // companion A queries and synthesize AAAA records from them
+            // Synthesized records are never cached, only the A records are
+            let mut synthesized = false;
+            let mut nodata = false;
+            for q in questions.iter().filter(|q| q.qtype() == Rtype::Aaaa) {
+                if !Dns64::needs_synthesis(q.qname(), &ret.answers) {
+                    continue;
+                }
+                let a_result = self
+                    .resolve(
+                        vec![Question::new(q.qname().clone(), Rtype::A, q.qclass())],
+                        ctx,
+                    )
+                    .await?;
+                let mut records = dns64.synthesize(q.qname(), &a_result.answers);
+                if records.is_empty() {
+                    nodata = true;
+                } else {
+                    synthesized = true;
+                    ret.answers.append(&mut records);
+                }
+            }
+            if synthesized && !nodata {
+                // The names are no longer NODATA; drop the negative SOA
+                ret.authority.clear();
+            }
         }
 
-        let msg = Self::build_query(questions)?;
-        let upstream = self.select_upstream();
-        let resp = Self::do_query(&upstream, msg).await?;
+        Ok(ret)
+    }
 
-        match resp.header().rcode() {
-            Rcode::NoError => {
-                let mut ret = Self::extract_answers(resp)?;
-                self.cache_answers(&ret).await;
-                // Concatenate the cached answers we retrived previously with the newly-fetched answers
-                ret.append(&mut local_answers);
-                Ok(ret)
+    // Resolve questions from local sources first, and then from upstream
+    async fn resolve(
+        &self,
+        questions: Vec<Question<Dname<Vec<u8>>>>,
+        ctx: &QueryContext,
+    ) -> Result<QueryResult, String> {
+        // Each question is resolved on its own, since most upstreams don't
+        // handle multi-question queries well. Results are merged in the
+        // original question order regardless of where they come from
+        let mut ret = QueryResult::new(Rcode::NoError, Vec::new(), Vec::new());
+        for q in questions {
+            if self.any_mode == AnyMode::Refuse && q.qtype() == Rtype::Any {
+                ret.merge(Self::refuse_any(&q));
+                continue;
+            }
+            // Attempt to answer locally first
+            let res = match self.try_answer_from_local(&q, ctx).await {
+                Some(res) => res,
+                None if self.any_mode == AnyMode::Expand && q.qtype() == Rtype::Any => {
+                    self.query_any_expanded(q, ctx).await?
+                }
+                None => {
+                    self.query_upstream(self.upstreams_for(&q, ctx), vec![q], ctx)
+                        .await?
+                }
+            };
+            ret.merge(res);
+        }
+        Ok(ret)
+    }
+
+    // Query one group of upstreams for the given questions and cache the answers
+    async fn query_upstream(
+        &self,
+        upstream_urls: &[Upstream],
+        questions: Vec<Question<Dname<Vec<u8>>>>,
+        ctx: &QueryContext,
+    ) -> Result<QueryResult, String> {
+        let query_questions = if self.randomize_case {
+            questions.iter().map(Self::randomize_case).collect()
+        } else {
+            questions.clone()
+        };
+        let msg = Self::build_query(query_questions, ctx.dnssec_ok, self.upstream_udp_size)?;
+        let upstreams = match self.query_strategy {
+            QueryStrategy::Random => vec![Self::select_upstream(upstream_urls)?],
+            QueryStrategy::Priority => self.upstreams_by_priority(upstream_urls)?,
+        };
+        let mut last_err = String::new();
+        for upstream in upstreams {
+            match self
+                .query_selected_upstream(upstream, msg.clone(), &questions, ctx.upstream.is_none())
+                .await
+            {
+                Ok(res) => return Ok(res),
+                Err(err) => {
+                    self.record_upstream_failure(upstream.url.clone());
+                    last_err = err;
+                }
             }
+        }
+        Err(last_err)
+    }
+
+    fn record_upstream_failure(&self, upstream: String) {
+        *self
+            .upstream_errors
+            .lock()
+            .unwrap()
+            .entry(upstream.clone())
+            .or_insert(0) += 1;
+        self.upstream_last_failure
+            .lock()
+            .unwrap()
+            .insert(upstream, crate::util::now());
+    }
+
+    // All usable upstreams of a group in the order they should be tried,
+    // i.e. the configured order with recently failed ones moved to the end
+    fn upstreams_by_priority<'a>(
+        &self,
+        upstream_urls: &'a [Upstream],
+    ) -> Result<Vec<&'a Upstream>, String> {
+        let now = crate::util::now();
+        let last_failure = self.upstream_last_failure.lock().unwrap();
+        let (mut healthy, failed): (Vec<&Upstream>, Vec<&Upstream>) = upstream_urls
+            .iter()
+            .filter(|u| u.weight > 0)
+            .partition(|u| match last_failure.get(&u.url) {
+                Some(ts) => now - ts > PRIORITY_FAILURE_BACKOFF_MS,
+                None => true,
+            });
+        healthy.extend(failed);
+        if healthy.len() == 0 {
+            return Err(NO_UPSTREAM_ERROR.to_string());
+        }
+        Ok(healthy)
+    }
+
+    async fn query_selected_upstream(
+        &self,
+        upstream: &Upstream,
+        msg: Message<Vec<u8>>,
+        questions: &[Question<Dname<Vec<u8>>>],
+        cache: bool,
+    ) -> Result<QueryResult, String> {
+        let resp = match upstream.format {
+            UpstreamFormat::Wireformat => self.do_query(&upstream.url, msg).await?,
+            UpstreamFormat::Json => self.do_query_json(&upstream.url, msg).await?,
+        };
+        let upstream = upstream.url.clone();
+        // An upstream that can't recurse returns referrals (or nothing) for
+        // names it isn't authoritative for; passing those on as if they were
+        // complete answers would be wrong, so treat them as failures to fall
+        // back to other upstreams, or eventually SERVFAIL
+        // Authoritative answers are still fine, e.g. from a forwarder
+        if !resp.header().ra() && !resp.header().aa() {
+            return Err(format!("Upstream {} does not offer recursion", upstream));
+        }
+
+        let rcode = resp.header().rcode();
+        match rcode {
+            // An empty answer section here means NODATA, i.e. the name exists
+            // but has no records of the requested type
+            Rcode::NoError => (),
             // NXDOMAIN is not an error we want to retry / panic upon
             // It simply means the domain doesn't exist
-            Rcode::NXDomain => Ok(Vec::new()),
-            rcode => Err(format!("Server error: {}", rcode)),
+            // Note that the answer section may still contain a CNAME chain
+            // leading to the nonexistent name
+            Rcode::NXDomain => (),
+            rcode => return Err(format!("Server error: {}", rcode)),
+        }
+        let (answers, skipped_answers) = Self::extract_answers(&resp)?;
+        let (authority, skipped_authority) = Self::extract_authority(&resp)?;
+        if skipped_answers + skipped_authority > 0 {
+            crate::util::log(&format!(
+                "Skipped {} unparseable records from upstream {}",
+                skipped_answers + skipped_authority,
+                upstream
+            ));
+        }
+        let answers = self.restore_case(answers, questions);
+        if rcode == Rcode::NoError && cache {
+            self.cache_answers(&answers).await;
+        }
+        let mut ret = QueryResult::new(rcode, answers, self.restore_case(authority, questions));
+        ret.upstream = Some(upstream);
+        Ok(ret)
+    }
+
+    // The RFC 8482 response to ANY: a single HINFO record with the CPU
+    // field set to "RFC8482" and an empty OS field
+    fn refuse_any(question: &Question<Dname<Vec<u8>>>) -> QueryResult {
+        // HINFO is two character-strings; build the wire format by hand
+        let mut rdata = vec![7u8];
+        rdata.extend_from_slice(b"RFC8482");
+        rdata.push(0);
+        let record = Record::new(
+            question.qname().clone(),
+            question.qclass(),
+            REFUSED_ANY_TTL,
+            AllRecordData::Other(UnknownRecordData::from_octets(Rtype::Hinfo, rdata)),
+        );
+        QueryResult::new(Rcode::NoError, vec![record], Vec::new())
+    }
+
+    // Many upstreams refuse ANY queries nowadays (RFC 8482), so instead
+    // query the common record types one by one and merge the answers
+    async fn query_any_expanded(
+        &self,
+        question: Question<Dname<Vec<u8>>>,
+        ctx: &QueryContext,
+    ) -> Result<QueryResult, String> {
+        let mut ret = QueryResult::new(Rcode::NoError, Vec::new(), Vec::new());
+        for rtype in ANY_EXPANSION_TYPES.iter() {
+            let q = Question::new(question.qname().clone(), *rtype, question.qclass());
+            ret.merge(
+                self.query_upstream(self.upstreams_for(&q, ctx), vec![q], ctx)
+                    .await?,
+            );
         }
+        if ret.answers.len() > 0 {
+            // Some types may have been NODATA; their SOAs are irrelevant now
+            ret.authority.clear();
+        }
+        Ok(ret)
     }
 
     pub async fn query_with_retry(
         &self,
         questions: Vec<Question<Dname<Vec<u8>>>>,
         retries: usize,
-    ) -> Result<Vec<Record<Dname<Vec<u8>>, OwnedRecordData>>, String> {
-        let mut last_res = Err("Dummy".to_string());
-        for _ in 0..retries {
-            last_res = self.query(questions.clone()).await;
+        ctx: &QueryContext,
+    ) -> Result<QueryResult, String> {
+        if questions.len() == 0 {
+            return Err("No attempts made: no questions to resolve".to_string());
+        }
+
+        // There is always one attempt; `retries` is the number of additional
+        // attempts after a failure
+        let mut last_res = self.query(questions.clone(), ctx).await;
+        for attempt in 0..retries.min(MAX_RETRIES) {
             if last_res.is_ok() {
                 break;
             }
+            if self.retry_backoff_ms > 0 {
+                // Give a briefly overloaded upstream some air; this counts
+                // against the wall time of the request, so keep it small
+                crate::util::sleep(self.retry_backoff_ms.saturating_mul(1 << attempt)).await;
+            }
+            last_res = self.query(questions.clone(), ctx).await;
+        }
+
+        if last_res.is_err() && self.stale_if_error && ctx.upstream.is_none() {
+            // All attempts failed; serve expired records from cache, if any
+            if let Some(stale) = self.try_answer_stale(&questions).await {
+                return Ok(stale);
+            }
         }
         return last_res;
     }
 
-    // Select an upstream randomly
-    fn select_upstream(&self) -> String {
-        let idx = crate::util::random_range(0, self.upstream_urls.len() as u16);
-        self.upstream_urls[idx as usize].clone()
+    // Answer from expired-but-present cache records, for use when
+    // upstreams are unavailable
+    async fn try_answer_stale(
+        &self,
+        questions: &[Question<Dname<Vec<u8>>>],
+    ) -> Option<QueryResult> {
+        let cache = self.cache.as_ref()?;
+        let mut ret = QueryResult::new(Rcode::NoError, Vec::new(), Vec::new());
+        for q in questions {
+            if let CacheLookup::Hit(mut ans, age) = cache.get_cache(q, true).await {
+                ret.answers.append(&mut ans);
+                ret.age = ret.age.max(age);
+            }
+        }
+        if ret.answers.len() == 0 {
+            return None;
+        }
+        ret.cache_hit = true;
+        Some(ret)
+    }
+
+    // Select an upstream randomly from a group, with the probability of
+    // each upstream being proportional to its weight
+    fn select_upstream(upstream_urls: &[Upstream]) -> Result<&Upstream, String> {
+        let total_weight: u64 = upstream_urls.iter().map(|u| u.weight as u64).sum();
+        // The list may be empty (or all weights zero) if misconfigured
+        if total_weight == 0 {
+            return Err(NO_UPSTREAM_ERROR.to_string());
+        }
+        // Walk the cumulative weights until we pass the random point
+        let mut point = crate::util::random_range(0, total_weight);
+        for u in upstream_urls {
+            if point < u.weight as u64 {
+                return Ok(u);
+            }
+            point -= u.weight as u64;
+        }
+        // Unreachable unless random() returned exactly 1
+        Ok(upstream_urls.iter().rev().find(|u| u.weight > 0).unwrap())
+    }
+
+    // Build the suffix trie for conditional forwarding
+    // Every key is treated as a domain suffix, with an optional leading
+    // wildcard; the suffix itself (the apex) is also matched.
+    fn build_forwarders(forwarders: HashMap<String, Vec<Upstream>>) -> TrieMap<Vec<Upstream>> {
+        let mut ret = TrieMap::with_boundary(b'.');
+        for (k, v) in forwarders.into_iter() {
+            if v.len() == 0 {
+                // Ignore empty upstream groups
+                continue;
+            }
+            let suffix = crate::util::normalize_name(k.trim_start_matches("*."));
+            // Keep the leading dot so that only whole labels can match
+            if ret
+                .put_prefix(format!(".{}", suffix).chars().rev().collect::<String>(), v)
+                .is_some()
+            {
+                // e.g. both `example.com` and `*.example.com` are configured
+                crate::util::log(&format!(
+                    "Conflicting forwarders for {}, only one of them is used",
+                    suffix
+                ));
+            }
+        }
+        ret
+    }
+
+    // Find the upstream group a question should be routed to
+    // defaulting to the global upstream list
+    // Conditional forwarding by name takes precedence over pinning by type,
+    // so that e.g. internal names never leak to a pinned public resolver
+    // An upstream forced by the request context overrides all of these
+    fn upstreams_for<'a>(
+        &'a self,
+        question: &Question<Dname<Vec<u8>>>,
+        ctx: &'a QueryContext,
+    ) -> &'a [Upstream] {
+        if let Some(upstream) = &ctx.upstream {
+            return std::slice::from_ref(upstream);
+        }
+        if !self.forwarders.is_empty() {
+            let name = format!(
+                ".{}",
+                crate::util::normalize_name(&question.qname().to_string())
+            );
+            if let Some(upstream_urls) = self
+                .forwarders
+                .get_by_prefix(name.chars().rev().collect::<String>())
+            {
+                return upstream_urls;
+            }
+        }
+        match self.upstreams_by_type.get(&question.qtype().to_int()) {
+            Some(upstream_urls) => upstream_urls,
+            None => &self.upstream_urls,
+        }
+    }
+
+    // Parse the record types of upstream groups pinned by type
+    fn build_upstreams_by_type(
+        upstreams_by_type: HashMap<String, Vec<Upstream>>,
+    ) -> HashMap<u16, Vec<Upstream>> {
+        let mut ret = HashMap::new();
+        for (k, v) in upstreams_by_type.into_iter() {
+            if v.len() == 0 {
+                // Ignore empty upstream groups
+                continue;
+            }
+            match crate::util::parse_qtype(&k) {
+                Ok(rtype) => {
+                    ret.insert(rtype.to_int(), v);
+                }
+                Err(err) => crate::util::log(&format!("Ignoring upstreams for {}: {}", k, err)),
+            }
+        }
+        ret
     }
 
     // Build UDP wireformat query from a list of questions
     // We don't use the client's query directly because we want to validate
     // it first, and we also want to be able to do caching and overriding
-    fn build_query(questions: Vec<Question<Dname<Vec<u8>>>>) -> Result<Message<Vec<u8>>, String> {
+    fn build_query(
+        questions: Vec<Question<Dname<Vec<u8>>>>,
+        dnssec_ok: bool,
+        udp_size: u16,
+    ) -> Result<Message<Vec<u8>>, String> {
         let mut builder = MessageBuilder::new_vec();
         // Set up the header
         let header = builder.header_mut();
-        // We don't use set_random_id because `getrandom` seems to be
-        // unreliable on Cloudflare Workers for some reason
-        header.set_id(crate::util::random_range(0, u16::MAX));
+        // Always use ID 0 as recommended by RFC 8484, so that identical
+        // queries are identical HTTP requests and can be served by HTTP caches
+        // The client's own ID is restored when building our response
+        header.set_id(0);
         header.set_qr(false); // For queries, QR = false
         header.set_opcode(Opcode::Query);
         header.set_rd(true); // Ask for recursive queries
@@ -98,70 +891,257 @@ impl Client {
                 .push(q)
                 .map_err(|_| "Size limit exceeded".to_string())?;
         }
-        Ok(question_builder.into_message())
+        if !dnssec_ok {
+            return Ok(question_builder.into_message());
+        }
+        // The DO bit lives in the OPT record; the size doesn't matter to us
+        // over HTTPS, but some upstreams use it on their side (e.g. when
+        // querying authoritative servers over UDP)
+        let mut opt = Opt::new(udp_size);
+        opt.set_dnssec_ok(true);
+        let mut additional_builder = question_builder.additional();
+        additional_builder
+            .push(opt.to_record())
+            .map_err(|_| "Size limit exceeded".to_string())?;
+        Ok(additional_builder.into_message())
     }
 
-    async fn do_query(upstream: &str, msg: Message<Vec<u8>>) -> Result<Message<Vec<u8>>, String> {
-        let body = Uint8Array::from(msg.as_slice());
-        let headers = Headers::new().map_err(|_| "Could not create headers".to_string())?;
-        headers
-            .append("Accept", "application/dns-message")
-            .map_err(|_| "Could not append header".to_string())?;
-        headers
-            .append("Content-Type", "application/dns-message")
-            .map_err(|_| "Could not append header".to_string())?;
+    async fn do_query(
+        &self,
+        upstream: &str,
+        msg: Message<Vec<u8>>,
+    ) -> Result<Message<Vec<u8>>, String> {
+        let query_id = msg.header().id();
+        let resp_body = self
+            .fetch_upstream(
+                upstream,
+                "POST",
+                Some(msg.as_slice()),
+                "application/dns-message",
+                Some("application/dns-message"),
+            )
+            .await?;
 
-        let mut request_init = RequestInit::new();
-        request_init
-            .method("POST")
-            .body(Some(&body))
-            .headers(&headers);
+        let resp = crate::util::parse_dns_wireformat(&resp_body)?;
+        // A mismatched ID or question means the response is not for our query
+        // (e.g. from a misbehaving caching proxy); don't trust it, but allow a
+        // retry. Since the ID is always 0, the question is the stronger check
+        // With 0x20 encoding, the casing of the question must also be preserved
+        if resp.header().id() != query_id
+            || Self::question_keys(&resp, self.randomize_case)
+                != Self::question_keys(&msg, self.randomize_case)
+        {
+            return Err("Upstream response does not match query".to_string());
+        }
+        Ok(resp)
+    }
 
-        let request = Request::new_with_str_and_init(upstream, &request_init)
-            .map_err(|_| "Failed to create Request object".to_string())?;
-        let resp: Response = crate::util::fetch_rs(&request)
-            .await
-            .map_err(|_| "Upstream request error".to_string())?
-            .into();
+    // Same as do_query, for upstreams only offering the JSON API
+    // The JSON response is converted back to wire format, so that everything
+    // else works the same regardless of the format of the upstream
+    async fn do_query_json(
+        &self,
+        upstream: &str,
+        msg: Message<Vec<u8>>,
+    ) -> Result<Message<Vec<u8>>, String> {
+        let mut questions = msg.question();
+        let q = match (questions.next(), questions.next()) {
+            (Some(Ok(q)), None) => q,
+            _ => return Err("JSON upstreams only support a single question".to_string()),
+        };
+        let mut url = format!(
+            "{}{}name={}&type={}",
+            upstream,
+            if upstream.contains('?') { "&" } else { "?" },
+            crate::util::encode_uri_component(&q.qname().to_string()),
+            q.qtype().to_int()
+        );
+        if msg.opt().map(|opt| opt.dnssec_ok()).unwrap_or(false) {
+            url.push_str("&do=1");
+        }
+        let resp_body = self
+            .fetch_upstream(&url, "GET", None, "application/dns-json", None)
+            .await?;
+
+        let resp = String::from_utf8(resp_body)
+            .map_err(|_| "Upstream JSON response is not UTF-8".to_string())?;
+        JsonUpstreamResponse::from_json(&resp)?.to_message(&msg)
+    }
 
-        if resp.status() != 200 {
-            return Err(format!("Unknown response status {}", resp.status()));
+    // Send a request to an upstream and return the response body if successful
+    async fn fetch_upstream(
+        &self,
+        url: &str,
+        method: &str,
+        body: Option<&[u8]>,
+        accept: &str,
+        content_type: Option<&str>,
+    ) -> Result<Vec<u8>, String> {
+        let mut headers = vec![("Accept", accept)];
+        if let Some(content_type) = content_type {
+            headers.push(("Content-Type", content_type));
         }
+        // Some upstreams treat anonymous clients differently
+        // Accept-Encoding is not set here: fetch() on Workers negotiates
+        // compression by itself and decompresses transparently
+        headers.push(("User-Agent", self.user_agent.as_str()));
 
-        let resp_body = resp
-            .array_buffer()
-            .map_err(|_| "Cannot get body".to_string())?;
-        let resp_body: ArrayBuffer = JsFuture::from(resp_body)
-            .await
-            .map_err(|_| "Failure receiving response body".to_string())?
-            .into();
+        let resp = (self.fetch)(UpstreamRequest {
+            url,
+            method,
+            headers,
+            body,
+            cf_options: self.cf_options.as_ref(),
+            max_response_bytes: self.max_upstream_response_bytes,
+        })
+        .await?;
 
-        crate::util::parse_dns_wireformat(&Uint8Array::new(&resp_body).to_vec())
+        if resp.status != 200 {
+            let mut err = format!("Unknown response status {}", resp.status);
+            if self.upstream_error_details && resp.body.len() > 0 {
+                // Providers often explain what's wrong, e.g. a wrong path or
+                // failed authentication; the body is bounded just the same
+                err = format!("{}: {}", err, Self::error_excerpt(&resp.body));
+            }
+            return Err(err);
+        }
+        Ok(resp.body)
+    }
+
+    // A short, single-line and header-safe excerpt of an error response body
+    fn error_excerpt(body: &[u8]) -> String {
+        String::from_utf8_lossy(body)
+            .chars()
+            .take(MAX_ERROR_BODY_CHARS)
+            .map(|c| if c.is_ascii_graphic() { c } else { ' ' })
+            .collect::<String>()
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    // Comparable form of the question section of a message
+    fn question_keys(msg: &Message<Vec<u8>>, exact_case: bool) -> Vec<(String, Rtype, Class)> {
+        msg.question()
+            .filter_map(|q| q.ok())
+            .map(|q| {
+                let name = q.qname().to_string();
+                (
+                    if exact_case {
+                        name
+                    } else {
+                        crate::util::normalize_name(&name)
+                    },
+                    q.qtype(),
+                    q.qclass(),
+                )
+            })
+            .collect()
+    }
+
+    // Randomize the casing of the letters in a question name (0x20 encoding)
+    // Upstreams preserve the casing in their responses, which gives spoofed
+    // responses a harder time matching our query
+    fn randomize_case(question: &Question<Dname<Vec<u8>>>) -> Question<Dname<Vec<u8>>> {
+        let name: String = question
+            .qname()
+            .to_string()
+            .chars()
+            .map(|c| {
+                if crate::util::random() < 0.5 {
+                    c.to_ascii_uppercase()
+                } else {
+                    c.to_ascii_lowercase()
+                }
+            })
+            .collect();
+        match name.parse() {
+            Ok(qname) => Question::new(qname, question.qtype(), question.qclass()),
+            // Should not happen, but fall back to the original name if it does
+            Err(_) => question.clone(),
+        }
+    }
+
+    // Give records owned by a question name the casing of the question as
+    // the client sent it, instead of the randomized one echoed by upstream
+    fn restore_case(
+        &self,
+        records: Vec<Record<Dname<Vec<u8>>, OwnedRecordData>>,
+        questions: &[Question<Dname<Vec<u8>>>],
+    ) -> Vec<Record<Dname<Vec<u8>>, OwnedRecordData>> {
+        if !self.randomize_case {
+            return records;
+        }
+        Self::with_question_case(records, questions)
+    }
+
+    fn with_question_case(
+        records: Vec<Record<Dname<Vec<u8>>, OwnedRecordData>>,
+        questions: &[Question<Dname<Vec<u8>>>],
+    ) -> Vec<Record<Dname<Vec<u8>>, OwnedRecordData>> {
+        records
+            .into_iter()
+            .map(|r| {
+                // Dname comparison ignores case
+                match questions.iter().find(|q| q.qname() == r.owner()) {
+                    Some(q) => Record::new(q.qname().clone(), r.class(), r.ttl(), r.data().clone()),
+                    None => r,
+                }
+            })
+            .collect()
     }
 
     fn extract_answers(
-        msg: Message<Vec<u8>>,
-    ) -> Result<Vec<Record<Dname<Vec<u8>>, OwnedRecordData>>, String> {
+        msg: &Message<Vec<u8>>,
+    ) -> Result<(Vec<Record<Dname<Vec<u8>>, OwnedRecordData>>, usize), String> {
         let answer_section = msg
             .answer()
             .map_err(|_| "Failed to parse DNS answer from upstream".to_string())?;
         // Answers can be empty; that is when upstream has no records for the questions
         // so we don't need to error out here if answers are empty
         // this is different from the server impl
-        let answers: Vec<_> = answer_section.collect();
+        Self::extract_records(answer_section)
+    }
+
+    fn extract_authority(
+        msg: &Message<Vec<u8>>,
+    ) -> Result<(Vec<Record<Dname<Vec<u8>>, OwnedRecordData>>, usize), String> {
+        let authority_section = msg
+            .authority()
+            .map_err(|_| "Failed to parse DNS authority from upstream".to_string())?;
+        Self::extract_records(authority_section)
+    }
+
+    // The records of a section we can parse, and how many were skipped
+    fn extract_records(
+        section: RecordSection<'_, Vec<u8>>,
+    ) -> Result<(Vec<Record<Dname<Vec<u8>>, OwnedRecordData>>, usize), String> {
+        let records: Vec<_> = section.collect();
 
         let mut ret: Vec<Record<Dname<Vec<u8>>, OwnedRecordData>> = Vec::new();
-        for a in answers {
-            let parsed_record = a.map_err(|_| "Failed to parse DNS answer record".to_string())?;
+        let mut skipped = 0;
+        for a in records {
+            // A single unparseable record shouldn't fail the whole response,
+            // so skip it and return whatever we can parse
+            let parsed_record = match a {
+                Ok(r) => r,
+                Err(_) => {
+                    skipped += 1;
+                    continue;
+                }
+            };
             // Actually parse the record
             // Note that we cannot just use UnknownRecordData here and not parse it;
             // it does not know how to parse all types of records correctly, which
             // could corrupt the actual record data
             let record: Record<ParsedDname<&Vec<u8>>, AllRecordData<&[u8], ParsedDname<&Vec<u8>>>> =
-                parsed_record
-                    .to_record()
-                    .map_err(|_| "Cannot parse record".to_string())?
-                    .ok_or("Cannot parse record".to_string())?;
+                match parsed_record.to_record() {
+                    Ok(Some(r)) => r,
+                    _ => {
+                        skipped += 1;
+                        continue;
+                    }
+                };
             // Convert the record to owned for sanity in type signature
             let owned_record = Record::new(
                 record
@@ -174,46 +1154,775 @@ impl Client {
                     Ok(data) => data,
                     // If this fails, it means that our resolver doesn't support the type yet
                     // so just skip this record
-                    Err(_) => continue,
+                    Err(_) => {
+                        skipped += 1;
+                        continue;
+                    }
                 },
             );
             ret.push(owned_record);
         }
-        Ok(ret)
+        Ok((ret, skipped))
+    }
+
+    // The set of overrides applying to the client, chosen by its subnet
+    fn override_resolver_for(&self, ctx: &QueryContext) -> &OverrideResolver {
+        ctx.client_ip
+            .and_then(|ip| {
+                self.ruleset_by_subnet
+                    .iter()
+                    .find(|(subnet, _)| subnet.contains(&ip))
+            })
+            .and_then(|(_, name)| self.rulesets.get(name))
+            .unwrap_or(&self.override_resolver)
     }
 
-    // Try to answer the questions as much as we can from the cache / override map
-    // returns the available answers, and the remaining questions that cannot be
-    // answered from cache or the override resolver
+    // Try to answer a question from the override map / cache
+    // returns None if it has to be resolved via upstream
     async fn try_answer_from_local(
         &self,
-        questions: Vec<Question<Dname<Vec<u8>>>>,
-    ) -> (
-        Vec<Record<Dname<Vec<u8>>, OwnedRecordData>>,
-        Vec<Question<Dname<Vec<u8>>>>,
-    ) {
-        let mut answers = Vec::new();
-        let mut remaining = Vec::new();
-        for q in questions {
-            if let Some(ans) = self.override_resolver.try_resolve(&q) {
-                // Try to resolve from override map first
-                answers.push(ans);
-            } else if let Some(mut ans) = self.cache.get_cache(&q).await {
-                // Then try cache
-                answers.append(&mut ans);
-            } else {
-                // If both failed, resolve via upstream
-                remaining.push(q);
+        q: &Question<Dname<Vec<u8>>>,
+        ctx: &QueryContext,
+    ) -> Option<QueryResult> {
+        let override_resolver = self.override_resolver_for(ctx);
+        if override_resolver.is_passthrough(q) {
+            // Always fresh from upstream, whatever else matches the name
+            return None;
+        }
+        if let Some(res) = override_resolver.try_resolve(q, ctx.client_ip) {
+            // Try to resolve from the override map first (including
+            // typed SRV / MX / SVCB / HTTPS overrides and the blocklist)
+            // Note that the answers may be empty if the name is overridden
+            // but not for the requested address family (NODATA)
+            // These never go into the cache, so TTL-0 answers stay uncached
+            return Some(res);
+        }
+        if let Some(res) = self.local_zones.try_resolve(q) {
+            // Overrides take precedence, so that names in local zones can
+            // still be blocked
+            return Some(res);
+        }
+        if ctx.upstream.is_some() {
+            // The answer has to come from the forced upstream
+            return None;
+        }
+        // Then try cache, if enabled
+        let mut ret = QueryResult::new(Rcode::NoError, Vec::new(), Vec::new());
+        match self.cache.as_ref()?.get_cache(q, false).await {
+            CacheLookup::Hit(ans, age) => {
+                ret.answers = ans;
+                ret.cache_hit = true;
+                ret.age = age;
+                Some(ret)
+            }
+            CacheLookup::Miss => None,
+            CacheLookup::Unavailable => {
+                self.cache_errors.fetch_add(1, Ordering::Relaxed);
+                None
             }
         }
-        (answers, remaining)
     }
 
-    #[allow(unused_must_use)]
+    // Describe how a question would be answered, without contacting upstream
+    // Cache lookups are real though, as they are needed to tell hits apart
+    pub async fn explain(
+        &self,
+        q: &Question<Dname<Vec<u8>>>,
+        ctx: &QueryContext,
+    ) -> serde_json::Value {
+        let override_resolver = self.override_resolver_for(ctx);
+        let passthrough = override_resolver.is_passthrough(q);
+        let local_rule = if self.any_mode == AnyMode::Refuse && q.qtype() == Rtype::Any {
+            Some("refuse_any")
+        } else {
+            override_resolver
+                .explain(q, ctx.client_ip)
+                .or_else(|| self.local_zones.try_resolve(q).map(|_| "local_zone"))
+        };
+        let cache = if local_rule.is_some() || passthrough || ctx.upstream.is_some() {
+            "skipped"
+        } else {
+            match &self.cache {
+                None => "disabled",
+                Some(cache) => match cache.get_cache(q, false).await {
+                    CacheLookup::Hit(_, _) => "hit",
+                    CacheLookup::Miss => "miss",
+                    CacheLookup::Unavailable => "unavailable",
+                },
+            }
+        };
+        // An upstream is picked from these at random, by weight
+        let upstreams: Vec<&str> = if local_rule.is_none() && cache != "hit" {
+            self.upstreams_for(q, ctx)
+                .iter()
+                .filter(|u| u.weight > 0)
+                .map(|u| u.url.as_str())
+                .collect()
+        } else {
+            Vec::new()
+        };
+        serde_json::json!({
+            "name": q.qname().to_string(),
+            "type": crate::util::rtype_to_string(q.qtype()),
+            "passthrough": passthrough,
+            "blocklisted": override_resolver.is_blocklisted(
+                &crate::util::normalize_name(&q.qname().to_string())
+            ),
+            "override": local_rule,
+            "cache": cache,
+            "upstreams": upstreams,
+        })
+    }
+
     async fn cache_answers(&self, answers: &[Record<Dname<Vec<u8>>, OwnedRecordData>]) {
-        for a in answers {
-            // Ignore error -- we don't really care
-            self.cache.put_cache(a).await;
+        if let Some(cache) = self.cache.as_ref() {
+            cache.put_answers(answers).await;
         }
     }
 }
+
+// Canned upstreams for tests, plugged in with `Client::set_fetch`
+#[cfg(test)]
+pub mod testing {
+    use super::*;
+    use crate::r#override::OverrideOptions;
+    use domain::rdata::A;
+    use std::cell::RefCell;
+    use std::net::Ipv4Addr;
+
+    pub type FetchResult<'a> = Pin<Box<dyn Future<Output = Result<UpstreamResponse, String>> + 'a>>;
+
+    pub const TEST_ADDR: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+
+    thread_local! {
+        // URLs of the requests answered with `respond` on this thread
+        static REQUESTS: RefCell<Vec<String>> = RefCell::new(Vec::new());
+    }
+
+    // The URLs requested since the last call, oldest first
+    pub fn take_requests() -> Vec<String> {
+        REQUESTS.with(|r| r.borrow_mut().split_off(0))
+    }
+
+    pub fn respond<'a>(req: &UpstreamRequest<'a>, status: u16, body: Vec<u8>) -> FetchResult<'a> {
+        REQUESTS.with(|r| r.borrow_mut().push(req.url.to_string()));
+        Box::pin(async move { Ok(UpstreamResponse { status, body }) })
+    }
+
+    // The query of a wireformat request
+    pub fn query_of(req: &UpstreamRequest<'_>) -> Message<Vec<u8>> {
+        Message::from_octets(req.body.unwrap().to_vec()).unwrap()
+    }
+
+    pub fn questions_of(msg: &Message<Vec<u8>>) -> Vec<Question<Dname<Vec<u8>>>> {
+        msg.question()
+            .map(|q| {
+                let q = q.unwrap();
+                Question::new(q.qname().to_dname().unwrap(), q.qtype(), q.qclass())
+            })
+            .collect()
+    }
+
+    // The response of a recursive upstream to a query
+    pub fn response(
+        query: &Message<Vec<u8>>,
+        rcode: Rcode,
+        answers: Vec<Record<Dname<Vec<u8>>, OwnedRecordData>>,
+        authority: Vec<Record<Dname<Vec<u8>>, OwnedRecordData>>,
+    ) -> Vec<u8> {
+        let mut builder = MessageBuilder::new_vec();
+        let header = builder.header_mut();
+        header.set_id(query.header().id());
+        header.set_qr(true);
+        header.set_rd(true);
+        header.set_ra(true);
+        header.set_rcode(rcode);
+        let mut question_builder = builder.question();
+        for q in questions_of(query) {
+            question_builder.push(q).unwrap();
+        }
+        let mut answer_builder = question_builder.answer();
+        for r in answers {
+            answer_builder.push(r).unwrap();
+        }
+        let mut authority_builder = answer_builder.authority();
+        for r in authority {
+            authority_builder.push(r).unwrap();
+        }
+        authority_builder.into_message().into_octets()
+    }
+
+    pub fn a_record(name: &Dname<Vec<u8>>, ttl: u32) -> Record<Dname<Vec<u8>>, OwnedRecordData> {
+        Record::new(
+            name.clone(),
+            Class::In,
+            ttl,
+            AllRecordData::A(A::new(TEST_ADDR)),
+        )
+    }
+
+    // An upstream knowing TEST_ADDR for every name, and no other records
+    pub fn fetch_a(req: UpstreamRequest<'_>) -> FetchResult<'_> {
+        let query = query_of(&req);
+        let answers = questions_of(&query)
+            .iter()
+            .filter(|q| q.qtype() == Rtype::A)
+            .map(|q| a_record(q.qname(), 300))
+            .collect();
+        let body = response(&query, Rcode::NoError, answers, Vec::new());
+        respond(&req, 200, body)
+    }
+
+    pub fn upstream(url: &str, weight: u32) -> Upstream {
+        Upstream {
+            url: url.to_string(),
+            weight,
+            format: UpstreamFormat::Wireformat,
+        }
+    }
+
+    // Options for a client without cache, overrides or anything else local
+    pub fn client_options(upstream_urls: Vec<Upstream>) -> ClientOptions {
+        ClientOptions {
+            upstream_urls,
+            forwarders: HashMap::new(),
+            upstreams_by_type: HashMap::new(),
+            cache: None,
+            override_resolver: OverrideResolver::new(OverrideOptions::default()).unwrap().0,
+            rulesets: HashMap::new(),
+            ruleset_by_subnet: Vec::new(),
+            local_zones: LocalZones::new(HashMap::new()).unwrap(),
+            dns64: None,
+            any_mode: AnyMode::default(),
+            stale_if_error: false,
+            user_agent: "test".to_string(),
+            randomize_case: false,
+            cf_options: None,
+            max_upstream_response_bytes: u16::MAX as usize,
+            upstream_udp_size: crate::edns::DEFAULT_UDP_SIZE,
+            upstream_error_details: false,
+            query_strategy: QueryStrategy::default(),
+            retry_backoff_ms: 0,
+        }
+    }
+
+    // Also makes upstream selection and failure tracking deterministic
+    pub fn test_client(options: ClientOptions, fetch: FetchFn) -> Client {
+        crate::util::set_random(|| 0.0);
+        crate::util::set_now(|| 0.0);
+        let mut client = Client::new(options);
+        client.set_fetch(fetch);
+        client
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::*;
+    use super::*;
+    use crate::r#override::OverrideOptions;
+    use crate::util::block_on;
+    use std::collections::HashSet;
+
+    fn question(name: &str, rtype: Rtype) -> Question<Dname<Vec<u8>>> {
+        Question::new(name.parse().unwrap(), rtype, Class::In)
+    }
+
+    // Round-trip the answers through the wire format, as clients see them
+    fn to_wire(question: Question<Dname<Vec<u8>>>, result: QueryResult) -> Message<Vec<u8>> {
+        let mut builder = MessageBuilder::new_vec();
+        builder.header_mut().set_qr(true);
+        builder.header_mut().set_rcode(result.rcode);
+        let mut question_builder = builder.question();
+        question_builder.push(question).unwrap();
+        let mut answer_builder = question_builder.answer();
+        for r in result.answers {
+            answer_builder.push(r).unwrap();
+        }
+        Message::from_octets(answer_builder.into_message().into_octets()).unwrap()
+    }
+
+    #[test]
+    fn refused_any_is_a_single_hinfo() {
+        let q = question("example.com", Rtype::Any);
+        let msg = to_wire(q.clone(), Client::refuse_any(&q));
+        assert_eq!(msg.header().rcode(), Rcode::NoError);
+        assert_eq!(msg.header_counts().ancount(), 1);
+
+        let (answers, skipped) = Client::extract_answers(&msg).unwrap();
+        assert_eq!(skipped, 0);
+        assert_eq!(answers.len(), 1);
+        let hinfo = &answers[0];
+        assert_eq!(hinfo.owner(), q.qname());
+        assert_eq!(hinfo.rtype(), Rtype::Hinfo);
+        assert_eq!(hinfo.ttl(), REFUSED_ANY_TTL);
+        // CPU "RFC8482" and an empty OS (RFC 8482 section 4.2)
+        assert_eq!(
+            crate::util::owned_record_data_to_buffer(hinfo.data()).unwrap(),
+            b"\x07RFC8482\x00".to_vec()
+        );
+    }
+
+    #[test]
+    fn answers_get_the_question_casing_back() {
+        let q = question("www.Example.com", Rtype::A);
+        crate::util::set_random(|| 0.0);
+        let randomized = Client::randomize_case(&q);
+        assert_eq!(randomized.qname().to_string(), "WWW.EXAMPLE.COM");
+
+        let data = AllRecordData::A(domain::rdata::A::new("192.0.2.1".parse().unwrap()));
+        let answers = vec![
+            Record::new(randomized.qname().clone(), Class::In, 300, data.clone()),
+            Record::new("OTHER.example.com".parse().unwrap(), Class::In, 300, data),
+        ];
+        let restored = Client::with_question_case(answers, &[q]);
+        assert_eq!(restored[0].owner().to_string(), "www.Example.com");
+        // Names other than the question's are left alone
+        assert_eq!(restored[1].owner().to_string(), "OTHER.example.com");
+    }
+
+    #[test]
+    fn select_upstream_follows_weights() {
+        let upstreams = [
+            upstream("https://a.example/dns-query", 1),
+            upstream("https://disabled.example/dns-query", 0),
+            upstream("https://b.example/dns-query", 3),
+        ];
+        let selected = |random: fn() -> f64| {
+            crate::util::set_random(random);
+            Client::select_upstream(&upstreams).unwrap().url.clone()
+        };
+        assert_eq!(selected(|| 0.0), "https://a.example/dns-query");
+        assert_eq!(selected(|| 0.25), "https://b.example/dns-query");
+        assert_eq!(selected(|| 0.999), "https://b.example/dns-query");
+    }
+
+    #[test]
+    fn select_upstream_needs_a_usable_upstream() {
+        let upstreams = [upstream("https://disabled.example/dns-query", 0)];
+        assert!(Client::select_upstream(&upstreams).is_err());
+        assert!(Client::select_upstream(&[]).is_err());
+    }
+
+    #[test]
+    fn upstream_answers_are_passed_on() {
+        let client = test_client(
+            client_options(vec![upstream("https://dns.example/dns-query", 1)]),
+            fetch_a,
+        );
+        let q = question("www.example.com", Rtype::A);
+        let msg = Client::build_query(vec![q.clone()], false, 1232).unwrap();
+        let result = block_on(client.query_selected_upstream(
+            &client.upstream_urls[0],
+            msg,
+            &[q.clone()],
+            false,
+        ))
+        .unwrap();
+        assert_eq!(result.rcode, Rcode::NoError);
+        assert_eq!(result.answers.len(), 1);
+        assert_eq!(result.answers[0].owner(), q.qname());
+        assert_eq!(
+            result.upstream.as_deref(),
+            Some("https://dns.example/dns-query")
+        );
+        assert_eq!(take_requests(), vec!["https://dns.example/dns-query"]);
+    }
+
+    fn fetch_unavailable(req: UpstreamRequest<'_>) -> FetchResult<'_> {
+        respond(&req, 503, b"try again later".to_vec())
+    }
+
+    #[test]
+    fn upstream_server_errors_are_failures() {
+        let client = test_client(
+            client_options(vec![upstream("https://dns.example/dns-query", 1)]),
+            fetch_unavailable,
+        );
+        let q = question("www.example.com", Rtype::A);
+        let msg = Client::build_query(vec![q.clone()], false, 1232).unwrap();
+        let result =
+            block_on(client.query_selected_upstream(&client.upstream_urls[0], msg, &[q], false));
+        assert_eq!(result.err().unwrap(), "Unknown response status 503");
+    }
+
+    // The primary is down, everything else works
+    fn fetch_primary_down(req: UpstreamRequest<'_>) -> FetchResult<'_> {
+        if req.url.starts_with("https://primary.example/") {
+            respond(&req, 503, Vec::new())
+        } else {
+            fetch_a(req)
+        }
+    }
+
+    #[test]
+    fn priority_falls_back_to_healthy_upstreams() {
+        let mut options = client_options(vec![
+            upstream("https://primary.example/dns-query", 1),
+            upstream("https://secondary.example/dns-query", 1),
+        ]);
+        options.query_strategy = QueryStrategy::Priority;
+        let client = test_client(options, fetch_primary_down);
+        let ctx = QueryContext::default();
+
+        let q = question("www.example.com", Rtype::A);
+        let result = block_on(client.query(vec![q.clone()], &ctx)).unwrap();
+        assert_eq!(
+            result.upstream.as_deref(),
+            Some("https://secondary.example/dns-query")
+        );
+        assert_eq!(
+            take_requests(),
+            vec![
+                "https://primary.example/dns-query",
+                "https://secondary.example/dns-query"
+            ]
+        );
+
+        // The failed primary is tried last for a while
+        let result = block_on(client.query(vec![q], &ctx)).unwrap();
+        assert_eq!(
+            result.upstream.as_deref(),
+            Some("https://secondary.example/dns-query")
+        );
+        assert_eq!(take_requests(), vec!["https://secondary.example/dns-query"]);
+    }
+
+    // An authoritative-only server, which can't recurse
+    fn fetch_no_recursion(req: UpstreamRequest<'_>) -> FetchResult<'_> {
+        let query = query_of(&req);
+        let mut body = response(&query, Rcode::NoError, Vec::new(), Vec::new());
+        // Clear RA, the top bit of the fourth header byte
+        body[3] &= 0x7f;
+        respond(&req, 200, body)
+    }
+
+    #[test]
+    fn upstreams_without_recursion_are_failures() {
+        let client = test_client(
+            client_options(vec![upstream("https://dns.example/dns-query", 1)]),
+            fetch_no_recursion,
+        );
+        let q = question("www.example.com", Rtype::A);
+        let msg = Client::build_query(vec![q.clone()], false, 1232).unwrap();
+        let result =
+            block_on(client.query_selected_upstream(&client.upstream_urls[0], msg, &[q], false));
+        assert_eq!(
+            result.err().unwrap(),
+            "Upstream https://dns.example/dns-query does not offer recursion"
+        );
+    }
+
+    // A JSON API upstream leaving out the RA flag, like some do
+    fn fetch_json_without_ra(req: UpstreamRequest<'_>) -> FetchResult<'_> {
+        let body = br#"{"Status": 0,
+            "Answer": [{"name": "www.example.com.", "type": 1, "TTL": 300, "data": "192.0.2.1"}]}"#;
+        respond(&req, 200, body.to_vec())
+    }
+
+    #[test]
+    fn json_upstreams_without_ra_are_recursive() {
+        let client = test_client(
+            client_options(vec![Upstream {
+                url: "https://dns.example/resolve".to_string(),
+                weight: 1,
+                format: UpstreamFormat::Json,
+            }]),
+            fetch_json_without_ra,
+        );
+        let q = question("www.example.com", Rtype::A);
+        let msg = Client::build_query(vec![q.clone()], false, 1232).unwrap();
+        let result =
+            block_on(client.query_selected_upstream(&client.upstream_urls[0], msg, &[q], false))
+                .unwrap();
+        assert_eq!(result.answers.len(), 1);
+        assert_eq!(result.answers[0].data().to_string(), "192.0.2.1");
+    }
+
+    #[test]
+    fn upstream_urls_are_validated() {
+        let check = |url: &str| Upstream::from_url(url.to_string()).map(|u| u.url);
+        assert!(check("https://[2001:db8::1]:53/dns-query").is_ok());
+        assert!(check("https://[2001:db8::1]/dns-query").is_ok());
+        assert!(check("HTTPS://user@dns.example:8443/dns-query?x=1").is_ok());
+        assert_eq!(
+            check("https://[2001:db8::zz]/dns-query").err().unwrap(),
+            "Invalid IPv6 host in upstream URL https://[2001:db8::zz]/dns-query"
+        );
+        assert_eq!(
+            check("https://[2001:db8::1]53/dns-query").err().unwrap(),
+            "Invalid IPv6 host in upstream URL https://[2001:db8::1]53/dns-query"
+        );
+        assert_eq!(
+            check("https://[2001:db8::1]:65536/dns-query")
+                .err()
+                .unwrap(),
+            "Invalid port in upstream URL https://[2001:db8::1]:65536/dns-query"
+        );
+        assert_eq!(
+            check("https://:443/dns-query").err().unwrap(),
+            "Upstream URL https://:443/dns-query has no host"
+        );
+        assert_eq!(
+            check("ftp://dns.example/").err().unwrap(),
+            "Upstream URL ftp://dns.example/ is not HTTP(S)"
+        );
+    }
+
+    #[test]
+    fn mixed_batches_keep_question_order() {
+        let mut options = client_options(vec![upstream("https://dns.example/dns-query", 1)]);
+        options.override_resolver = OverrideResolver::new(OverrideOptions {
+            overrides: serde_json::from_value(serde_json::json!({
+                "local.example": "192.0.2.7",
+            }))
+            .unwrap(),
+            ..Default::default()
+        })
+        .unwrap()
+        .0;
+        let client = test_client(options, fetch_a);
+        let questions = vec![
+            question("first.example", Rtype::A),
+            question("local.example", Rtype::A),
+            question("last.example", Rtype::A),
+        ];
+        let result = block_on(client.query(questions, &QueryContext::default())).unwrap();
+        let answers: Vec<(String, String)> = result
+            .answers
+            .iter()
+            .map(|r| (r.owner().to_string(), r.data().to_string()))
+            .collect();
+        assert_eq!(
+            answers,
+            vec![
+                ("first.example".to_string(), "192.0.2.1".to_string()),
+                ("local.example".to_string(), "192.0.2.7".to_string()),
+                ("last.example".to_string(), "192.0.2.1".to_string()),
+            ]
+        );
+        // Only the other two went upstream, one at a time
+        assert_eq!(take_requests().len(), 2);
+    }
+
+    #[test]
+    fn pinned_types_use_their_own_upstreams() {
+        let mut options = client_options(vec![upstream("https://dns.example/dns-query", 1)]);
+        options.upstreams_by_type.insert(
+            "65".to_string(),
+            vec![upstream("https://https-rr.example/dns-query", 1)],
+        );
+        let client = test_client(options, fetch_a);
+        let ctx = QueryContext::default();
+        let urls = |rtype| -> Vec<String> {
+            client
+                .upstreams_for(&question("www.example.com", rtype), &ctx)
+                .iter()
+                .map(|u| u.url.clone())
+                .collect()
+        };
+        assert_eq!(
+            urls(Rtype::from_int(65)),
+            vec!["https://https-rr.example/dns-query"]
+        );
+        assert_eq!(urls(Rtype::A), vec!["https://dns.example/dns-query"]);
+    }
+
+    #[test]
+    fn malformed_records_are_skipped_and_counted() {
+        let q = question("www.example.com", Rtype::A);
+        // An A record must have exactly 4 bytes of data
+        let malformed = Record::new(
+            q.qname().clone(),
+            Class::In,
+            300,
+            AllRecordData::Other(UnknownRecordData::from_octets(
+                Rtype::A,
+                vec![192, 0, 2, 1, 0],
+            )),
+        );
+        let result = QueryResult::new(
+            Rcode::NoError,
+            vec![
+                a_record(q.qname(), 300),
+                malformed,
+                a_record(q.qname(), 600),
+            ],
+            Vec::new(),
+        );
+        let msg = to_wire(q, result);
+        let (answers, skipped) = Client::extract_answers(&msg).unwrap();
+        assert_eq!(skipped, 1);
+        assert_eq!(answers.len(), 2);
+        assert_eq!(answers[0].ttl(), 300);
+        assert_eq!(answers[1].ttl(), 600);
+    }
+
+    fn fetch_wrong_id(req: UpstreamRequest<'_>) -> FetchResult<'_> {
+        let mut body = response(&query_of(&req), Rcode::NoError, Vec::new(), Vec::new());
+        body[0] ^= 0xff;
+        respond(&req, 200, body)
+    }
+
+    fn fetch_wrong_question(req: UpstreamRequest<'_>) -> FetchResult<'_> {
+        let other = question("other.example.com", Rtype::A);
+        let query = Client::build_query(vec![other], false, 1232).unwrap();
+        let body = response(&query, Rcode::NoError, Vec::new(), Vec::new());
+        respond(&req, 200, body)
+    }
+
+    #[test]
+    fn mismatched_responses_are_rejected() {
+        let cases: [(FetchFn, bool); 3] = [
+            (fetch_a, true),
+            (fetch_wrong_id, false),
+            (fetch_wrong_question, false),
+        ];
+        for (fetch, matches) in cases.iter() {
+            let client = test_client(
+                client_options(vec![upstream("https://dns.example/dns-query", 1)]),
+                *fetch,
+            );
+            let msg = Client::build_query(vec![question("www.example.com", Rtype::A)], false, 1232)
+                .unwrap();
+            let result = block_on(client.do_query("https://dns.example/dns-query", msg));
+            if *matches {
+                assert!(result.is_ok());
+            } else {
+                assert_eq!(
+                    result.err().unwrap(),
+                    "Upstream response does not match query"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn retries_are_additional_attempts() {
+        let attempts = |retries, fetch: FetchFn| {
+            let client = test_client(
+                client_options(vec![upstream("https://dns.example/dns-query", 1)]),
+                fetch,
+            );
+            let q = question("www.example.com", Rtype::A);
+            let result =
+                block_on(client.query_with_retry(vec![q], retries, &QueryContext::default()));
+            (result.is_ok(), take_requests().len())
+        };
+        assert_eq!(attempts(0, fetch_unavailable), (false, 1));
+        assert_eq!(attempts(3, fetch_unavailable), (false, 4));
+        // No retries once an attempt succeeds
+        assert_eq!(attempts(3, fetch_a), (true, 1));
+    }
+
+    #[test]
+    fn passthrough_names_escape_blocked_suffixes() {
+        let mut options = client_options(vec![upstream("https://dns.example/dns-query", 1)]);
+        let mut blocklist = HashSet::new();
+        blocklist.insert("cdn.ads.example".to_string());
+        options.override_resolver = OverrideResolver::new(OverrideOptions {
+            overrides: serde_json::from_value(serde_json::json!({
+                "*.ads.example": "0.0.0.0",
+                "cdn.ads.example": "passthrough",
+            }))
+            .unwrap(),
+            extra_block_list: blocklist,
+            ..Default::default()
+        })
+        .unwrap()
+        .0;
+        let client = test_client(options, fetch_a);
+        let ctx = QueryContext::default();
+
+        let q = question("cdn.ads.example", Rtype::A);
+        let result = block_on(client.query(vec![q], &ctx)).unwrap();
+        assert_eq!(
+            result.upstream.as_deref(),
+            Some("https://dns.example/dns-query")
+        );
+        assert_eq!(result.answers[0].data().to_string(), "192.0.2.1");
+        assert_eq!(take_requests().len(), 1);
+
+        // Everything else under the suffix is still blocked locally
+        let q = question("other.ads.example", Rtype::A);
+        let result = block_on(client.query(vec![q], &ctx)).unwrap();
+        assert_eq!(result.upstream, None);
+        assert_eq!(result.answers[0].data().to_string(), "0.0.0.0");
+        assert!(take_requests().is_empty());
+    }
+
+    #[test]
+    fn zero_ttl_overrides_are_served_but_not_cached() {
+        let mut options = client_options(vec![upstream("https://dns.example/dns-query", 1)]);
+        options.override_resolver = OverrideResolver::new(OverrideOptions {
+            overrides: serde_json::from_value(serde_json::json!({
+                "volatile.example": "192.0.2.7",
+            }))
+            .unwrap(),
+            override_ttl: 0,
+            ttl_clamp: crate::util::TtlClamp { min: 60, max: None },
+            ..Default::default()
+        })
+        .unwrap()
+        .0;
+        let client = test_client(options, fetch_a);
+        let ctx = QueryContext::default();
+
+        // Answered locally every time, never from the cache or upstream
+        for _ in 0..2 {
+            let q = question("volatile.example", Rtype::A);
+            let result = block_on(client.query(vec![q], &ctx)).unwrap();
+            assert!(!result.cache_hit);
+            assert_eq!(result.upstream, None);
+            assert_eq!(result.answers.len(), 1);
+            assert_eq!(result.answers[0].ttl(), 0);
+            assert_eq!(result.answers[0].data().to_string(), "192.0.2.7");
+        }
+        assert!(take_requests().is_empty());
+    }
+
+    // Knows AAAA records only for names starting with "v6."
+    fn fetch_dual_stack(req: UpstreamRequest<'_>) -> FetchResult<'_> {
+        let query = query_of(&req);
+        let answers = questions_of(&query)
+            .iter()
+            .filter_map(|q| match q.qtype() {
+                Rtype::A => Some(a_record(q.qname(), 300)),
+                Rtype::Aaaa if q.qname().to_string().starts_with("v6.") => Some(Record::new(
+                    q.qname().clone(),
+                    Class::In,
+                    300,
+                    AllRecordData::Aaaa(domain::rdata::Aaaa::new("2001:db8::1".parse().unwrap())),
+                )),
+                _ => None,
+            })
+            .collect();
+        let body = response(&query, Rcode::NoError, answers, Vec::new());
+        respond(&req, 200, body)
+    }
+
+    #[test]
+    fn dns64_synthesizes_per_question() {
+        let mut options = client_options(vec![upstream("https://dns.example/dns-query", 1)]);
+        options.dns64 =
+            Some(Dns64::new(serde_json::from_value(serde_json::json!({})).unwrap()).unwrap());
+        let client = test_client(options, fetch_dual_stack);
+        let questions = vec![
+            question("v6.example", Rtype::Aaaa),
+            question("v4.example", Rtype::Aaaa),
+        ];
+        let result = block_on(client.query(questions, &QueryContext::default())).unwrap();
+        let answers: Vec<(String, String)> = result
+            .answers
+            .iter()
+            .map(|r| (r.owner().to_string(), r.data().to_string()))
+            .collect();
+        assert_eq!(
+            answers,
+            vec![
+                ("v6.example".to_string(), "2001:db8::1".to_string()),
+                ("v4.example".to_string(), "64:ff9b::c000:201".to_string()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,198 @@
+use crate::util::OwnedRecordData;
+use domain::base::{Dname, Record, Rtype};
+use domain::rdata::{Aaaa, AllRecordData};
+use serde::{Deserialize, Serialize};
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+fn default_prefix() -> String {
+    "64:ff9b::/96".to_string()
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct Dns64Options {
+    // The NAT64 prefix to embed IPv4 addresses into, in CIDR notation
+    // Only the prefix lengths allowed by RFC 6052 are accepted
+    #[serde(default = "default_prefix")]
+    prefix: String,
+}
+
+// Synthesizes AAAA records from A records (RFC 6147) for clients
+// sitting on IPv6-only networks behind a NAT64 gateway
+pub struct Dns64 {
+    prefix: [u8; 16],
+    prefix_len: usize, // in bytes
+}
+
+impl Dns64 {
+    pub fn new(options: Dns64Options) -> Result<Dns64, String> {
+        let mut parts = options.prefix.splitn(2, '/');
+        let addr: Ipv6Addr = parts
+            .next()
+            .unwrap()
+            .parse()
+            .map_err(|_| "Invalid DNS64 prefix address".to_string())?;
+        let prefix_len: usize = parts
+            .next()
+            .unwrap_or("96")
+            .parse()
+            .map_err(|_| "Invalid DNS64 prefix length".to_string())?;
+        match prefix_len {
+            32 | 40 | 48 | 56 | 64 | 96 => (),
+            _ => return Err("Unsupported DNS64 prefix length".to_string()),
+        }
+        Ok(Dns64 {
+            prefix: addr.octets(),
+            prefix_len: prefix_len / 8,
+        })
+    }
+
+    // Embed an IPv4 address into the prefix following RFC 6052, Section 2.2
+    // Bits 64 to 71 (the "u" octet) must be left as zero, so the address
+    // is split around it for prefixes shorter than /96
+    fn embed(&self, addr: &Ipv4Addr) -> Ipv6Addr {
+        let mut octets = [0u8; 16];
+        octets[..self.prefix_len].copy_from_slice(&self.prefix[..self.prefix_len]);
+        let mut pos = self.prefix_len;
+        for b in addr.octets().iter() {
+            if pos == 8 {
+                pos += 1;
+            }
+            octets[pos] = *b;
+            pos += 1;
+        }
+        Ipv6Addr::from(octets)
+    }
+
+    // Build AAAA records out of the A records answering `qname`
+    // The owner names are kept, so that CNAME chains in the original
+    // answers still lead to the synthesized records
+    pub fn synthesize(
+        &self,
+        qname: &Dname<Vec<u8>>,
+        answers: &[Record<Dname<Vec<u8>>, OwnedRecordData>],
+    ) -> Vec<Record<Dname<Vec<u8>>, OwnedRecordData>> {
+        let mut ret = Vec::new();
+        for r in chain_of(qname, answers) {
+            if let AllRecordData::A(data) = r.data() {
+                ret.push(Record::new(
+                    r.owner().clone(),
+                    r.class(),
+                    r.ttl(),
+                    AllRecordData::Aaaa(Aaaa::new(self.embed(&data.addr()))),
+                ));
+            }
+        }
+        ret
+    }
+
+    // Whether a DNS64 companion query is needed for the AAAA question of
+    // `qname`, i.e. none of the answers for it are AAAA records
+    // Answers of the other questions in the same query don't count
+    pub fn needs_synthesis(
+        qname: &Dname<Vec<u8>>,
+        answers: &[Record<Dname<Vec<u8>>, OwnedRecordData>],
+    ) -> bool {
+        !chain_of(qname, answers)
+            .iter()
+            .any(|r| r.rtype() == Rtype::Aaaa)
+    }
+}
+
+// The answers owned by `qname` or by any name its CNAME chain leads to
+fn chain_of<'a>(
+    qname: &Dname<Vec<u8>>,
+    answers: &'a [Record<Dname<Vec<u8>>, OwnedRecordData>],
+) -> Vec<&'a Record<Dname<Vec<u8>>, OwnedRecordData>> {
+    let mut names = vec![qname.clone()];
+    // Every round follows at least one more CNAME, so this terminates
+    // even if the answers contain a loop
+    loop {
+        let mut targets = Vec::new();
+        for r in answers.iter().filter(|r| names.contains(r.owner())) {
+            if let AllRecordData::Cname(cname) = r.data() {
+                if !names.contains(cname.cname()) && !targets.contains(cname.cname()) {
+                    targets.push(cname.cname().clone());
+                }
+            }
+        }
+        if targets.is_empty() {
+            break;
+        }
+        names.append(&mut targets);
+    }
+    answers
+        .iter()
+        .filter(|r| names.contains(r.owner()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use domain::base::iana::Class;
+    use domain::rdata::{Cname, A};
+
+    fn dns64(prefix: &str) -> Dns64 {
+        Dns64::new(Dns64Options {
+            prefix: prefix.to_string(),
+        })
+        .unwrap()
+    }
+
+    fn record(owner: &str, data: OwnedRecordData) -> Record<Dname<Vec<u8>>, OwnedRecordData> {
+        Record::new(owner.parse().unwrap(), Class::In, 300, data)
+    }
+
+    fn a(owner: &str, addr: &str) -> Record<Dname<Vec<u8>>, OwnedRecordData> {
+        record(owner, AllRecordData::A(A::new(addr.parse().unwrap())))
+    }
+
+    #[test]
+    fn embedding_skips_the_u_octet() {
+        let addr = "192.0.2.33".parse().unwrap();
+        assert_eq!(
+            dns64("64:ff9b::/96").embed(&addr),
+            "64:ff9b::c000:221".parse::<Ipv6Addr>().unwrap()
+        );
+        // RFC 6052, Section 2.4: bits 64 to 71 stay zero
+        assert_eq!(
+            dns64("2001:db8:122::/48").embed(&addr),
+            "2001:db8:122:c000:2:2100::".parse::<Ipv6Addr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn synthesis_is_decided_per_question() {
+        let v6: Dname<Vec<u8>> = "v6.example".parse().unwrap();
+        let v4: Dname<Vec<u8>> = "v4.example".parse().unwrap();
+        let answers = vec![
+            record(
+                "v6.example",
+                AllRecordData::Aaaa(Aaaa::new("2001:db8::1".parse().unwrap())),
+            ),
+            record(
+                "v4.example",
+                AllRecordData::Cname(Cname::new("target.example".parse().unwrap())),
+            ),
+        ];
+        assert!(!Dns64::needs_synthesis(&v6, &answers));
+        assert!(Dns64::needs_synthesis(&v4, &answers));
+
+        // Only A records along the chain of the question are used
+        let companion = vec![
+            record(
+                "v4.example",
+                AllRecordData::Cname(Cname::new("target.example".parse().unwrap())),
+            ),
+            a("target.example", "192.0.2.1"),
+            a("unrelated.example", "192.0.2.2"),
+        ];
+        let synthesized = dns64("64:ff9b::/96").synthesize(&v4, &companion);
+        assert_eq!(synthesized.len(), 1);
+        assert_eq!(
+            synthesized[0].owner(),
+            &"target.example".parse::<Dname<Vec<u8>>>().unwrap()
+        );
+        assert_eq!(synthesized[0].data().to_string(), "64:ff9b::c000:201");
+    }
+}
@@ -0,0 +1,155 @@
+use domain::base::iana::Class;
+use domain::base::{rdata::UnknownRecordData, Dname, Record, Rtype};
+
+// The UDP payload size we advertise in our OPT records
+// (the DNS Flag Day 2020 recommendation)
+pub const DEFAULT_UDP_SIZE: u16 = 1232;
+
+// EDNS(0) option code for padding (RFC 7830)
+const OPTION_PADDING: u16 = 12;
+// EDNS(0) option code for Extended DNS Errors (RFC 8914)
+const OPTION_EDE: u16 = 15;
+
+// EDE info codes we use
+pub const EDE_BLOCKED: u16 = 15;
+pub const EDE_NO_REACHABLE_AUTHORITY: u16 = 22;
+pub const EDE_NETWORK_ERROR: u16 = 23;
+
+// Fixed part of an OPT record on the wire: root owner name (1),
+// type (2), class (2), TTL (4) and RDLENGTH (2)
+const OPT_RECORD_HEADER_LEN: usize = 11;
+// Option code (2) and option length (2)
+const OPTION_HEADER_LEN: usize = 4;
+
+// The DO ("DNSSEC OK") bit in the flags part of the OPT TTL (RFC 3225)
+const FLAG_DO: u32 = 0x8000;
+
+// A minimal EDNS(0) OPT pseudo-record (RFC 6891)
+// The record is built by hand and emitted as UnknownRecordData
+pub struct Opt {
+    udp_size: u16,
+    dnssec_ok: bool,
+    options: Vec<(u16, Vec<u8>)>,
+}
+
+impl Opt {
+    pub fn new(udp_size: u16) -> Opt {
+        Opt {
+            udp_size,
+            dnssec_ok: false,
+            options: Vec::new(),
+        }
+    }
+
+    pub fn set_dnssec_ok(&mut self, dnssec_ok: bool) {
+        self.dnssec_ok = dnssec_ok;
+    }
+
+    pub fn push_option(&mut self, code: u16, data: Vec<u8>) {
+        self.options.push((code, data));
+    }
+
+    // Add an Extended DNS Error with optional UTF-8 extra text
+    pub fn push_ede(&mut self, info_code: u16, extra_text: &str) {
+        let mut data = info_code.to_be_bytes().to_vec();
+        data.extend_from_slice(extra_text.as_bytes());
+        self.push_option(OPTION_EDE, data);
+    }
+
+    // Length of the whole OPT record on the wire
+    pub fn wire_len(&self) -> usize {
+        OPT_RECORD_HEADER_LEN
+            + self
+                .options
+                .iter()
+                .map(|(_, data)| OPTION_HEADER_LEN + data.len())
+                .sum::<usize>()
+    }
+
+    // Add a padding option so that a message of `msg_len` octets (excluding
+    // this OPT record) ends up with a length that is a multiple of `block_size`
+    // once this record is appended (RFC 7830, RFC 8467)
+    // The padded message is never made longer than `max_len`; if the next
+    // block boundary is beyond that, the message is padded up to `max_len`
+    pub fn pad_to_block(&mut self, msg_len: usize, block_size: usize, max_len: usize) {
+        if block_size == 0 {
+            return;
+        }
+        let unpadded_len = msg_len + self.wire_len() + OPTION_HEADER_LEN;
+        if unpadded_len > max_len {
+            return;
+        }
+        let padding_len = (block_size - unpadded_len % block_size) % block_size;
+        let padding_len = padding_len.min(max_len - unpadded_len);
+        self.push_option(OPTION_PADDING, vec![0; padding_len]);
+    }
+
+    pub fn to_record(&self) -> Record<Dname<Vec<u8>>, UnknownRecordData<Vec<u8>>> {
+        let mut rdata = Vec::new();
+        for (code, data) in self.options.iter() {
+            rdata.extend_from_slice(&code.to_be_bytes());
+            rdata.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            rdata.extend_from_slice(data);
+        }
+        // The class field carries the UDP payload size, and the TTL field
+        // carries the extended RCODE, version (both zero here) and flags
+        Record::new(
+            Dname::root_vec(),
+            Class::Int(self.udp_size),
+            if self.dnssec_ok { FLAG_DO } else { 0 },
+            UnknownRecordData::from_octets(Rtype::Opt, rdata),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ede_options_are_laid_out_as_in_rfc_8914() {
+        let mut opt = Opt::new(DEFAULT_UDP_SIZE);
+        opt.push_ede(EDE_BLOCKED, "");
+        opt.push_ede(17, "");
+        opt.push_ede(EDE_NO_REACHABLE_AUTHORITY, "");
+        opt.push_ede(EDE_NETWORK_ERROR, "timed out");
+        let record = opt.to_record();
+        let mut expected = Vec::new();
+        for code in [15u16, 17, 22].iter() {
+            // OPTION-CODE 15, OPTION-LENGTH 2, INFO-CODE
+            expected.extend_from_slice(&[0, 15, 0, 2]);
+            expected.extend_from_slice(&code.to_be_bytes());
+        }
+        expected.extend_from_slice(&[0, 15, 0, 11, 0, 23]);
+        expected.extend_from_slice(b"timed out");
+        assert_eq!(record.data().data(), &expected);
+        assert_eq!(record.class(), Class::Int(DEFAULT_UDP_SIZE));
+        assert_eq!(opt.wire_len(), OPT_RECORD_HEADER_LEN + expected.len());
+    }
+
+    #[test]
+    fn do_bit_is_in_the_ttl_flags() {
+        let mut opt = Opt::new(DEFAULT_UDP_SIZE);
+        assert_eq!(opt.to_record().ttl(), 0);
+        opt.set_dnssec_ok(true);
+        assert_eq!(opt.to_record().ttl(), 0x8000);
+    }
+
+    #[test]
+    fn padding_fills_up_to_the_block_size() {
+        for msg_len in [12, 100, 457, 468, 469].iter() {
+            let mut opt = Opt::new(DEFAULT_UDP_SIZE);
+            opt.push_ede(EDE_BLOCKED, "");
+            opt.pad_to_block(*msg_len, 468, 4096);
+            assert_eq!((msg_len + opt.wire_len()) % 468, 0);
+        }
+        // Never beyond the maximum length, even if that misses the block
+        let mut opt = Opt::new(DEFAULT_UDP_SIZE);
+        opt.pad_to_block(470, 468, 500);
+        assert_eq!(470 + opt.wire_len(), 500);
+        // And no padding at all if even the empty option doesn't fit
+        let mut opt = Opt::new(DEFAULT_UDP_SIZE);
+        opt.pad_to_block(490, 468, 500);
+        assert_eq!(opt.wire_len(), OPT_RECORD_HEADER_LEN);
+    }
+}
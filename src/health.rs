@@ -0,0 +1,95 @@
+use crate::client::QueryResult;
+use crate::util::OwnedRecordData;
+use domain::base::{iana::Rcode, rdata::RecordData, Dname, Question, Record, Rtype};
+use domain::rdata::{Aaaa, AllRecordData, A};
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+
+#[derive(Deserialize, Serialize)]
+pub struct HealthCheckOptions {
+    // The special name to answer locally, e.g. `health.resolver`
+    name: String,
+    // An IP address is answered as A / AAAA, anything else as TXT
+    value: String,
+    #[serde(default)]
+    ttl: u32,
+}
+
+// Answers a fixed, configured name locally so that monitoring systems
+// can check the resolver end-to-end without depending on any upstream
+pub struct HealthCheck {
+    name: String,
+    rdata: OwnedRecordData,
+    ttl: u32,
+}
+
+impl HealthCheck {
+    pub fn new(options: HealthCheckOptions) -> Result<HealthCheck, String> {
+        let rdata = match options.value.parse::<IpAddr>() {
+            Ok(IpAddr::V4(addr)) => AllRecordData::A(A::new(addr)),
+            Ok(IpAddr::V6(addr)) => AllRecordData::Aaaa(Aaaa::new(addr)),
+            Err(_) => AllRecordData::Txt(crate::util::text_to_txt(&options.value)?),
+        };
+        Ok(HealthCheck {
+            name: crate::util::normalize_name(&options.name),
+            rdata,
+            ttl: options.ttl,
+        })
+    }
+
+    // Answer the questions if all of them are for the health check name
+    // Questions for other types than the configured record get NODATA
+    pub fn try_answer(&self, questions: &[Question<Dname<Vec<u8>>>]) -> Option<QueryResult> {
+        let mut answers = Vec::new();
+        for q in questions {
+            if crate::util::normalize_name(&q.qname().to_string()) != self.name {
+                return None;
+            }
+            if q.qtype() != self.rdata.rtype() && q.qtype() != Rtype::Any {
+                continue;
+            }
+            answers.push(Record::new(
+                q.qname().clone(),
+                q.qclass(),
+                self.ttl,
+                self.rdata.clone(),
+            ));
+        }
+        Some(QueryResult::new(Rcode::NoError, answers, Vec::new()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use domain::base::iana::Class;
+
+    fn answer(value: &str, rtype: Rtype) -> QueryResult {
+        let health_check = HealthCheck::new(HealthCheckOptions {
+            name: "health.resolver".to_string(),
+            value: value.to_string(),
+            ttl: 0,
+        })
+        .unwrap();
+        let q = Question::new("health.resolver".parse().unwrap(), rtype, Class::In);
+        health_check.try_answer(&[q]).unwrap()
+    }
+
+    #[test]
+    fn matching_type_is_answered() {
+        let result = answer("192.0.2.1", Rtype::A);
+        assert_eq!(result.rcode, Rcode::NoError);
+        assert_eq!(result.answers.len(), 1);
+        assert_eq!(result.answers[0].rtype(), Rtype::A);
+    }
+
+    #[test]
+    fn other_types_get_nodata() {
+        for rtype in [Rtype::Aaaa, Rtype::Mx, Rtype::Txt].iter() {
+            let result = answer("192.0.2.1", *rtype);
+            assert_eq!(result.rcode, Rcode::NoError);
+            assert!(result.answers.is_empty());
+        }
+        assert!(answer("ok", Rtype::A).answers.is_empty());
+    }
+}
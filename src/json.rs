@@ -0,0 +1,526 @@
+use crate::client::QueryResult;
+use crate::util::OwnedRecordData;
+use domain::base::{
+    iana::{Class, Rcode},
+    rdata::RecordData,
+    serial::Serial,
+    Dname, Message, MessageBuilder, Question, Record, Rtype,
+};
+use domain::rdata::{Aaaa, AllRecordData, Cname, Mx, Ns, Ptr, Soa, Srv, Txt, A};
+use serde::{Deserialize, Serialize};
+
+// The record type of a JSON query, either numeric (`28`) or mnemonic (`AAAA`)
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum JsonQueryType {
+    Number(u16),
+    Mnemonic(String),
+}
+
+// A DNS query in the JSON API format, as used by Cloudflare and Google
+// i.e. `{"name": "example.com", "type": "AAAA"}`
+#[derive(Deserialize)]
+pub struct JsonQuery {
+    pub name: String,
+    #[serde(default, rename = "type")]
+    pub qtype: Option<JsonQueryType>,
+}
+
+impl JsonQuery {
+    pub fn from_json(json: &str) -> Result<JsonQuery, String> {
+        serde_json::from_str(json).map_err(|e| format!("Malformed JSON query: {}", e))
+    }
+
+    fn rtype(&self) -> Result<Rtype, String> {
+        match &self.qtype {
+            // Default to A when no type is given
+            None => crate::util::parse_qtype(""),
+            Some(JsonQueryType::Number(n)) => Ok(Rtype::from_int(*n)),
+            Some(JsonQueryType::Mnemonic(s)) => crate::util::parse_qtype(s),
+        }
+    }
+
+    // Build a wireformat query equivalent to this JSON query, so that
+    // the rest of the server can handle both formats the same way
+    pub fn to_message(&self) -> Result<Message<Vec<u8>>, String> {
+        // Names in JSON queries may be internationalized (U-labels), but
+        // they must be in the ASCII form (A-labels) on the wire
+        let qname = crate::util::idn_to_ascii(&self.name)?;
+        crate::util::validate_name(&qname)?;
+        let qname: Dname<Vec<u8>> = qname
+            .parse()
+            .map_err(|_| "Invalid domain name".to_string())?;
+
+        let mut builder = MessageBuilder::new_vec();
+        let header = builder.header_mut();
+        header.set_qr(false);
+        header.set_rd(true); // JSON queries are always recursive
+        let mut question_builder = builder.question();
+        question_builder
+            .push(Question::new(qname, self.rtype()?, Class::In))
+            .map_err(|_| "Size limit exceeded".to_string())?;
+        Ok(question_builder.into_message())
+    }
+}
+
+#[derive(Serialize)]
+struct JsonQuestion {
+    name: String,
+    #[serde(rename = "type")]
+    qtype: u16,
+}
+
+#[derive(Serialize)]
+struct JsonRecord {
+    name: String,
+    #[serde(rename = "type")]
+    rtype: u16,
+    #[serde(rename = "TTL")]
+    ttl: u32,
+    data: String,
+}
+
+// A DNS response in the JSON API format, compatible with Cloudflare and Google
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct JsonResponse {
+    status: u16,
+    #[serde(rename = "TC")]
+    tc: bool,
+    #[serde(rename = "RD")]
+    rd: bool,
+    #[serde(rename = "RA")]
+    ra: bool,
+    #[serde(rename = "AD")]
+    ad: bool,
+    #[serde(rename = "CD")]
+    cd: bool,
+    question: Vec<JsonQuestion>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    answer: Vec<JsonRecord>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    authority: Vec<JsonRecord>,
+}
+
+impl JsonResponse {
+    pub fn new(questions: &[Question<Dname<Vec<u8>>>], result: &QueryResult) -> JsonResponse {
+        JsonResponse {
+            status: result.rcode.to_int() as u16,
+            tc: result.truncated,
+            rd: true,
+            ra: true,
+            ad: false,
+            cd: false,
+            question: questions
+                .iter()
+                .map(|q| JsonQuestion {
+                    name: Self::render_name(q.qname()),
+                    qtype: q.qtype().to_int(),
+                })
+                .collect(),
+            answer: result.answers.iter().map(Self::render_record).collect(),
+            authority: result.authority.iter().map(Self::render_record).collect(),
+        }
+    }
+
+    pub fn to_json(&self) -> Result<Vec<u8>, String> {
+        serde_json::to_vec(self).map_err(|_| "Cannot serialize JSON response".to_string())
+    }
+
+    fn render_name(name: &Dname<Vec<u8>>) -> String {
+        format!("{}.", name)
+    }
+
+    fn render_record(record: &Record<Dname<Vec<u8>>, OwnedRecordData>) -> JsonRecord {
+        JsonRecord {
+            name: Self::render_name(record.owner()),
+            rtype: record.rtype().to_int(),
+            ttl: record.ttl(),
+            data: Self::render_data(record.data()),
+        }
+    }
+
+    // Quote a character-string, escaping quotes and backslashes, and also
+    // bytes that are not printable ASCII as `\DDD` (RFC 1035, Section 5.1)
+    // so that parse_txt on the other end gets back the exact same bytes
+    fn render_character_string(s: &[u8]) -> String {
+        let mut ret = String::from("\"");
+        for &b in s {
+            match b {
+                b'"' | b'\\' => {
+                    ret.push('\\');
+                    ret.push(b as char);
+                }
+                0x20..=0x7e => ret.push(b as char),
+                _ => ret.push_str(&format!("\\{:03}", b)),
+            }
+        }
+        ret.push('"');
+        ret
+    }
+
+    // Render record data in the master file format
+    fn render_data(data: &OwnedRecordData) -> String {
+        match data {
+            AllRecordData::Txt(txt) => txt
+                .iter()
+                .map(Self::render_character_string)
+                .collect::<Vec<_>>()
+                .join(" "),
+            // Anything we don't handle specially is rendered in the generic
+            // format of RFC 3597, i.e. `\# <length> <hex>`, so that unusual
+            // record types never fail the whole response
+            AllRecordData::Other(data) => {
+                let octets = data.data();
+                let hex: String = octets.iter().map(|b| format!("{:02x}", b)).collect();
+                if octets.len() == 0 {
+                    "\\# 0".to_string()
+                } else {
+                    format!("\\# {} {}", octets.len(), hex)
+                }
+            }
+            data => data.to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct JsonUpstreamRecord {
+    name: String,
+    #[serde(rename = "type")]
+    rtype: u16,
+    #[serde(rename = "TTL", default)]
+    ttl: u32,
+    data: String,
+}
+
+impl JsonUpstreamRecord {
+    fn to_record(&self) -> Result<Record<Dname<Vec<u8>>, OwnedRecordData>, String> {
+        let owner = self
+            .name
+            .parse()
+            .map_err(|_| format!("Invalid name in upstream JSON response: {}", self.name))?;
+        let data = Self::parse_data(Rtype::from_int(self.rtype), &self.data)?;
+        Ok(Record::new(owner, Class::In, self.ttl, data))
+    }
+
+    // Parse record data in the master file format, i.e. the counterpart of
+    // JsonResponse::render_data; types we don't know how to parse are only
+    // accepted in the generic format of RFC 3597
+    fn parse_data(rtype: Rtype, data: &str) -> Result<OwnedRecordData, String> {
+        let invalid = || {
+            format!(
+                "Invalid {} data in upstream JSON response: {}",
+                crate::util::rtype_to_string(rtype),
+                data
+            )
+        };
+        let fields: Vec<&str> = data.split_whitespace().collect();
+        if fields.first() == Some(&"\\#") {
+            let len: usize = fields
+                .get(1)
+                .and_then(|len| len.parse().ok())
+                .ok_or_else(invalid)?;
+            let hex = fields[2..].concat();
+            let octets = (0..hex.len())
+                .step_by(2)
+                .map(|i| {
+                    hex.get(i..i + 2)
+                        .and_then(|h| u8::from_str_radix(h, 16).ok())
+                })
+                .collect::<Option<Vec<u8>>>()
+                .ok_or_else(invalid)?;
+            if octets.len() != len {
+                return Err(invalid());
+            }
+            return crate::util::octets_to_owned_record_data(rtype, &octets);
+        }
+
+        let name = |i: usize| -> Result<Dname<Vec<u8>>, String> {
+            fields
+                .get(i)
+                .and_then(|f| f.parse().ok())
+                .ok_or_else(invalid)
+        };
+        let num16 = |i: usize| -> Result<u16, String> {
+            fields
+                .get(i)
+                .and_then(|f| f.parse().ok())
+                .ok_or_else(invalid)
+        };
+        let num32 = |i: usize| -> Result<u32, String> {
+            fields
+                .get(i)
+                .and_then(|f| f.parse().ok())
+                .ok_or_else(invalid)
+        };
+        Ok(match rtype {
+            Rtype::A => AllRecordData::A(A::new(data.trim().parse().map_err(|_| invalid())?)),
+            Rtype::Aaaa => {
+                AllRecordData::Aaaa(Aaaa::new(data.trim().parse().map_err(|_| invalid())?))
+            }
+            Rtype::Cname => AllRecordData::Cname(Cname::new(name(0)?)),
+            Rtype::Ns => AllRecordData::Ns(Ns::new(name(0)?)),
+            Rtype::Ptr => AllRecordData::Ptr(Ptr::new(name(0)?)),
+            Rtype::Mx => AllRecordData::Mx(Mx::new(num16(0)?, name(1)?)),
+            Rtype::Srv => AllRecordData::Srv(Srv::new(num16(0)?, num16(1)?, num16(2)?, name(3)?)),
+            Rtype::Soa => AllRecordData::Soa(Soa::new(
+                name(0)?,
+                name(1)?,
+                Serial(num32(2)?),
+                num32(3)?,
+                num32(4)?,
+                num32(5)?,
+                num32(6)?,
+            )),
+            Rtype::Txt => AllRecordData::Txt(Self::parse_txt(data).map_err(|_| invalid())?),
+            _ => return Err(invalid()),
+        })
+    }
+
+    // TXT data is a list of quoted character-strings, which may contain
+    // escapes (`\"`, `\\` or `\DDD`); some upstreams leave out the quotes
+    // for a single string, though
+    fn parse_txt(data: &str) -> Result<Txt<Vec<u8>>, String> {
+        let data = data.trim().as_bytes();
+        if data.first() != Some(&b'"') {
+            return crate::util::text_to_txt(&String::from_utf8_lossy(data));
+        }
+
+        let mut strings: Vec<Vec<u8>> = Vec::new();
+        let mut i = 0;
+        while i < data.len() {
+            match data[i] {
+                b'"' => {
+                    let mut s = Vec::new();
+                    i += 1;
+                    loop {
+                        match data.get(i) {
+                            Some(b'"') => break,
+                            Some(b'\\') => {
+                                let digits = data
+                                    .get(i + 1..i + 4)
+                                    .and_then(|d| std::str::from_utf8(d).ok()?.parse::<u8>().ok());
+                                match digits {
+                                    Some(b) => {
+                                        s.push(b);
+                                        i += 3;
+                                    }
+                                    None => {
+                                        s.push(*data.get(i + 1).ok_or("Unterminated escape")?);
+                                        i += 1;
+                                    }
+                                }
+                            }
+                            Some(b) => s.push(*b),
+                            None => return Err("Unterminated string".to_string()),
+                        }
+                        i += 1;
+                    }
+                    strings.push(s);
+                }
+                b if b.is_ascii_whitespace() => (),
+                _ => return Err("Unquoted string".to_string()),
+            }
+            i += 1;
+        }
+
+        let mut buf = Vec::new();
+        for s in strings {
+            if s.is_empty() {
+                buf.push(0);
+            }
+            for chunk in s.chunks(255) {
+                buf.push(chunk.len() as u8);
+                buf.extend_from_slice(chunk);
+            }
+        }
+        if buf.is_empty() {
+            buf.push(0);
+        }
+        Txt::from_slice(&buf).map_err(|_| "Cannot build TXT record".to_string())
+    }
+}
+
+fn default_ra() -> bool {
+    true
+}
+
+// A response of an upstream only offering the JSON API, i.e. the
+// counterpart of JsonResponse
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct JsonUpstreamResponse {
+    status: u16,
+    #[serde(rename = "TC", default)]
+    tc: bool,
+    // Upstreams offering the JSON API are always recursive, and some of
+    // them leave out the flag entirely
+    #[serde(rename = "RA", default = "default_ra")]
+    ra: bool,
+    #[serde(rename = "AD", default)]
+    ad: bool,
+    #[serde(default)]
+    answer: Vec<JsonUpstreamRecord>,
+    #[serde(default)]
+    authority: Vec<JsonUpstreamRecord>,
+}
+
+impl JsonUpstreamResponse {
+    pub fn from_json(json: &str) -> Result<JsonUpstreamResponse, String> {
+        serde_json::from_str(json).map_err(|e| format!("Malformed upstream JSON response: {}", e))
+    }
+
+    // The equivalent wireformat response to the query, with the same ID and
+    // question (JSON responses don't preserve the casing of the latter)
+    pub fn to_message(&self, query: &Message<Vec<u8>>) -> Result<Message<Vec<u8>>, String> {
+        let size_exceeded = |_| "Size limit exceeded".to_string();
+        let mut builder = MessageBuilder::new_vec();
+        let header = builder.header_mut();
+        header.set_id(query.header().id());
+        header.set_qr(true);
+        header.set_rd(query.header().rd());
+        header.set_ra(self.ra);
+        header.set_ad(self.ad);
+        header.set_tc(self.tc);
+        // Only the lower 4 bits fit into the header, the rest would be in OPT
+        header.set_rcode(Rcode::from_int((self.status & 0x0f) as u8));
+
+        let mut question_builder = builder.question();
+        for q in query.question() {
+            let q = q.map_err(|_| "Failed to parse question".to_string())?;
+            question_builder.push(q).map_err(size_exceeded)?;
+        }
+        // Like with wireformat upstreams, a single record we can't parse
+        // (e.g. a type we only know by number) shouldn't fail the whole
+        // response, so skip it and keep whatever we can parse
+        let mut answer_builder = question_builder.answer();
+        for r in self.answer.iter().filter_map(|r| r.to_record().ok()) {
+            answer_builder.push(r).map_err(size_exceeded)?;
+        }
+        let mut authority_builder = answer_builder.authority();
+        for r in self.authority.iter().filter_map(|r| r.to_record().ok()) {
+            authority_builder.push(r).map_err(size_exceeded)?;
+        }
+        Ok(authority_builder.into_message())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(name: &str, rtype: Rtype) -> Message<Vec<u8>> {
+        let mut builder = MessageBuilder::new_vec();
+        builder.header_mut().set_id(0x1234);
+        builder.header_mut().set_rd(true);
+        let mut question_builder = builder.question();
+        question_builder
+            .push(Question::new(
+                name.parse::<Dname<Vec<u8>>>().unwrap(),
+                rtype,
+                Class::In,
+            ))
+            .unwrap();
+        question_builder.into_message()
+    }
+
+    #[test]
+    fn json_response_to_wireformat() {
+        let resp = JsonUpstreamResponse::from_json(
+            r#"{"Status": 0, "TC": false, "RD": true, "RA": true, "AD": false,
+                "Question": [{"name": "www.example.com.", "type": 1}],
+                "Answer": [
+                    {"name": "www.example.com.", "type": 5, "TTL": 60, "data": "example.com."},
+                    {"name": "example.com.", "type": 1, "TTL": 300, "data": "192.0.2.1"}
+                ]}"#,
+        )
+        .unwrap();
+        let msg = resp
+            .to_message(&query("www.example.com", Rtype::A))
+            .unwrap();
+        assert_eq!(msg.header().id(), 0x1234);
+        assert!(msg.header().qr());
+        assert!(msg.header().ra());
+        assert_eq!(msg.header().rcode(), Rcode::NoError);
+        assert_eq!(msg.header_counts().qdcount(), 1);
+        assert_eq!(msg.header_counts().ancount(), 2);
+
+        let a: Vec<_> = msg
+            .answer()
+            .unwrap()
+            .limit_to::<A>()
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(a.len(), 1);
+        assert_eq!(a[0].ttl(), 300);
+        assert_eq!(
+            a[0].data().addr(),
+            "192.0.2.1".parse::<std::net::Ipv4Addr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn unparseable_records_are_skipped() {
+        // HTTPS in presentation format, which we can't parse (yet)
+        let resp = JsonUpstreamResponse::from_json(
+            r#"{"Status": 0, "RA": true,
+                "Answer": [
+                    {"name": "example.com.", "type": 65, "TTL": 300, "data": "1 . alpn=h2"},
+                    {"name": "example.com.", "type": 65, "TTL": 300, "data": "\\# 3 000100"}
+                ],
+                "Authority": [
+                    {"name": "example.com.", "type": 6, "TTL": 300,
+                     "data": "ns.example.com. admin.example.com. 1 7200 3600 86400 300"}
+                ]}"#,
+        )
+        .unwrap();
+        let msg = resp
+            .to_message(&query("example.com", Rtype::from_int(65)))
+            .unwrap();
+        assert_eq!(msg.header().rcode(), Rcode::NoError);
+        // Only the record in the generic format is kept
+        assert_eq!(msg.header_counts().ancount(), 1);
+        assert_eq!(msg.header_counts().nscount(), 1);
+    }
+
+    #[test]
+    fn nxdomain_status_is_kept() {
+        let resp = JsonUpstreamResponse::from_json(r#"{"Status": 3, "RA": true}"#).unwrap();
+        let msg = resp
+            .to_message(&query("nope.example.com", Rtype::A))
+            .unwrap();
+        assert_eq!(msg.header().rcode(), Rcode::NXDomain);
+        assert_eq!(msg.header_counts().ancount(), 0);
+    }
+
+    #[test]
+    fn txt_data_is_escaped() {
+        let txt = Txt::from_slice(b"\x13a \"quoted\" \\path\x0d\x0a\x7f").unwrap();
+        let rendered = JsonResponse::render_data(&AllRecordData::Txt(txt));
+        assert_eq!(rendered, r#""a \"quoted\" \\path\013\010\127""#);
+        // Which parses back into the same bytes
+        let parsed = JsonUpstreamRecord::parse_txt(&rendered).unwrap();
+        assert_eq!(
+            parsed.iter().collect::<Vec<_>>(),
+            vec![&b"a \"quoted\" \\path\x0d\x0a\x7f"[..]]
+        );
+    }
+
+    #[test]
+    fn missing_ra_counts_as_recursive() {
+        let resp = JsonUpstreamResponse::from_json(
+            r#"{"Status": 0,
+                "Answer": [{"name": "example.com.", "type": 1, "TTL": 300, "data": "192.0.2.1"}]}"#,
+        )
+        .unwrap();
+        let msg = resp.to_message(&query("example.com", Rtype::A)).unwrap();
+        assert!(msg.header().ra());
+        assert_eq!(msg.header_counts().ancount(), 1);
+
+        // An explicit RA=false is still kept
+        let resp = JsonUpstreamResponse::from_json(r#"{"Status": 0, "RA": false}"#).unwrap();
+        let msg = resp.to_message(&query("example.com", Rtype::A)).unwrap();
+        assert!(!msg.header().ra());
+    }
+}
@@ -1,4 +1,4 @@
-use js_sys::{Promise, Uint8Array};
+use js_sys::{Promise, Reflect, Uint8Array};
 use serde::{Deserialize, Serialize};
 use wasm_bindgen::prelude::*;
 use wasm_bindgen_futures::JsFuture;
@@ -25,6 +25,8 @@ extern "C" {
         value: JsValue,
         options: JsValue,
     ) -> Promise;
+    #[wasm_bindgen(method, js_name = "get")]
+    pub fn get_with_opts(this: &JsKvNamespace, key: &str, opts: JsValue) -> Promise;
     #[wasm_bindgen(method, js_name = "getWithMetadata")]
     pub fn get_with_metadata_opts(this: &JsKvNamespace, key: &str, opts: JsValue) -> Promise;
     #[wasm_bindgen(method)]
@@ -145,6 +147,56 @@ impl KvNamespace {
         )
     }
 
+    // Get a buffer value from KV, without metadata
+    pub async fn get_buf(&self, key: &str) -> Option<Vec<u8>> {
+        let promise = self.inner.get_with_opts(
+            key,
+            JsValue::from_serde(&KvGetOptions {
+                data_type: "arrayBuffer".to_string(),
+            })
+            .unwrap(),
+        );
+        match JsFuture::from(promise).await {
+            // A nonexistent key resolves to null
+            Ok(v) if !v.is_null() => Some(Uint8Array::new(&v).to_vec()),
+            _ => None,
+        }
+    }
+
+    // Get a plain string value from KV
+    pub async fn get_text(&self, key: &str) -> Option<String> {
+        let promise = self.inner.get_with_opts(
+            key,
+            JsValue::from_serde(&KvGetOptions {
+                data_type: "text".to_string(),
+            })
+            .unwrap(),
+        );
+        match JsFuture::from(promise).await {
+            // A nonexistent key resolves to null
+            Ok(v) => v.as_string(),
+            Err(_) => None,
+        }
+    }
+
+    // Without a TTL, the value is kept until overwritten
+    pub async fn put_text(&self, key: &str, value: &str, ttl: Option<u64>) -> Result<(), String> {
+        let promise = self.inner.put_with_opts(
+            key,
+            JsValue::from_str(value),
+            JsValue::from_serde(&KvPutOptions {
+                expiration: None,
+                expiration_ttl: ttl,
+                metadata: None,
+            })
+            .unwrap(),
+        );
+        match JsFuture::from(promise).await {
+            Ok(_) => Ok(()),
+            Err(_) => Err("Failed to put text to KV".to_string()),
+        }
+    }
+
     // List KV keys by prefix only
     pub async fn list_prefix(&self, prefix: &str) -> Result<KvListResult, String> {
         let promise = self.inner.list(
@@ -169,8 +221,35 @@ extern "C" {
 
     #[wasm_bindgen(getter, static_method_of = Global, js_class = globalThis, js_name = DNS_CACHE)]
     fn dns_cache() -> JsKvNamespace;
+
+    #[wasm_bindgen(getter, static_method_of = Global, js_class = globalThis, js_name = AUDIT_LOG)]
+    fn audit_log() -> JsKvNamespace;
+
+    #[wasm_bindgen(getter, static_method_of = Global, js_class = globalThis, js_name = BLOCKLIST)]
+    fn blocklist() -> JsKvNamespace;
+
+    #[wasm_bindgen(getter, static_method_of = Global, js_class = globalThis, js_name = METRICS)]
+    fn metrics() -> JsKvNamespace;
+}
+
+// The getters below trap if their binding is missing, so check first
+// wherever a binding is not strictly required
+pub fn has_binding(name: &str) -> bool {
+    Reflect::has(&js_sys::global(), &JsValue::from_str(name)).unwrap_or(false)
 }
 
 pub fn get_dns_cache() -> KvNamespace {
     KvNamespace::wrap(Global::dns_cache())
 }
+
+pub fn get_audit_log() -> KvNamespace {
+    KvNamespace::wrap(Global::audit_log())
+}
+
+pub fn get_blocklist() -> KvNamespace {
+    KvNamespace::wrap(Global::blocklist())
+}
+
+pub fn get_metrics() -> KvNamespace {
+    KvNamespace::wrap(Global::metrics())
+}
@@ -1,10 +1,18 @@
+mod audit;
 mod cache;
+mod chaos;
 mod client;
+mod dns64;
+mod edns;
+mod health;
+mod json;
 mod kv;
+mod metrics;
 mod r#override;
 mod server;
 mod trie_map;
 mod util;
+mod zone;
 
 use cfg_if::cfg_if;
 use wasm_bindgen::prelude::*;
@@ -32,5 +40,10 @@ pub async fn handle_request_rs(ev: ExtendableEvent, req: Request) -> Response {
     // Set up panic hook
     set_panic_hook();
 
-    server::Server::get().await.handle_request(ev, req).await
+    let server = match server::Server::get().await {
+        Ok(server) => server,
+        Err(err) => return server::Server::build_init_error_response(err),
+    };
+    server.prefetch_once(&ev);
+    server.handle_request(ev, req).await
 }
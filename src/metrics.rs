@@ -0,0 +1,247 @@
+use crate::client::QueryResult;
+use crate::kv;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use wasm_bindgen::JsValue;
+use wasm_bindgen_futures::future_to_promise;
+use web_sys::ExtendableEvent;
+
+fn default_flush_key() -> String {
+    "metrics".to_string()
+}
+
+// Options for persisting counters to the METRICS KV namespace
+#[derive(Deserialize, Serialize)]
+pub struct MetricsFlushOptions {
+    // Flush after every this many queries handled by an instance
+    interval: u64,
+    #[serde(default = "default_flush_key")]
+    key: String,
+}
+
+// Counters aggregated over all instances, as stored in KV
+#[derive(Deserialize, Serialize, Default)]
+struct StoredCounters {
+    queries_total: u64,
+    cache_hits: u64,
+    cache_misses: u64,
+}
+
+// Counters of the current instance as served at /stats
+#[derive(Serialize)]
+struct Stats<'a> {
+    queries_total: u64,
+    cache_hits: u64,
+    cache_misses: u64,
+    cache_errors: u64,
+    upstream_errors: BTreeMap<&'a String, &'a u64>,
+}
+
+// In-memory counters of the current Worker instance
+// These are not shared between instances, and are reset whenever
+// the instance is recycled by the runtime, unless flushed to KV
+pub struct Metrics {
+    queries_total: AtomicU64,
+    // Queries answered (at least partly) from cache
+    cache_hits: AtomicU64,
+    // Queries that had to go to upstream
+    cache_misses: AtomicU64,
+    flush: Option<MetricsFlushOptions>,
+    // Values of the counters at the last flush
+    flushed_queries_total: AtomicU64,
+    flushed_cache_hits: AtomicU64,
+    flushed_cache_misses: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new(flush: Option<MetricsFlushOptions>) -> Result<Metrics, String> {
+        if let Some(flush) = &flush {
+            if flush.interval == 0 {
+                return Err("Metrics flush interval must be positive".to_string());
+            }
+        }
+        Ok(Metrics {
+            queries_total: AtomicU64::new(0),
+            cache_hits: AtomicU64::new(0),
+            cache_misses: AtomicU64::new(0),
+            flush,
+            flushed_queries_total: AtomicU64::new(0),
+            flushed_cache_hits: AtomicU64::new(0),
+            flushed_cache_misses: AtomicU64::new(0),
+        })
+    }
+
+    pub fn record_query(&self, result: &QueryResult) {
+        self.queries_total.fetch_add(1, Ordering::Relaxed);
+        if result.cache_hit {
+            self.cache_hits.fetch_add(1, Ordering::Relaxed);
+        } else if result.upstream.is_some() {
+            self.cache_misses.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // Add the counts since the last flush to the counters stored in KV, in
+    // the background (via waitUntil), every `interval` queries
+    // Flushes from different instances may race with each other, in which
+    // case the last writer wins and some counts are lost; this is fine for
+    // the rough aggregate numbers these are meant to be
+    pub fn flush_if_due(&self, ev: &ExtendableEvent) {
+        let flush = match &self.flush {
+            Some(flush) => flush,
+            None => return,
+        };
+        if self.queries_total.load(Ordering::Relaxed) % flush.interval != 0 {
+            return;
+        }
+
+        // Taken synchronously, so that overlapping flushes of this instance
+        // never count the same queries twice
+        let delta = StoredCounters {
+            queries_total: Self::take_delta(&self.queries_total, &self.flushed_queries_total),
+            cache_hits: Self::take_delta(&self.cache_hits, &self.flushed_cache_hits),
+            cache_misses: Self::take_delta(&self.cache_misses, &self.flushed_cache_misses),
+        };
+        let key = flush.key.clone();
+        let promise = future_to_promise(async move {
+            let namespace = kv::get_metrics();
+            let mut stored: StoredCounters = namespace
+                .get_text(&key)
+                .await
+                .and_then(|text| serde_json::from_str(&text).ok())
+                .unwrap_or_default();
+            stored.queries_total += delta.queries_total;
+            stored.cache_hits += delta.cache_hits;
+            stored.cache_misses += delta.cache_misses;
+            // Ignore error -- metrics are best-effort
+            if let Ok(text) = serde_json::to_string(&stored) {
+                let _ = namespace.put_text(&key, &text, None).await;
+            }
+            Ok(JsValue::UNDEFINED)
+        });
+        let _ = ev.wait_until(&promise);
+    }
+
+    fn take_delta(counter: &AtomicU64, flushed: &AtomicU64) -> u64 {
+        let value = counter.load(Ordering::Relaxed);
+        value - flushed.swap(value, Ordering::Relaxed)
+    }
+
+    // Render all counters in the Prometheus text exposition format
+    // Counters owned by the client (KV and upstream errors) are passed in
+    pub fn render_prometheus(
+        &self,
+        cache_errors: u64,
+        upstream_errors: &HashMap<String, u64>,
+    ) -> String {
+        let mut ret = String::new();
+        Self::render_counter(
+            &mut ret,
+            "workerns_queries_total",
+            "Total number of DNS queries handled",
+            self.queries_total.load(Ordering::Relaxed),
+        );
+        Self::render_counter(
+            &mut ret,
+            "workerns_cache_hits_total",
+            "Queries answered from cache",
+            self.cache_hits.load(Ordering::Relaxed),
+        );
+        Self::render_counter(
+            &mut ret,
+            "workerns_cache_misses_total",
+            "Queries forwarded to upstream",
+            self.cache_misses.load(Ordering::Relaxed),
+        );
+        Self::render_counter(
+            &mut ret,
+            "workerns_cache_errors_total",
+            "Cache lookups that failed due to KV errors",
+            cache_errors,
+        );
+
+        ret.push_str("# HELP workerns_upstream_errors_total Failed upstream requests\n");
+        ret.push_str("# TYPE workerns_upstream_errors_total counter\n");
+        let mut upstreams: Vec<_> = upstream_errors.iter().collect();
+        // Keep the output stable between scrapes
+        upstreams.sort();
+        for (upstream, count) in upstreams {
+            ret.push_str(&format!(
+                "workerns_upstream_errors_total{{upstream=\"{}\"}} {}\n",
+                Self::escape_label_value(upstream),
+                count
+            ));
+        }
+        ret
+    }
+
+    // Render all counters as a JSON object, for dashboards and scripts
+    // that would rather not parse the Prometheus format
+    pub fn render_json(&self, cache_errors: u64, upstream_errors: &HashMap<String, u64>) -> String {
+        let stats = Stats {
+            queries_total: self.queries_total.load(Ordering::Relaxed),
+            cache_hits: self.cache_hits.load(Ordering::Relaxed),
+            cache_misses: self.cache_misses.load(Ordering::Relaxed),
+            cache_errors,
+            // Sorted, to keep the output stable between requests
+            upstream_errors: upstream_errors.iter().collect(),
+        };
+        serde_json::to_string(&stats).unwrap()
+    }
+
+    fn render_counter(out: &mut String, name: &str, help: &str, value: u64) {
+        out.push_str(&format!("# HELP {} {}\n", name, help));
+        out.push_str(&format!("# TYPE {} counter\n", name));
+        out.push_str(&format!("{} {}\n", name, value));
+    }
+
+    fn escape_label_value(value: &str) -> String {
+        value
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prometheus_label_values_are_escaped() {
+        let metrics = Metrics::new(None).unwrap();
+        let mut upstream_errors = HashMap::new();
+        upstream_errors.insert("https://a.example/q?x=\"1\"\\\n".to_string(), 2);
+        let text = metrics.render_prometheus(0, &upstream_errors);
+        // Quotes, backslashes and newlines must not end the label value
+        assert!(text.contains(
+            r#"workerns_upstream_errors_total{upstream="https://a.example/q?x=\"1\"\\\n"} 2"#
+        ));
+    }
+
+    #[test]
+    fn json_includes_all_counters() {
+        let metrics = Metrics::new(None).unwrap();
+        metrics.queries_total.fetch_add(3, Ordering::Relaxed);
+        metrics.cache_hits.fetch_add(2, Ordering::Relaxed);
+        metrics.cache_misses.fetch_add(1, Ordering::Relaxed);
+        let mut upstream_errors = HashMap::new();
+        upstream_errors.insert("https://b.example/dns-query".to_string(), 4);
+        upstream_errors.insert("https://a.example/dns-query".to_string(), 5);
+        let json: serde_json::Value =
+            serde_json::from_str(&metrics.render_json(6, &upstream_errors)).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "queries_total": 3,
+                "cache_hits": 2,
+                "cache_misses": 1,
+                "cache_errors": 6,
+                "upstream_errors": {
+                    "https://a.example/dns-query": 5,
+                    "https://b.example/dns-query": 4,
+                },
+            })
+        );
+    }
+}
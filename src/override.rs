@@ -1,23 +1,105 @@
+use crate::client::QueryResult;
+use crate::edns::EDE_BLOCKED;
 use crate::trie_map::TrieMap;
-use crate::util::OwnedRecordData;
-use domain::base::{Dname, Question, Record, Rtype};
-use domain::rdata::{Aaaa, AllRecordData, A};
+use crate::util::{OwnedRecordData, Subnet, TtlClamp};
+use domain::base::rdata::{RecordData, UnknownRecordData};
+use domain::base::{iana::Rcode, serial::Serial, Dname, Question, Record, Rtype};
+use domain::rdata::{Aaaa, AllRecordData, Cname, Mx, Ptr, Soa, Srv, A};
 use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
-use std::net::{IpAddr, Ipv4Addr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+// Put a simple blocklist of domains at ../blocklist.txt, and / or
+// categorized ones at ../blocklists/<category>.txt (see build.rs)
+// All domains in the files will be resolved to 0.0.0.0 (or ::)
+// This can be used for ad-blocking, as converting the
+// blocklists to JSON config file would not be a great idea,
+// but converting them to a dumb list of domains should be trivial
+static BLOCK_LIST_SOURCES: &[(&str, &str)] = include!(concat!(env!("OUT_DIR"), "/blocklists.rs"));
 
 lazy_static! {
-    // Put a simple blocklist of domains at ../blocklist.txt
-    // All domains in the file will be resolved to 0.0.0.0
-    // This can be used for ad-blocking, as converting the
-    // blocklists to JSON config file would not be a great idea,
-    // but converting them to a dumb list of domains should be trivial
-    static ref BLOCK_LIST: HashSet<String> = parse_blocklist_file();
+    // Compiled-in blocklists by category
+    static ref BLOCK_LISTS: HashMap<&'static str, HashSet<String>> = {
+        let mut ret = HashMap::new();
+        for (category, text) in BLOCK_LIST_SOURCES.iter() {
+            ret.entry(*category)
+                .or_insert_with(HashSet::new)
+                .extend(parse_blocklist(text));
+        }
+        ret
+    };
+
+    // Static hosts at ../hosts.txt, in the format of /etc/hosts, resolved
+    // like exact overrides (which take precedence), e.g. to work with a
+    // known set of names fully offline during development
+    // Along with the warnings from parsing them (see hosts_warnings)
+    static ref HOSTS: (HashMap<String, Vec<IpAddr>>, Vec<String>) =
+        parse_hosts(include_str!(concat!(env!("OUT_DIR"), "/hosts.txt")));
+}
+
+// The compiled-in blocklists to apply, given a map of categories to whether
+// they are enabled; categories not in the map are enabled
+pub fn builtin_blocklists(
+    enabled: &HashMap<String, bool>,
+) -> Result<Vec<&'static HashSet<String>>, String> {
+    for category in enabled.keys() {
+        if !BLOCK_LISTS.contains_key(category.as_str()) {
+            return Err(format!("Unknown blocklist category {}", category));
+        }
+    }
+    Ok(BLOCK_LISTS
+        .iter()
+        .filter(|(category, _)| enabled.get(**category).copied().unwrap_or(true))
+        .map(|(_, list)| list)
+        .collect())
+}
+
+// Problems with the lines of hosts.txt that were skipped, for logging
+pub fn hosts_warnings() -> &'static [String] {
+    &HOSTS.1
+}
+
+// An address followed by any number of names per line; `#` starts a comment
+// Also returns a warning for every line that had to be skipped
+fn parse_hosts(text: &str) -> (HashMap<String, Vec<IpAddr>>, Vec<String>) {
+    let mut ret: HashMap<String, Vec<IpAddr>> = HashMap::new();
+    let mut warnings = Vec::new();
+    for line in text.lines() {
+        let line = line.splitn(2, '#').next().unwrap_or("");
+        let mut fields = line.split_whitespace();
+        let addr = match fields.next().map(|addr| addr.parse::<IpAddr>()) {
+            Some(Ok(addr)) => addr,
+            Some(Err(_)) => {
+                warnings.push(format!("Invalid address in hosts.txt: {}", line));
+                continue;
+            }
+            None => continue,
+        };
+        for name in fields {
+            let addrs = ret.entry(crate::util::normalize_name(name)).or_default();
+            if !addrs.contains(&addr) {
+                addrs.push(addr);
+            }
+        }
+    }
+    (ret, warnings)
 }
 
-fn parse_blocklist_file() -> HashSet<String> {
+// Override value forcing a name to be resolved by upstream, e.g. to punch
+// a hole into a wildcard override or the blocklist
+const PASSTHROUGH: &str = "passthrough";
+
+// Blocked names resolve to the unspecified address of either family
+const BLOCKED_ADDRS: [IpAddr; 2] = [
+    IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+    IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+];
+
+// One domain per line, same as ../blocklist.txt
+pub fn parse_blocklist(text: &str) -> HashSet<String> {
     let mut ret = HashSet::new();
-    for line in include_str!("../blocklist.txt").lines() {
+    for line in text.lines() {
         if line.is_empty() {
             continue;
         }
@@ -26,94 +108,1046 @@ fn parse_blocklist_file() -> HashSet<String> {
             continue;
         }
 
-        ret.insert(line.trim().to_string());
+        ret.insert(crate::util::normalize_name(line.trim()));
     }
     ret
 }
 
+#[derive(Deserialize, Serialize)]
+pub struct SrvOverride {
+    priority: u16,
+    weight: u16,
+    port: u16,
+    target: String,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct MxOverride {
+    preference: u16,
+    exchange: String,
+}
+
+// SvcParams of SVCB / HTTPS records (RFC 9460, Section 7)
+#[derive(Deserialize, Serialize, Default)]
+pub struct SvcParamsOverride {
+    #[serde(default)]
+    alpn: Vec<String>,
+    #[serde(default)]
+    no_default_alpn: bool,
+    #[serde(default)]
+    port: Option<u16>,
+    #[serde(default)]
+    ipv4hint: Vec<Ipv4Addr>,
+    // ECHConfigList, base64-encoded
+    #[serde(default)]
+    ech: Option<String>,
+    #[serde(default)]
+    ipv6hint: Vec<Ipv6Addr>,
+}
+
+// Priority 0 makes an AliasMode record, where only the target is used
+#[derive(Deserialize, Serialize)]
+pub struct SvcbOverride {
+    priority: u16,
+    // "." means the owner name itself
+    target: String,
+    #[serde(default)]
+    params: SvcParamsOverride,
+}
+
+// Overrides for record types other than addresses, e.g.
+// `"_sip._tcp.local": {"srv": [{"priority": 0, "weight": 5, "port": 5060, "target": "pbx.local"}]}`
+// or `"svc.local": {"https": [{"priority": 1, "target": ".", "params": {"alpn": ["h2"]}}]}`
+// A `cname` makes the name an alias, and no other records are used then
+#[derive(Deserialize, Serialize)]
+pub struct TypedOverride {
+    #[serde(default)]
+    cname: Option<String>,
+    #[serde(default)]
+    srv: Vec<SrvOverride>,
+    #[serde(default)]
+    mx: Vec<MxOverride>,
+    #[serde(default)]
+    svcb: Vec<SvcbOverride>,
+    #[serde(default)]
+    https: Vec<SvcbOverride>,
+}
+
+// Maximum number of CNAME overrides followed for one question, which
+// guards against loops in the config
+const MAX_CNAME_CHAIN: usize = 8;
+
+// domain has no support for these types yet, so their rdata is built by hand
+const RTYPE_SVCB: u16 = 64;
+const RTYPE_HTTPS: u16 = 65;
+
+// One or more IP addresses
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum AddressList {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl AddressList {
+    fn into_vec(self) -> Vec<String> {
+        match self {
+            AddressList::One(addr) => vec![addr],
+            AddressList::Many(addrs) => addrs,
+        }
+    }
+}
+
+// Addresses depending on the subnet of the client (CF-Connecting-IP), e.g.
+// `"svc.local": {"by_subnet": {"10.1.0.0/16": "10.1.0.5"}, "default": "10.0.0.5"}`
+// The most specific matching subnet wins; clients outside all of them
+// get `default`, or the name is handled as if it had no override at all
+#[derive(Deserialize, Serialize)]
+pub struct ScopedOverride {
+    by_subnet: HashMap<String, AddressList>,
+    #[serde(default)]
+    default: Option<AddressList>,
+}
+
+// An override is either an IP address, a list of IP addresses (e.g. one
+// of each family for dual-stack hosts), addresses scoped by client subnet
+// (exact names only) or a set of typed records
+// The special address PASSTHROUGH exempts a name from all other rules
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum OverrideValue {
+    Address(String),
+    Addresses(Vec<String>),
+    // Before Typed, which would accept any object
+    Scoped(ScopedOverride),
+    Typed(TypedOverride),
+}
+
+// How names in the blocklist are answered
+#[derive(Deserialize, Serialize, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum BlockMode {
+    // Resolve to 0.0.0.0 / ::
+    Address,
+    // Respond with NXDOMAIN and a synthetic SOA, so that clients cache
+    // the negative answer for `negative_ttl`
+    NxDomain,
+}
+
+impl Default for BlockMode {
+    fn default() -> BlockMode {
+        BlockMode::Address
+    }
+}
+
+fn default_soa_mname() -> String {
+    "localhost.".to_string()
+}
+
+fn default_soa_rname() -> String {
+    "nobody.invalid.".to_string()
+}
+
+// Names used in SOA records we synthesize for negative answers
+#[derive(Deserialize, Serialize, Clone)]
+pub struct SyntheticSoaOptions {
+    #[serde(default = "default_soa_mname")]
+    mname: String,
+    #[serde(default = "default_soa_rname")]
+    rname: String,
+}
+
+impl Default for SyntheticSoaOptions {
+    fn default() -> SyntheticSoaOptions {
+        SyntheticSoaOptions {
+            mname: default_soa_mname(),
+            rname: default_soa_rname(),
+        }
+    }
+}
+
+// Everything an OverrideResolver is built from
+#[derive(Default)]
+pub struct OverrideOptions {
+    pub overrides: HashMap<String, OverrideValue>,
+    pub override_ttl: u32,
+    pub block_ttl: u32,
+    pub negative_ttl: u32,
+    pub synthetic_soa: SyntheticSoaOptions,
+    pub block_mode: BlockMode,
+    pub ttl_clamp: TtlClamp,
+    // The enabled categories of the compiled-in blocklists
+    pub builtin_block_lists: Vec<&'static HashSet<String>>,
+    pub extra_block_list: HashSet<String>,
+}
+
 pub struct OverrideResolver {
-    simple_matches: HashMap<String, IpAddr>,
-    suffix_matches: TrieMap<IpAddr>,
+    simple_matches: HashMap<String, Vec<IpAddr>>,
+    suffix_matches: TrieMap<Vec<IpAddr>>,
+    // Single-label wildcards (`%.example.com`), keyed by the base name
+    single_label_matches: HashMap<String, Vec<IpAddr>>,
+    // Reverse names (in-addr.arpa / ip6.arpa) mapped to hostnames
+    ptr_matches: HashMap<String, Dname<Vec<u8>>>,
+    // Typed (SRV / MX / SVCB / HTTPS) records, matched by exact name only
+    typed_matches: HashMap<String, Vec<OwnedRecordData>>,
+    // Aliases, matched by exact name only
+    cname_matches: HashMap<String, Dname<Vec<u8>>>,
+    // Exact names with addresses scoped by client subnet, sorted by
+    // descending prefix length so that the first match is the most specific
+    scoped_matches: HashMap<String, Vec<(Subnet, Vec<IpAddr>)>>,
+    // Names always resolved by upstream (exact matches only)
+    passthrough_names: HashSet<String>,
+    // The enabled categories of the compiled-in blocklists
+    builtin_block_lists: Vec<&'static HashSet<String>>,
+    // Blocklist loaded at runtime (from KV), in addition to the builtin ones
+    extra_block_list: HashSet<String>,
     override_ttl: u32,
+    // TTL of sinkhole addresses answered for blocked names, usually shorter
+    // than `override_ttl` so that unblocking takes effect quickly
+    block_ttl: u32,
+    // TTL of synthesized SOA records, i.e. for how long clients should
+    // cache negative answers we generate locally
+    negative_ttl: u32,
+    soa_mname: Dname<Vec<u8>>,
+    soa_rname: Dname<Vec<u8>>,
+    block_mode: BlockMode,
 }
 
 impl OverrideResolver {
-    pub fn new(overrides: HashMap<String, String>, override_ttl: u32) -> OverrideResolver {
-        let (simple_matches, suffix_matches) = Self::build_match_tables(overrides);
-        OverrideResolver {
-            suffix_matches,
-            simple_matches,
+    // Also returns what is worth logging about the overrides: warnings
+    // about entries that were ignored or fixed up, and a summary at the end
+    pub fn new(options: OverrideOptions) -> Result<(OverrideResolver, Vec<String>), String> {
+        let OverrideOptions {
+            overrides,
             override_ttl,
-        }
+            block_ttl,
+            negative_ttl,
+            synthetic_soa,
+            block_mode,
+            ttl_clamp,
+            builtin_block_lists,
+            extra_block_list,
+        } = options;
+        // A TTL of 0 asks clients not to cache the answers at all, e.g. for
+        // aliases that change all the time; the clamp (meant for upstream
+        // TTLs) must not turn that into something else
+        let clamp_ttl = |ttl| if ttl == 0 { 0 } else { ttl_clamp.apply(ttl) };
+        let mut ret = OverrideResolver {
+            simple_matches: HashMap::new(),
+            // Suffixes only match whole labels, even if a key lacks the leading dot
+            suffix_matches: TrieMap::with_boundary(b'.'),
+            single_label_matches: HashMap::new(),
+            ptr_matches: HashMap::new(),
+            typed_matches: HashMap::new(),
+            cname_matches: HashMap::new(),
+            scoped_matches: HashMap::new(),
+            passthrough_names: HashSet::new(),
+            builtin_block_lists,
+            extra_block_list,
+            // Synthesized answers obey the same TTL bounds as cached ones,
+            // so that TTLs in responses are consistent across both paths
+            override_ttl: clamp_ttl(override_ttl),
+            block_ttl: clamp_ttl(block_ttl),
+            negative_ttl: ttl_clamp.apply(negative_ttl),
+            soa_mname: synthetic_soa
+                .mname
+                .parse()
+                .map_err(|_| "Invalid SOA mname".to_string())?,
+            soa_rname: synthetic_soa
+                .rname
+                .parse()
+                .map_err(|_| "Invalid SOA rname".to_string())?,
+            block_mode,
+        };
+        let mut messages = ret.build_match_tables(overrides, &HOSTS.0);
+        messages.push(format!(
+            "Loaded {} exact, {} suffix, {} single-label, {} PTR and {} typed overrides, {} blocklist entries",
+            ret.simple_matches.len(),
+            ret.suffix_matches.len(),
+            ret.single_label_matches.len(),
+            ret.ptr_matches.len(),
+            ret.typed_matches.len(),
+            ret.extra_block_list.len()
+                + ret
+                    .builtin_block_lists
+                    .iter()
+                    .map(|l| l.len())
+                    .sum::<usize>()
+        ));
+        Ok((ret, messages))
     }
 
+    // Returns warnings about entries that were ignored or fixed up
     fn build_match_tables(
-        overrides: HashMap<String, String>,
-    ) -> (HashMap<String, IpAddr>, TrieMap<IpAddr>) {
-        let mut simple = HashMap::new();
-        let mut suffix = TrieMap::new();
+        &mut self,
+        overrides: HashMap<String, OverrideValue>,
+        hosts: &HashMap<String, Vec<IpAddr>>,
+    ) -> Vec<String> {
+        let mut warnings = Vec::new();
+        let mut apexes = Vec::new();
         for (k, v) in overrides.into_iter() {
-            match v.parse::<IpAddr>() {
-                Ok(addr) => {
-                    if k.starts_with("*.") {
-                        // Anything starting with a wildcard character is a suffix match
-                        // we convert it to a prefix match by reversing the domain
-                        // Note that we get rid of the wildcard but keep the dot, i.e.
-                        // we don't allow suffix match in the middle of a part of a domain
-                        suffix.put_prefix(k[1..].chars().rev().collect::<String>(), addr);
-                    } else {
-                        simple.insert(k, addr);
+            let (k, warning) = Self::normalize_key(k);
+            warnings.extend(warning);
+            let v = match v {
+                OverrideValue::Address(v) if v == PASSTHROUGH => {
+                    self.passthrough_names
+                        .insert(crate::util::normalize_name(&k));
+                    continue;
+                }
+                OverrideValue::Address(v) => vec![v],
+                OverrideValue::Addresses(v) => v,
+                OverrideValue::Scoped(scoped) => {
+                    let k = crate::util::normalize_name(&k);
+                    if let Some(default) = scoped.default {
+                        let addrs = Self::parse_addrs(default.into_vec());
+                        if addrs.len() > 0 {
+                            self.simple_matches.insert(k.clone(), addrs);
+                        }
+                    }
+                    let mut scopes = Vec::new();
+                    for (subnet, addrs) in scoped.by_subnet {
+                        match Subnet::parse(&subnet) {
+                            Ok(subnet) => {
+                                scopes.push((subnet, Self::parse_addrs(addrs.into_vec())))
+                            }
+                            Err(e) => warnings.push(format!("Override for {}: {}", k, e)),
+                        }
+                    }
+                    scopes.sort_by_key(|(subnet, _)| std::cmp::Reverse(subnet.prefix_len()));
+                    self.scoped_matches.insert(k, scopes);
+                    continue;
+                }
+                OverrideValue::Typed(t) => {
+                    if let Some(cname) = &t.cname {
+                        match cname.parse::<Dname<Vec<u8>>>() {
+                            Ok(target) => {
+                                self.cname_matches
+                                    .insert(crate::util::normalize_name(&k), target);
+                            }
+                            Err(_) => warnings.push(format!("Invalid CNAME for {}", k)),
+                        }
                     }
+                    self.typed_matches.insert(
+                        crate::util::normalize_name(&k),
+                        Self::build_typed_records(t, &mut warnings),
+                    );
+                    continue;
+                }
+            };
+
+            if Self::is_reverse_name(&k) {
+                // Reverse names map to hostnames instead of IP addresses
+                // Only one hostname per reverse name is supported
+                match v.first().map(|v| v.parse::<Dname<Vec<u8>>>()) {
+                    Some(Ok(target)) => {
+                        self.ptr_matches
+                            .insert(crate::util::normalize_name(&k), target);
+                    }
+                    // Ignore malformed hostnames
+                    _ => (),
+                }
+                continue;
+            }
+
+            let addrs = Self::parse_addrs(v);
+            if addrs.len() == 0 {
+                continue;
+            }
+
+            if k.starts_with("%.") {
+                // `%.example.com` matches exactly one label below `example.com`
+                // These are stored by the base name and looked up by
+                // stripping the first label of the queried name
+                self.single_label_matches
+                    .insert(crate::util::normalize_name(&k[2..]), addrs);
+            } else if k.starts_with("**.") {
+                // `**.example.com` is the same as `*.example.com`, except
+                // that it also covers the apex `example.com` itself
+                let k = crate::util::normalize_name(&k[2..]);
+                if self
+                    .suffix_matches
+                    .put_prefix(k.chars().rev().collect::<String>(), addrs.clone())
+                    .is_some()
+                {
+                    warnings.push(Self::duplicate_warning(&k));
+                }
+                apexes.push((k[1..].to_string(), addrs));
+            } else if k.starts_with("*.") {
+                // Anything starting with a wildcard character is a suffix match
+                // we convert it to a prefix match by reversing the domain
+                // Note that we get rid of the wildcard but keep the dot, i.e.
+                // we don't allow suffix match in the middle of a part of a domain
+                let k = crate::util::normalize_name(&k[1..]);
+                if self
+                    .suffix_matches
+                    .put_prefix(k.chars().rev().collect::<String>(), addrs)
+                    .is_some()
+                {
+                    // e.g. both `*.example.com` and `**.example.com`
+                    warnings.push(Self::duplicate_warning(&k));
                 }
-                // Ignore malformed IP addresses
+            } else {
+                let k = crate::util::normalize_name(&k);
+                if self.is_blocklisted(&k) {
+                    // Not an error, the override wins; but worth knowing
+                    warnings.push(format!("Override for {} shadows its blocklist entry", k));
+                }
+                self.simple_matches.insert(k, addrs);
+            }
+        }
+        // Explicit entries for the apex take precedence over `**.` entries
+        for (apex, addrs) in apexes {
+            self.simple_matches.entry(apex).or_insert(addrs);
+        }
+        // And explicit overrides over static hosts
+        for (name, addrs) in hosts.iter() {
+            if !self.passthrough_names.contains(name) {
+                self.simple_matches
+                    .entry(name.clone())
+                    .or_insert_with(|| addrs.clone());
+            }
+        }
+        // Exact overrides answer reverse lookups of their addresses as well,
+        // unless there is an explicit one for the reverse name; if several
+        // names share an address, the first in alphabetical order wins
+        let mut names: Vec<&String> = self.simple_matches.keys().collect();
+        names.sort();
+        for name in names {
+            let target = match name.parse::<Dname<Vec<u8>>>() {
+                Ok(target) => target,
                 Err(_) => continue,
+            };
+            for addr in self.simple_matches[name].iter() {
+                // Nobody wants 0.0.0.0 to resolve back to some random name
+                if addr.is_unspecified() {
+                    continue;
+                }
+                self.ptr_matches
+                    .entry(crate::util::reverse_name(addr))
+                    .or_insert_with(|| target.clone());
+            }
+        }
+        warnings
+    }
+
+    // Keys that look right may still not match because of stray whitespace
+    // or Unicode labels (which never appear in queries as such); fix them
+    // up, but return a warning so that the config can be corrected
+    fn normalize_key(key: String) -> (String, Option<String>) {
+        let trimmed = key.trim();
+        let (normalized, warning) = match crate::util::idn_to_ascii(trimmed) {
+            Ok(ascii) => (ascii.to_ascii_lowercase(), None),
+            Err(e) => (
+                trimmed.to_ascii_lowercase(),
+                Some(format!("Override key {:?}: {}", key, e)),
+            ),
+        };
+        if warning.is_none() && normalized != key {
+            let warning = format!("Override key {:?} normalized to {:?}", key, normalized);
+            return (normalized, Some(warning));
+        }
+        (normalized, warning)
+    }
+
+    fn try_match_scoped(&self, name: &str, client_ip: Option<IpAddr>) -> Option<&Vec<IpAddr>> {
+        let client_ip = client_ip?;
+        self.scoped_matches
+            .get(name)?
+            .iter()
+            .find(|(subnet, _)| subnet.contains(&client_ip))
+            .map(|(_, addrs)| addrs)
+    }
+
+    // Whether the question must skip both overrides and the cache
+    pub fn is_passthrough(&self, question: &Question<Dname<Vec<u8>>>) -> bool {
+        self.passthrough_names
+            .contains(&crate::util::normalize_name(&question.qname().to_string()))
+    }
+
+    pub fn is_blocklisted(&self, name: &str) -> bool {
+        self.builtin_block_lists.iter().any(|l| l.contains(name))
+            || self.extra_block_list.contains(name)
+    }
+
+    // Ignore malformed IP addresses
+    fn parse_addrs(addrs: Vec<String>) -> Vec<IpAddr> {
+        addrs.iter().filter_map(|v| v.parse().ok()).collect()
+    }
+
+    fn duplicate_warning(suffix: &str) -> String {
+        format!(
+            "Conflicting wildcard overrides for *{}, only one of them is used",
+            suffix
+        )
+    }
+
+    fn build_typed_records(
+        typed: TypedOverride,
+        warnings: &mut Vec<String>,
+    ) -> Vec<OwnedRecordData> {
+        let mut ret = Vec::new();
+        for srv in typed.srv {
+            match srv.target.parse::<Dname<Vec<u8>>>() {
+                Ok(target) => ret.push(AllRecordData::Srv(Srv::new(
+                    srv.priority,
+                    srv.weight,
+                    srv.port,
+                    target,
+                ))),
+                // Ignore malformed target names
+                Err(_) => continue,
+            }
+        }
+        for mx in typed.mx {
+            match mx.exchange.parse::<Dname<Vec<u8>>>() {
+                Ok(exchange) => ret.push(AllRecordData::Mx(Mx::new(mx.preference, exchange))),
+                // Ignore malformed exchange names
+                Err(_) => continue,
+            }
+        }
+        for (rtype, records) in [(RTYPE_SVCB, typed.svcb), (RTYPE_HTTPS, typed.https)]
+            .iter()
+            .map(|(rtype, records)| (Rtype::from_int(*rtype), records))
+        {
+            for svcb in records {
+                match Self::build_svcb_rdata(svcb) {
+                    Ok(rdata) => ret.push(AllRecordData::Other(UnknownRecordData::from_octets(
+                        rtype, rdata,
+                    ))),
+                    Err(e) => warnings.push(format!("Ignoring {} override: {}", rtype, e)),
+                }
+            }
+        }
+        ret
+    }
+
+    // Wire format of SVCB / HTTPS rdata: priority, the uncompressed target
+    // name and the SvcParams in ascending order of their keys
+    fn build_svcb_rdata(svcb: &SvcbOverride) -> Result<Vec<u8>, String> {
+        let target: Dname<Vec<u8>> = svcb
+            .target
+            .parse()
+            .map_err(|_| format!("Invalid target {}", svcb.target))?;
+        let mut rdata = svcb.priority.to_be_bytes().to_vec();
+        rdata.extend_from_slice(target.as_slice());
+
+        let params = &svcb.params;
+        let mut push_param = |key: u16, value: Vec<u8>| -> Result<(), String> {
+            if value.len() > u16::MAX as usize {
+                return Err(format!("SvcParam {} is too long", key));
+            }
+            rdata.extend_from_slice(&key.to_be_bytes());
+            rdata.extend_from_slice(&(value.len() as u16).to_be_bytes());
+            rdata.extend_from_slice(&value);
+            Ok(())
+        };
+        if params.alpn.len() > 0 {
+            let mut value = Vec::new();
+            for id in params.alpn.iter() {
+                if id.is_empty() || id.len() > 255 {
+                    return Err(format!("Invalid ALPN id {}", id));
+                }
+                value.push(id.len() as u8);
+                value.extend_from_slice(id.as_bytes());
             }
+            push_param(1, value)?;
+        }
+        if params.no_default_alpn {
+            push_param(2, Vec::new())?;
+        }
+        if let Some(port) = params.port {
+            push_param(3, port.to_be_bytes().to_vec())?;
+        }
+        if params.ipv4hint.len() > 0 {
+            push_param(
+                4,
+                params
+                    .ipv4hint
+                    .iter()
+                    .flat_map(|a| a.octets().to_vec())
+                    .collect(),
+            )?;
+        }
+        if let Some(ech) = &params.ech {
+            push_param(
+                5,
+                base64::decode(ech).map_err(|_| "Invalid ECH config".to_string())?,
+            )?;
+        }
+        if params.ipv6hint.len() > 0 {
+            push_param(
+                6,
+                params
+                    .ipv6hint
+                    .iter()
+                    .flat_map(|a| a.octets().to_vec())
+                    .collect(),
+            )?;
         }
-        (simple, suffix)
+        Ok(rdata)
     }
 
+    fn is_reverse_name(name: &str) -> bool {
+        let name = crate::util::normalize_name(name);
+        name.ends_with(".in-addr.arpa") || name.ends_with(".ip6.arpa")
+    }
+
+    // Returns Some with the (possibly empty, i.e. NODATA, or NXDOMAIN for
+    // blocked names) result if the name is overridden, or None if the
+    // question is not handled here
+    // `client_ip` is only used for subnet-scoped overrides
     pub fn try_resolve(
         &self,
         question: &Question<Dname<Vec<u8>>>,
-    ) -> Option<Record<Dname<Vec<u8>>, OwnedRecordData>> {
+        client_ip: Option<IpAddr>,
+    ) -> Option<QueryResult> {
+        self.try_resolve_with_rule(question, client_ip)
+            .map(|(_, result)| result)
+    }
+
+    // The kind of rule that answers the question (e.g. "exact", "suffix" or
+    // "blocklist"), or None if it is not handled here
+    pub fn explain(
+        &self,
+        question: &Question<Dname<Vec<u8>>>,
+        client_ip: Option<IpAddr>,
+    ) -> Option<&'static str> {
+        self.try_resolve_with_rule(question, client_ip)
+            .map(|(rule, _)| rule)
+    }
+
+    fn try_resolve_with_rule(
+        &self,
+        question: &Question<Dname<Vec<u8>>>,
+        client_ip: Option<IpAddr>,
+    ) -> Option<(&'static str, QueryResult)> {
+        let name = crate::util::normalize_name(&question.qname().to_string());
+        if self.passthrough_names.contains(&name) {
+            return None;
+        }
+        if self.cname_matches.contains_key(&name) {
+            return Some(("cname", self.resolve_cname_chain(question, client_ip)));
+        }
+        let scoped_addrs = self.try_match_scoped(&name, client_ip);
+        // Explicit overrides take precedence over the blocklist
+        let blocked = scoped_addrs.is_none()
+            && !self.simple_matches.contains_key(&name)
+            && !self.typed_matches.contains_key(&name)
+            && self.is_blocklisted(&name);
+        // A nonexistent name has no records of any type, so this comes before
+        // the type checks below
+        if blocked && self.block_mode == BlockMode::NxDomain {
+            let mut ret = QueryResult::new(
+                Rcode::NXDomain,
+                Vec::new(),
+                vec![self.synthesize_soa(question)],
+            );
+            ret.ede = Some(EDE_BLOCKED);
+            return Some(("blocklist", ret));
+        }
+
+        let (rule, mut records) = self.try_resolve_records(question, &name, scoped_addrs)?;
+        if blocked {
+            for r in records.iter_mut() {
+                r.set_ttl(self.block_ttl);
+            }
+        }
+        let mut ret = QueryResult::new(Rcode::NoError, records, Vec::new());
+        if blocked {
+            ret.ede = Some(EDE_BLOCKED);
+        }
+        Some((rule, ret))
+    }
+
+    // Answer with the CNAME records of the chain starting at the queried name,
+    // followed by the records of its end if that is overridden as well, like
+    // a recursive resolver would do; otherwise the client has to look up the
+    // final target on its own
+    fn resolve_cname_chain(
+        &self,
+        question: &Question<Dname<Vec<u8>>>,
+        client_ip: Option<IpAddr>,
+    ) -> QueryResult {
+        let mut ret = QueryResult::new(Rcode::NoError, Vec::new(), Vec::new());
+        let mut current = question.qname().clone();
+        for _ in 0..MAX_CNAME_CHAIN {
+            let target = match self
+                .cname_matches
+                .get(&crate::util::normalize_name(&current.to_string()))
+            {
+                Some(target) => target,
+                None => {
+                    let q = Question::new(current, question.qtype(), question.qclass());
+                    if let Some((_, mut result)) = self.try_resolve_with_rule(&q, client_ip) {
+                        ret.rcode = result.rcode;
+                        ret.answers.append(&mut result.answers);
+                        ret.authority = result.authority;
+                        ret.ede = result.ede;
+                    }
+                    return ret;
+                }
+            };
+            ret.answers.push(Record::new(
+                current,
+                question.qclass(),
+                self.override_ttl,
+                AllRecordData::Cname(Cname::new(target.clone())),
+            ));
+            if question.qtype() == Rtype::Cname {
+                // Nothing to follow for the alias itself
+                return ret;
+            }
+            current = target.clone();
+        }
+        crate::util::log(&format!(
+            "CNAME override chain of {} is too long",
+            question.qname()
+        ));
+        ret
+    }
+
+    fn try_resolve_records(
+        &self,
+        question: &Question<Dname<Vec<u8>>>,
+        name: &str,
+        scoped_addrs: Option<&Vec<IpAddr>>,
+    ) -> Option<(&'static str, Vec<Record<Dname<Vec<u8>>, OwnedRecordData>>)> {
+        if let Some(records) = self.try_resolve_typed(question) {
+            return Some(("typed", records));
+        }
+
+        if question.qtype() == Rtype::Ptr {
+            return self.try_resolve_ptr(question).map(|r| ("ptr", vec![r]));
+        }
+
         match question.qtype() {
-            // We only handle resolution of IP addresses
+            // We only handle resolution of IP addresses (and PTR above)
             Rtype::A | Rtype::A6 | Rtype::Aaaa | Rtype::Cname | Rtype::Any => (),
             // So if the question is anything else, just skip
             _ => return None,
         }
 
-        let name = question.qname().to_string();
-        if let Some(addr) = self.simple_matches.get(&name) {
-            self.respond_with_addr(question, addr)
-        } else if BLOCK_LIST.get(&name).is_some() {
-            self.respond_with_addr(question, &IpAddr::V4(Ipv4Addr::UNSPECIFIED))
-        } else if let Some(addr) = self
+        // Precedence: subnet-scoped and other exact matches, the blocklist,
+        // single-label wildcards (`%.`) and at last greedy suffix wildcards
+        // (`*.` / `**.`)
+        let (rule, addrs) = if let Some(addrs) = scoped_addrs {
+            ("scoped", addrs.as_slice())
+        } else if let Some(addrs) = self.simple_matches.get(name) {
+            ("exact", addrs.as_slice())
+        } else if self.is_blocklisted(name) {
+            ("blocklist", &BLOCKED_ADDRS[..])
+        } else if let Some(addrs) = name
+            .splitn(2, '.')
+            .nth(1)
+            .and_then(|parent| self.single_label_matches.get(parent))
+        {
+            ("single_label", addrs.as_slice())
+        } else if let Some(addrs) = self
             .suffix_matches
             .get_by_prefix(name.chars().rev().collect::<String>())
         {
-            self.respond_with_addr(question, addr)
+            ("suffix", addrs.as_slice())
         } else {
+            return None;
+        };
+        Some((rule, self.respond_with_addrs(question, addrs)))
+    }
+
+    // Resolve SRV / MX questions from typed overrides
+    fn try_resolve_typed(
+        &self,
+        question: &Question<Dname<Vec<u8>>>,
+    ) -> Option<Vec<Record<Dname<Vec<u8>>, OwnedRecordData>>> {
+        match question.qtype() {
+            Rtype::Srv | Rtype::Mx => (),
+            rtype if rtype.to_int() == RTYPE_SVCB || rtype.to_int() == RTYPE_HTTPS => (),
+            _ => return None,
+        }
+
+        let name = crate::util::normalize_name(&question.qname().to_string());
+        let records: Vec<_> = self
+            .typed_matches
+            .get(&name)?
+            .iter()
+            .filter(|rdata| rdata.rtype() == question.qtype())
+            .map(|rdata| self.respond_with_record(question, rdata.clone()))
+            .collect();
+        if records.len() == 0 {
             None
+        } else {
+            Some(records)
         }
     }
 
-    fn respond_with_addr(
+    fn respond_with_record(
         &self,
         question: &Question<Dname<Vec<u8>>>,
-        addr: &IpAddr,
-    ) -> Option<Record<Dname<Vec<u8>>, OwnedRecordData>> {
-        let rdata: OwnedRecordData = match addr {
-            IpAddr::V4(addr) => AllRecordData::A(A::new(addr.clone())),
-            IpAddr::V6(addr) => AllRecordData::Aaaa(Aaaa::new(addr.clone())),
+        rdata: OwnedRecordData,
+    ) -> Record<Dname<Vec<u8>>, OwnedRecordData> {
+        Record::new(
+            question.qname().clone(),
+            question.qclass(),
+            self.override_ttl,
+            rdata,
+        )
+    }
+
+    // A minimal SOA record for the authority section of negative answers
+    // we synthesize ourselves (RFC 2308); its owner is the parent of the
+    // queried name since we don't know (or care about) the actual zone cut
+    fn synthesize_soa(
+        &self,
+        question: &Question<Dname<Vec<u8>>>,
+    ) -> Record<Dname<Vec<u8>>, OwnedRecordData> {
+        let name = crate::util::normalize_name(&question.qname().to_string());
+        let owner = match name.splitn(2, '.').nth(1) {
+            Some(parent) => parent.parse().unwrap_or_else(|_| Dname::root_vec()),
+            None => Dname::root_vec(),
         };
+        Record::new(
+            owner,
+            question.qclass(),
+            self.negative_ttl,
+            AllRecordData::Soa(Soa::new(
+                self.soa_mname.clone(),
+                self.soa_rname.clone(),
+                Serial(1),
+                // Refresh, retry and expire are meaningless here
+                3600,
+                600,
+                86400,
+                // The negative caching TTL is the minimum of this field
+                // and the TTL of the SOA itself
+                self.negative_ttl,
+            )),
+        )
+    }
 
-        let record = Record::new(
+    fn try_resolve_ptr(
+        &self,
+        question: &Question<Dname<Vec<u8>>>,
+    ) -> Option<Record<Dname<Vec<u8>>, OwnedRecordData>> {
+        let name = crate::util::normalize_name(&question.qname().to_string());
+        let target = self.ptr_matches.get(&name)?;
+        Some(Record::new(
             question.qname().clone(),
             question.qclass(),
             self.override_ttl,
-            rdata,
+            AllRecordData::Ptr(Ptr::new(target.clone())),
+        ))
+    }
+
+    // Only addresses of the family asked for are returned, while ANY
+    // (and the other types we handle) gets addresses of both families
+    fn respond_with_addrs(
+        &self,
+        question: &Question<Dname<Vec<u8>>>,
+        addrs: &[IpAddr],
+    ) -> Vec<Record<Dname<Vec<u8>>, OwnedRecordData>> {
+        addrs
+            .iter()
+            .filter(|addr| match question.qtype() {
+                Rtype::A => addr.is_ipv4(),
+                Rtype::Aaaa => addr.is_ipv6(),
+                _ => true,
+            })
+            .map(|addr| {
+                let rdata: OwnedRecordData = match addr {
+                    IpAddr::V4(addr) => AllRecordData::A(A::new(addr.clone())),
+                    IpAddr::V6(addr) => AllRecordData::Aaaa(Aaaa::new(addr.clone())),
+                };
+                self.respond_with_record(question, rdata)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use domain::base::iana::Class;
+    use serde_json::json;
+
+    fn question(name: &str, rtype: Rtype) -> Question<Dname<Vec<u8>>> {
+        Question::new(name.parse().unwrap(), rtype, Class::In)
+    }
+
+    fn resolver(overrides: serde_json::Value) -> OverrideResolver {
+        OverrideResolver::new(OverrideOptions {
+            overrides: serde_json::from_value(overrides).unwrap(),
+            override_ttl: 300,
+            ..Default::default()
+        })
+        .unwrap()
+        .0
+    }
+
+    // The record data of the answers in presentation format
+    fn answers(resolver: &OverrideResolver, name: &str, rtype: Rtype) -> Option<Vec<String>> {
+        resolver
+            .try_resolve(&question(name, rtype), None)
+            .map(|result| {
+                result
+                    .answers
+                    .iter()
+                    .map(|r| r.data().to_string())
+                    .collect()
+            })
+    }
+
+    #[test]
+    fn double_wildcard_matches_apex_and_subdomains() {
+        let resolver = resolver(json!({"**.example.com": "192.0.2.1"}));
+        for name in ["example.com", "www.example.com", "a.b.example.com"].iter() {
+            assert_eq!(
+                answers(&resolver, name, Rtype::A),
+                Some(vec!["192.0.2.1".to_string()])
+            );
+        }
+        // Only whole labels match
+        assert_eq!(answers(&resolver, "notexample.com", Rtype::A), None);
+    }
+
+    #[test]
+    fn single_wildcard_leaves_the_apex_alone() {
+        let resolver = resolver(json!({"*.example.com": "192.0.2.1"}));
+        assert_eq!(
+            answers(&resolver, "a.b.example.com", Rtype::A),
+            Some(vec!["192.0.2.1".to_string()])
+        );
+        assert_eq!(answers(&resolver, "example.com", Rtype::A), None);
+    }
+
+    #[test]
+    fn keys_are_trimmed_and_lowercased_with_a_warning() {
+        let (key, warning) = OverrideResolver::normalize_key("WWW.Example.com \t".to_string());
+        assert_eq!(key, "www.example.com");
+        assert_eq!(
+            warning.unwrap(),
+            "Override key \"WWW.Example.com \\t\" normalized to \"www.example.com\""
+        );
+        // Keys that are fine as they are don't warn
+        let (key, warning) = OverrideResolver::normalize_key("*.example.com".to_string());
+        assert_eq!(key, "*.example.com");
+        assert!(warning.is_none());
+    }
+
+    #[test]
+    fn normalized_keys_match_queries() {
+        let (resolver, messages) = OverrideResolver::new(OverrideOptions {
+            overrides: serde_json::from_value(json!({"  Host.Example.COM ": "192.0.2.1"})).unwrap(),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(
+            answers(&resolver, "host.example.com", Rtype::A),
+            Some(vec!["192.0.2.1".to_string()])
+        );
+        assert!(messages[0].contains("normalized to \"host.example.com\""));
+    }
+
+    #[test]
+    fn scoped_overrides_depend_on_the_client_subnet() {
+        let resolver = resolver(json!({
+            "ads.example": {
+                "by_subnet": {"10.0.0.0/16": "192.0.2.1", "10.0.1.0/24": "0.0.0.0"},
+                "default": "192.0.2.2",
+            }
+        }));
+        let answer = |client_ip: Option<&str>| {
+            let result = resolver
+                .try_resolve(
+                    &question("ads.example", Rtype::A),
+                    client_ip.map(|ip| ip.parse().unwrap()),
+                )
+                .unwrap();
+            result.answers[0].data().to_string()
+        };
+        // The guest subnet is more specific than the one of the whole site
+        assert_eq!(answer(Some("10.0.1.7")), "0.0.0.0");
+        assert_eq!(answer(Some("10.0.2.7")), "192.0.2.1");
+        assert_eq!(answer(Some("192.168.1.1")), "192.0.2.2");
+        assert_eq!(answer(None), "192.0.2.2");
+    }
+
+    #[test]
+    fn blocked_answers_use_the_block_ttl() {
+        let mut blocklist = HashSet::new();
+        blocklist.insert("ads.example".to_string());
+        let (resolver, _) = OverrideResolver::new(OverrideOptions {
+            overrides: serde_json::from_value(json!({"internal.example": "192.0.2.1"})).unwrap(),
+            override_ttl: 3600,
+            block_ttl: 10,
+            extra_block_list: blocklist,
+            ..Default::default()
+        })
+        .unwrap();
+        let ttl = |name| {
+            resolver
+                .try_resolve(&question(name, Rtype::A), None)
+                .unwrap()
+                .answers[0]
+                .ttl()
+        };
+        assert_eq!(ttl("ads.example"), 10);
+        assert_eq!(ttl("internal.example"), 3600);
+    }
+
+    #[test]
+    fn hosts_are_parsed_and_resolved() {
+        let (hosts, warnings) = parse_hosts(
+            "# Development hosts\n\
+             192.0.2.1 dev.example  api.dev.example # two names\n\
+             \n\
+             2001:db8::1\tdev.example\n\
+             bogus nope.example\n",
+        );
+        assert_eq!(hosts.len(), 2);
+        assert_eq!(
+            warnings,
+            vec!["Invalid address in hosts.txt: bogus nope.example"]
+        );
+
+        let mut resolver = resolver(json!({"api.dev.example": "192.0.2.9"}));
+        resolver.build_match_tables(HashMap::new(), &hosts);
+        assert_eq!(
+            answers(&resolver, "dev.example", Rtype::A),
+            Some(vec!["192.0.2.1".to_string()])
+        );
+        assert_eq!(
+            answers(&resolver, "dev.example", Rtype::Aaaa),
+            Some(vec!["2001:db8::1".to_string()])
+        );
+        // Explicit overrides take precedence
+        assert_eq!(
+            answers(&resolver, "api.dev.example", Rtype::A),
+            Some(vec!["192.0.2.9".to_string()])
+        );
+    }
+
+    #[test]
+    fn address_overrides_answer_reverse_lookups() {
+        let resolver = resolver(json!({
+            "host.example": ["192.0.2.1", "2001:db8::1"],
+            "blocked.example": "0.0.0.0",
+        }));
+        let ptr = |name| {
+            let result = resolver.try_resolve(&question(name, Rtype::Ptr), None)?;
+            assert_eq!(result.answers.len(), 1);
+            match result.answers[0].data() {
+                AllRecordData::Ptr(ptr) => Some(ptr.ptrdname().to_string()),
+                _ => panic!("not a PTR record"),
+            }
+        };
+        assert_eq!(
+            ptr("1.2.0.192.in-addr.arpa"),
+            Some("host.example".to_string())
+        );
+        assert_eq!(
+            ptr("1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa"),
+            Some("host.example".to_string())
         );
-        return Some(record);
+        // Unspecified (blocking) addresses have no reverse mapping
+        assert_eq!(ptr("0.0.0.0.in-addr.arpa"), None);
     }
 }
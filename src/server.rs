@@ -1,31 +1,40 @@
-use crate::client::Client;
-use crate::r#override::OverrideResolver;
+use crate::audit::{AuditLog, AuditLogOptions};
+use crate::cache::{CacheMode, DnsCache};
+use crate::chaos::{ChaosOptions, ChaosResponder};
+use crate::client::{
+    AnyMode, Client, ClientOptions, QueryContext, QueryResult, QueryStrategy, Upstream,
+    NO_UPSTREAM_ERROR,
+};
+use crate::dns64::{Dns64, Dns64Options};
+use crate::edns::{Opt, DEFAULT_UDP_SIZE, EDE_NETWORK_ERROR, EDE_NO_REACHABLE_AUTHORITY};
+use crate::health::{HealthCheck, HealthCheckOptions};
+use crate::json::{JsonQuery, JsonQueryType, JsonResponse};
+use crate::metrics::{Metrics, MetricsFlushOptions};
+use crate::r#override::{
+    parse_blocklist, BlockMode, OverrideOptions, OverrideResolver, OverrideValue,
+    SyntheticSoaOptions,
+};
+use crate::util::{Subnet, TtlClamp};
+use crate::zone::{LocalZoneOptions, LocalZones};
 use async_static::async_static;
 use domain::base::{
-    iana::{Opcode, Rcode},
-    record::AsRecord,
-    Dname, Message, MessageBuilder, Question, ToDname,
+    iana::{Class, Opcode, Rcode},
+    message_builder::StaticCompressor,
+    Dname, Message, MessageBuilder, Question, Rtype, ToDname,
 };
 use js_sys::{ArrayBuffer, Uint8Array};
-use serde::Deserialize;
-use std::borrow::Borrow;
-use std::collections::HashMap;
-use wasm_bindgen_futures::JsFuture;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use wasm_bindgen::JsValue;
+use wasm_bindgen_futures::{future_to_promise, JsFuture};
 use web_sys::*;
 
 macro_rules! err_response {
-    ($x:expr) => {
+    ($fmt:expr, $x:expr) => {
         match $x {
             Ok(b) => b,
-            Err(err) => {
-                let headers = Headers::new().unwrap();
-                headers.append("X-PeterCxy-Error-Message", &err).unwrap();
-                return Response::new_with_opt_str_and_init(
-                    Some(&err),
-                    ResponseInit::new().status(400).headers(&headers),
-                )
-                .unwrap();
-            }
+            Err(err) => return Server::build_error_response($fmt, &err),
         }
     };
 }
@@ -33,7 +42,9 @@ macro_rules! err_response {
 async_static! {
     // Cache of a single Server object to avoid parsing config
     // multiple times
-    static ref SERVER: Server = Server::init().await;
+    // Holds the error instead if the config is invalid, so that it can be
+    // reported in responses instead of panicking on every request
+    static ref SERVER: Result<Server, String> = Server::init().await;
 }
 
 enum DnsResponseFormat {
@@ -41,73 +52,684 @@ enum DnsResponseFormat {
     JsonFormat,
 }
 
-#[derive(Deserialize)]
+// Format of the error responses, negotiated by the Accept header
+enum ErrorFormat {
+    PlainText,
+    Json,
+}
+
+// Operational mode of the server
+#[derive(Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ServerMode {
+    Normal,
+    // Answer every query with SERVFAIL without touching upstream or cache,
+    // e.g. to stop serving (possibly bad) data during an incident
+    Blackhole,
+}
+
+impl Default for ServerMode {
+    fn default() -> ServerMode {
+        ServerMode::Normal
+    }
+}
+
+// Response code for questions of `refused_types`
+#[derive(Deserialize, Serialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum RefusedTypesRcode {
+    Refused,
+    NotImp,
+}
+
+impl Default for RefusedTypesRcode {
+    fn default() -> RefusedTypesRcode {
+        RefusedTypesRcode::Refused
+    }
+}
+
+impl RefusedTypesRcode {
+    fn rcode(&self) -> Rcode {
+        match self {
+            RefusedTypesRcode::Refused => Rcode::Refused,
+            RefusedTypesRcode::NotImp => Rcode::NotImp,
+        }
+    }
+}
+
+// Zone transfers make no sense through a recursive resolver
+fn default_refused_types() -> Vec<String> {
+    vec!["AXFR".to_string(), "IXFR".to_string()]
+}
+
+// An alternative set of overrides and blocklists, applied instead of the
+// top-level ones to clients in the subnets mapped to it (see
+// `rulesets_by_subnet`), e.g. strict ad-blocking only for guests
+#[derive(Deserialize, Serialize)]
+pub struct RulesetOptions {
+    #[serde(default)]
+    overrides: HashMap<String, OverrideValue>,
+    // Whether the compiled-in blocklists apply (those enabled by the
+    // top-level `blocklist_categories`)
+    #[serde(default = "default_true")]
+    builtin_blocklist: bool,
+    // Same as the top-level option
+    #[serde(default)]
+    blocklist_kv_key: Option<String>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_response_udp_size() -> u16 {
+    DEFAULT_UDP_SIZE
+}
+
+fn default_upstream_udp_size() -> u16 {
+    DEFAULT_UDP_SIZE
+}
+
+// Workers allow 6 simultaneous open connections per request
+fn default_cache_write_concurrency() -> usize {
+    6
+}
+
+// The maximum size of a DNS message
+fn default_max_upstream_response_bytes() -> usize {
+    u16::MAX as usize
+}
+
+fn default_negative_ttl() -> u32 {
+    60
+}
+
+fn default_user_agent() -> String {
+    format!("{}/{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
+}
+
+#[derive(Deserialize, Serialize)]
 pub struct ServerOptions {
-    upstream_urls: Vec<String>,
+    // "normal" (default) or "blackhole"
+    #[serde(default)]
+    mode: ServerMode,
+    // Record types answered locally with `refused_types_rcode` ("refused",
+    // the default, or "notimp") instead of being forwarded, e.g. ANY
+    #[serde(default = "default_refused_types")]
+    refused_types: Vec<String>,
+    #[serde(default)]
+    refused_types_rcode: RefusedTypesRcode,
+    // Either URLs or `{"url": ..., "weight": ..., "format": ...}` objects,
+    // where format is "wireformat" (default) or "json" (see client.rs)
+    upstream_urls: Vec<Upstream>,
+    // Map of domain suffixes to upstream groups (conditional forwarding)
+    #[serde(default)]
+    forwarders: HashMap<String, Vec<Upstream>>,
+    // Map of record types to upstream groups, e.g. to send all HTTPS queries
+    // to a resolver with better support for them
+    // Names matched by `forwarders` still go to their own upstreams
+    #[serde(default)]
+    upstreams_by_type: HashMap<String, Vec<Upstream>>,
+    // Number of retries after the first failed attempt (at most 10)
     retries: usize,
+    // "random" (default, by weight) or "priority" (in the configured order,
+    // falling back to the next upstream in the group on failure)
+    // With "priority", every attempt walks the whole group
     #[serde(default)]
-    overrides: HashMap<String, String>,
+    query_strategy: QueryStrategy,
+    // Wait this many milliseconds before the first retry, doubling the
+    // delay for every further retry; 0 (default) retries immediately
+    #[serde(default)]
+    retry_backoff_ms: u32,
+    #[serde(default)]
+    overrides: HashMap<String, OverrideValue>,
+    // 0 (the default) means clients shouldn't cache overridden answers;
+    // ttl_clamp applies to anything else
     #[serde(default)]
     override_ttl: u32,
+    // TTL of the 0.0.0.0 / :: answers for blocked names with the "address"
+    // block_mode; defaults to `override_ttl`
+    // (with "nxdomain", `negative_ttl` applies instead)
+    #[serde(default)]
+    block_ttl: Option<u32>,
+    // Zones answered authoritatively without upstream, by origin (see zone.rs)
+    #[serde(default)]
+    local_zones: HashMap<String, LocalZoneOptions>,
+    // TTL (and SOA minimum) of negative answers synthesized locally
+    #[serde(default = "default_negative_ttl")]
+    negative_ttl: u32,
+    // mname / rname of the synthesized SOA records
+    #[serde(default)]
+    synthetic_soa: SyntheticSoaOptions,
+    // "address" (default) or "nxdomain"
+    #[serde(default)]
+    block_mode: BlockMode,
+    // Categories of the compiled-in blocklists (see build.rs) to whether
+    // they are enabled, e.g. `{"ads": true, "malware": false}`
+    // Categories not listed here are enabled
+    #[serde(default)]
+    blocklist_categories: HashMap<String, bool>,
+    // Key of an additional blocklist in the BLOCKLIST KV namespace, in the
+    // same format as blocklist.txt and optionally gzip-compressed
+    // Large lists are better kept there than compiled into the worker
+    #[serde(default)]
+    blocklist_kv_key: Option<String>,
+    // Named rulesets, and the client subnets (in CIDR notation) they apply
+    // to; clients outside all of these get the top-level overrides and
+    // blocklists, and the most specific subnet wins
+    #[serde(default)]
+    rulesets: HashMap<String, RulesetOptions>,
+    #[serde(default)]
+    rulesets_by_subnet: HashMap<String, String>,
+    // Bounds applied to TTLs of both cached and overridden records
+    #[serde(default)]
+    ttl_clamp: TtlClamp,
+    // Caps on cached TTLs by record type, e.g. `{"A": 3600, "TXT": 300}`
+    // Types not listed keep the upstream TTL (subject to ttl_clamp)
+    #[serde(default)]
+    ttl_by_type: HashMap<String, u32>,
+    // If set, keep cached records for this many seconds past their TTL,
+    // and serve them when all upstream attempts fail
+    #[serde(default)]
+    stale_if_error_ttl: Option<u64>,
+    // Set to false to never touch the DNS_CACHE KV namespace; no query data
+    // is persisted then, and the binding does not need to exist
+    // (caching is also disabled, with a warning, if the binding is missing)
+    #[serde(default = "default_true")]
+    cache_enabled: bool,
+    // "per_record" (default) or "per_question"; the latter stores all answers
+    // to one question in a single KV entry to save on KV operations
+    // Entries of one mode are not visible to the other
+    #[serde(default)]
+    cache_mode: CacheMode,
+    // Maximum number of concurrent KV writes when caching answers
+    #[serde(default = "default_cache_write_concurrency")]
+    cache_write_concurrency: usize,
+    #[serde(default)]
+    dns64: Option<Dns64Options>,
+    #[serde(default)]
+    health_check: Option<HealthCheckOptions>,
+    // CHAOS TXT queries for version.bind / hostname.bind: "mode" is "answer"
+    // (default), "refuse" or "forward", with the strings in "version" and
+    // "hostname" (both default to the name and version of this worker)
+    #[serde(default)]
+    chaos: ChaosOptions,
+    // How ANY questions are handled: "forward" (default), "expand" or "refuse"
+    #[serde(default)]
+    any_mode: AnyMode,
+    // Names to resolve (and thus cache) proactively after startup
+    #[serde(default)]
+    prefetch: Vec<String>,
+    // Pad responses to a multiple of this size with the EDNS(0) padding option
+    // (only for clients using EDNS); 468 is the size recommended by RFC 8467
+    #[serde(default)]
+    padding_block_size: Option<usize>,
+    // UDP payload size advertised in our OPT records; responses to EDNS
+    // clients are truncated to the smaller of this and the client's size
+    #[serde(default = "default_response_udp_size")]
+    response_udp_size: u16,
+    // Log all queries to the AUDIT_LOG KV namespace (see audit.rs)
+    #[serde(default)]
+    audit_log: Option<AuditLogOptions>,
+    // User-Agent sent to upstreams
+    #[serde(default = "default_user_agent")]
+    user_agent: String,
+    // Randomize the casing of names sent to upstreams and verify it in
+    // responses (0x20 encoding) to make spoofing harder; note that this
+    // prevents identical queries from hitting the same HTTP cache entry
+    // Responses to clients always echo the question as they sent it
+    #[serde(default)]
+    randomize_case: bool,
+    // Cloudflare-specific options for upstream fetches (the `cf` property
+    // of RequestInit), e.g. `{"cacheTtl": 60}`; note that Cloudflare does
+    // not cache POST requests, which is what we send upstream
+    #[serde(default)]
+    upstream_cf_options: Option<serde_json::Value>,
+    // Upstream responses larger than this are treated as failures (and
+    // retried), guarding memory against broken or hostile upstreams
+    #[serde(default = "default_max_upstream_response_bytes")]
+    max_upstream_response_bytes: usize,
+    // UDP payload size advertised to upstreams whenever an OPT record is
+    // attached to queries (i.e. for DNSSEC); 1232 as per DNS Flag Day 2020
+    #[serde(default = "default_upstream_udp_size")]
+    upstream_udp_size: u16,
+    // Expose X-Upstream and X-Cache headers in responses for debugging, and
+    // include the body of upstream error responses in error messages
+    #[serde(default)]
+    debug_headers: bool,
+    // Only return the question and answer sections (plus OPT), dropping
+    // authority records even if upstream provided them; this helps with
+    // constrained stub resolvers that choke on larger responses
+    #[serde(default)]
+    minimal_responses: bool,
+    // Drop RRSIG / NSEC / NSEC3 / DNSKEY / DS records some upstreams return
+    // even without DO from responses to clients that didn't set DO either,
+    // as required by RFC 4035 (unless the type was asked for explicitly)
+    #[serde(default = "default_true")]
+    strip_dnssec: bool,
+    // Only return this many answer records, setting TC if there were more
+    // This limits amplification and helps constrained clients
+    #[serde(default)]
+    max_answer_records: Option<usize>,
+    // Cap TTLs in responses (but not in the cache) at this many seconds, so
+    // that clients re-query often and e.g. pick up override changes quickly
+    #[serde(default)]
+    serve_max_ttl: Option<u32>,
+    // Explain blocked names and upstream failures to EDNS clients with
+    // Extended DNS Errors (RFC 8914)
+    #[serde(default)]
+    extended_errors: bool,
+    // Sort answers by type and then record data, so that responses are
+    // deterministic regardless of where the answers came from
+    // Off by default, as it defeats the round-robin done by upstreams
+    #[serde(default)]
+    sort_answers: bool,
+    // Shuffle answers of the same type (after sorting, if enabled), like the
+    // round-robin of traditional resolvers, to spread load across names
+    // with multiple addresses for clients only using the first one
+    #[serde(default)]
+    shuffle_answers: bool,
+    // Secret for the monitoring endpoints (/metrics, /stats), to be passed in
+    // the X-Stats-Secret header; the endpoints are disabled if unset
+    #[serde(default)]
+    stats_secret: Option<String>,
+    // Secret allowing requests to pick the upstream with the
+    // X-Upstream-Override header (passing the secret in
+    // X-Upstream-Override-Secret), e.g. to compare answers in staging
+    // Such requests neither read from nor write to the cache
+    #[serde(default)]
+    upstream_override_secret: Option<String>,
+    // Periodically add the counters to the ones stored in the METRICS KV
+    // namespace, so that they survive instances being recycled
+    #[serde(default)]
+    metrics_flush: Option<MetricsFlushOptions>,
+}
+
+// Options masked in ServerOptions::redacted_json
+const SECRET_OPTIONS: [&str; 2] = ["stats_secret", "upstream_override_secret"];
+
+impl ServerOptions {
+    // The effective options as JSON, for display at /stats?config=1
+    // Secrets are masked, but the rest (e.g. overrides) is shown as-is
+    pub fn redacted_json(&self) -> String {
+        let mut value = match serde_json::to_value(self) {
+            Ok(value) => value,
+            Err(e) => return format!("Could not serialize options: {}", e),
+        };
+        for key in SECRET_OPTIONS.iter() {
+            if let Some(secret) = value.get_mut(key) {
+                if !secret.is_null() {
+                    *secret = serde_json::Value::from("<redacted>");
+                }
+            }
+        }
+        serde_json::to_string_pretty(&value).unwrap_or_default()
+    }
 }
 
 pub struct Server {
+    mode: ServerMode,
+    // Numeric record types
+    refused_types: HashSet<u16>,
+    refused_types_rcode: RefusedTypesRcode,
     client: Client,
     retries: usize,
+    health_check: Option<HealthCheck>,
+    chaos: ChaosResponder,
+    prefetch: Vec<String>,
+    prefetch_started: AtomicBool,
+    padding_block_size: Option<usize>,
+    response_udp_size: u16,
+    audit_log: Option<AuditLog>,
+    debug_headers: bool,
+    minimal_responses: bool,
+    strip_dnssec: bool,
+    max_answer_records: Option<usize>,
+    serve_max_ttl: Option<u32>,
+    extended_errors: bool,
+    sort_answers: bool,
+    shuffle_answers: bool,
+    metrics: Metrics,
+    stats_secret: Option<String>,
+    upstream_override_secret: Option<String>,
+    // Rendered before the options are consumed below
+    config_json: String,
 }
 
 impl Server {
-    fn new(options: ServerOptions) -> Server {
-        Server {
-            client: Client::new(
-                options.upstream_urls,
-                OverrideResolver::new(options.overrides, options.override_ttl),
-            ),
-            retries: options.retries,
+    // `has_binding` tells whether a KV namespace is bound to the worker
+    fn new(
+        options: ServerOptions,
+        kv_block_lists: HashMap<String, HashSet<String>>,
+        has_binding: fn(&str) -> bool,
+    ) -> Result<Server, String> {
+        let config_json = options.redacted_json();
+        let kv_block_list = |key: &Option<String>| {
+            key.as_ref()
+                .and_then(|key| kv_block_lists.get(key))
+                .cloned()
+                .unwrap_or_default()
+        };
+        let builtin_block_lists =
+            crate::r#override::builtin_blocklists(&options.blocklist_categories)?;
+        let mut rulesets = HashMap::new();
+        for (name, ruleset) in options.rulesets {
+            let (resolver, messages) = OverrideResolver::new(OverrideOptions {
+                overrides: ruleset.overrides,
+                override_ttl: options.override_ttl,
+                block_ttl: options.block_ttl.unwrap_or(options.override_ttl),
+                negative_ttl: options.negative_ttl,
+                synthetic_soa: options.synthetic_soa.clone(),
+                block_mode: options.block_mode,
+                ttl_clamp: options.ttl_clamp,
+                builtin_block_lists: if ruleset.builtin_blocklist {
+                    builtin_block_lists.clone()
+                } else {
+                    Vec::new()
+                },
+                extra_block_list: kv_block_list(&ruleset.blocklist_kv_key),
+            })?;
+            for msg in messages {
+                crate::util::log(&format!("Ruleset {}: {}", name, msg));
+            }
+            rulesets.insert(name, resolver);
         }
+        let mut ruleset_by_subnet = Vec::new();
+        for (subnet, name) in options.rulesets_by_subnet {
+            if !rulesets.contains_key(&name) {
+                return Err(format!("Unknown ruleset {} for {}", name, subnet));
+            }
+            ruleset_by_subnet.push((Subnet::parse(&subnet)?, name));
+        }
+        ruleset_by_subnet.sort_by_key(|(subnet, _)| std::cmp::Reverse(subnet.prefix_len()));
+
+        // Forgetting the binding is an easy mistake on the first deployment;
+        // resolving without a cache beats failing every request
+        let cache_enabled = options.cache_enabled && has_binding("DNS_CACHE");
+        if options.cache_enabled && !cache_enabled {
+            crate::util::log("DNS_CACHE KV binding not found, caching is disabled");
+        }
+
+        // Unlike the cache, an audit log that silently goes nowhere is worse
+        // than refusing to start
+        if options.audit_log.is_some() && !has_binding("AUDIT_LOG") {
+            return Err(
+                "audit_log is configured but the AUDIT_LOG KV binding is missing".to_string(),
+            );
+        }
+        if options.metrics_flush.is_some() && !has_binding("METRICS") {
+            return Err(
+                "metrics_flush is configured but the METRICS KV binding is missing".to_string(),
+            );
+        }
+
+        let refused_types = Self::parse_refused_types(&options.refused_types)?;
+
+        let (override_resolver, messages) = OverrideResolver::new(OverrideOptions {
+            overrides: options.overrides,
+            override_ttl: options.override_ttl,
+            block_ttl: options.block_ttl.unwrap_or(options.override_ttl),
+            negative_ttl: options.negative_ttl,
+            synthetic_soa: options.synthetic_soa,
+            block_mode: options.block_mode,
+            ttl_clamp: options.ttl_clamp,
+            builtin_block_lists,
+            extra_block_list: kv_block_list(&options.blocklist_kv_key),
+        })?;
+        for msg in crate::r#override::hosts_warnings()
+            .iter()
+            .chain(messages.iter())
+        {
+            crate::util::log(msg);
+        }
+
+        Ok(Server {
+            mode: options.mode,
+            refused_types,
+            refused_types_rcode: options.refused_types_rcode,
+            client: Client::new(ClientOptions {
+                upstream_urls: options.upstream_urls,
+                forwarders: options.forwarders,
+                upstreams_by_type: options.upstreams_by_type,
+                cache: if cache_enabled {
+                    Some(DnsCache::new(
+                        options.ttl_clamp,
+                        options.ttl_by_type,
+                        options.stale_if_error_ttl.unwrap_or(0),
+                        options.cache_mode,
+                        options.cache_write_concurrency,
+                    )?)
+                } else {
+                    None
+                },
+                override_resolver,
+                rulesets,
+                ruleset_by_subnet,
+                local_zones: LocalZones::new(options.local_zones)?,
+                dns64: options.dns64.map(Dns64::new).transpose()?,
+                any_mode: options.any_mode,
+                stale_if_error: options.stale_if_error_ttl.is_some(),
+                user_agent: options.user_agent,
+                randomize_case: options.randomize_case,
+                cf_options: options.upstream_cf_options,
+                max_upstream_response_bytes: options.max_upstream_response_bytes,
+                upstream_udp_size: options.upstream_udp_size,
+                upstream_error_details: options.debug_headers,
+                query_strategy: options.query_strategy,
+                retry_backoff_ms: options.retry_backoff_ms,
+            }),
+            retries: options.retries,
+            health_check: options.health_check.map(HealthCheck::new).transpose()?,
+            chaos: ChaosResponder::new(options.chaos),
+            prefetch: options.prefetch,
+            prefetch_started: AtomicBool::new(false),
+            padding_block_size: options.padding_block_size,
+            response_udp_size: options.response_udp_size,
+            audit_log: options.audit_log.map(AuditLog::new),
+            debug_headers: options.debug_headers,
+            minimal_responses: options.minimal_responses,
+            strip_dnssec: options.strip_dnssec,
+            max_answer_records: options.max_answer_records,
+            serve_max_ttl: options.serve_max_ttl,
+            extended_errors: options.extended_errors,
+            sort_answers: options.sort_answers,
+            shuffle_answers: options.shuffle_answers,
+            metrics: Metrics::new(options.metrics_flush)?,
+            stats_secret: options.stats_secret,
+            upstream_override_secret: options.upstream_override_secret,
+            config_json,
+        })
     }
 
     // The server initialization process might become truly async in the future
-    async fn init() -> Server {
-        let config: ServerOptions = serde_json::from_str(include_str!("../config.json")).unwrap();
-        Self::new(config)
+    async fn init() -> Result<Server, String> {
+        // build.rs puts config.json here, or a default config if it's missing
+        let config: ServerOptions =
+            serde_json::from_str(include_str!(concat!(env!("OUT_DIR"), "/config.json")))
+                .map_err(|e| format!("Invalid config.json: {}", e))?;
+        let keys = config.blocklist_kv_key.iter().chain(
+            config
+                .rulesets
+                .values()
+                .filter_map(|r| r.blocklist_kv_key.as_ref()),
+        );
+        let mut kv_block_lists = HashMap::new();
+        for key in keys {
+            if kv_block_lists.contains_key(key) {
+                continue;
+            }
+            // Not fatal, as a KV hiccup would otherwise break the instance for good
+            let block_list = Self::load_kv_blocklist(key).await.unwrap_or_else(|e| {
+                crate::util::log(&e);
+                HashSet::new()
+            });
+            kv_block_lists.insert(key.clone(), block_list);
+        }
+        Self::new(config, kv_block_lists, crate::kv::has_binding)
+            .map_err(|e| format!("Invalid config.json: {}", e))
+    }
+
+    async fn load_kv_blocklist(key: &str) -> Result<HashSet<String>, String> {
+        let buf = crate::kv::get_blocklist()
+            .get_buf(key)
+            .await
+            .ok_or(format!("Blocklist {} not found in KV", key))?;
+        let text = String::from_utf8(crate::util::maybe_gunzip(buf)?)
+            .map_err(|_| format!("Blocklist {} is not valid UTF-8", key))?;
+        Ok(parse_blocklist(&text))
+    }
+
+    pub async fn get<'a>() -> Result<&'a Server, &'a str> {
+        SERVER.await.as_ref().map_err(|e| e.as_str())
+    }
+
+    // Warm up the cache with the configured prefetch names
+    // This is only done once per instance, in the background of the first
+    // request (via waitUntil) so that the request itself is not delayed
+    pub fn prefetch_once(&'static self, ev: &ExtendableEvent) {
+        if self.prefetch.len() == 0
+            || self.mode == ServerMode::Blackhole
+            || self.prefetch_started.swap(true, Ordering::Relaxed)
+        {
+            return;
+        }
+
+        let promise = future_to_promise(async move {
+            self.prefetch().await;
+            Ok(JsValue::UNDEFINED)
+        });
+        // Ignore error -- prefetching is best-effort
+        let _ = ev.wait_until(&promise);
     }
 
-    pub async fn get<'a>() -> &'a Server {
-        SERVER.await
+    #[allow(unused_must_use)]
+    async fn prefetch(&self) {
+        for name in self.prefetch.iter() {
+            let name: Dname<Vec<u8>> = match name.parse() {
+                Ok(name) => name,
+                // Ignore malformed names
+                Err(_) => continue,
+            };
+            for rtype in [Rtype::A, Rtype::Aaaa].iter() {
+                // Client::query caches the answers as a side effect
+                self.client
+                    .query_with_retry(
+                        vec![Question::new(name.clone(), *rtype, Class::In)],
+                        self.retries,
+                        &QueryContext::default(),
+                    )
+                    .await;
+            }
+        }
     }
 
-    pub async fn handle_request(&self, _ev: ExtendableEvent, req: Request) -> Response {
-        let body = err_response!(Self::parse_dns_body(&req).await);
+    pub async fn handle_request(&self, ev: ExtendableEvent, req: Request) -> Response {
+        if let Some(resp) = self.try_handle_stats(&req) {
+            return resp;
+        }
+
+        let err_format = Self::get_error_format(&req);
+        let body = err_response!(&err_format, Self::parse_dns_body(&req).await);
         let query_id = body.header().id(); // random ID that needs to be preserved in response
-        let questions = err_response!(Self::extract_questions(body));
-        let records = err_response!(
-            self.client
-                .query_with_retry(questions.clone(), self.retries)
-                .await
-        );
+        let client_udp_size = body.opt().map(|opt| opt.udp_payload_size()); // None without EDNS
+        let dnssec_ok = body.opt().map(|opt| opt.dnssec_ok()).unwrap_or(false);
+        let questions = err_response!(&err_format, Self::extract_questions(body));
+        let ctx = QueryContext {
+            client_ip: req
+                .headers()
+                .get("CF-Connecting-IP")
+                .ok()
+                .flatten()
+                .and_then(|ip| ip.parse().ok()),
+            upstream: err_response!(&err_format, self.get_upstream_override(&req)),
+            dnssec_ok,
+        };
+        // Dry runs still look up the cache, so the kill switch covers them
+        // too; in blackhole mode they get the same SERVFAIL as any query
+        if self.mode != ServerMode::Blackhole && req.headers().has("X-Explain").unwrap_or(false) {
+            return self.handle_explain(&req, &questions, &ctx).await;
+        }
+        let (result, query_error) = self.answer_questions(&questions, &ctx).await;
+        self.metrics.record_query(&result);
+        self.metrics.flush_if_due(&ev);
+        if let Some(audit_log) = &self.audit_log {
+            audit_log.log(&ev, &req, &questions, &result);
+        }
         let resp_format = Self::get_response_format(&req);
+        let opt = self.response_opt(client_udp_size, dnssec_ok, &result, query_error.as_deref());
+        let debug_upstream = result.upstream.clone();
+        let debug_cache_hit = result.cache_hit;
+        let age = result.age;
 
-        let resp_body = err_response!(match &resp_format {
-            &DnsResponseFormat::WireFormat =>
-                Self::build_answer_wireformat(query_id, questions, records).map(|x| x.into_octets()),
-            &DnsResponseFormat::JsonFormat => Err("JSON is not supported yet".to_string()),
-        });
+        let resp_body = err_response!(
+            &err_format,
+            match &resp_format {
+                &DnsResponseFormat::WireFormat => Self::build_answer_wireformat(
+                    query_id,
+                    questions,
+                    result,
+                    opt,
+                    self.max_response_size(client_udp_size),
+                    self.padding_block_size,
+                )
+                .map(|x| x.into_octets()),
+                &DnsResponseFormat::JsonFormat => JsonResponse::new(&questions, &result).to_json(),
+            }
+        );
         let resp_content_type = match resp_format {
             DnsResponseFormat::WireFormat => "application/dns-message",
             DnsResponseFormat::JsonFormat => "application/dns-json",
         };
 
         // Build the response
-        let resp_headers =
-            err_response!(Headers::new().map_err(|_| "Could not create headers".to_string()));
-        err_response!(resp_headers
-            .append("Content-Type", resp_content_type)
-            .map_err(|_| "Could not create headers".to_string()));
+        let resp_headers = err_response!(
+            &err_format,
+            Headers::new().map_err(|_| "Could not create headers".to_string())
+        );
+        err_response!(
+            &err_format,
+            resp_headers
+                .append("Content-Type", resp_content_type)
+                .map_err(|_| "Could not create headers".to_string())
+        );
         // Content-Length is needed in case the DNS message itself contained end-of-string or end-of-line
-        err_response!(resp_headers
-            .append("Content-Length", &resp_body.len().to_string())
-            .map_err(|_| "Could not create headers".to_string()));
+        err_response!(
+            &err_format,
+            resp_headers
+                .append("Content-Length", &resp_body.len().to_string())
+                .map_err(|_| "Could not create headers".to_string())
+        );
+        // How long ago the (oldest) answer was fetched, for HTTP caches
+        err_response!(
+            &err_format,
+            resp_headers
+                .append("Age", &age.to_string())
+                .map_err(|_| "Could not create headers".to_string())
+        );
+        if self.debug_headers {
+            if let Some(upstream) = debug_upstream {
+                err_response!(
+                    &err_format,
+                    resp_headers
+                        .append("X-Upstream", &upstream)
+                        .map_err(|_| "Could not create headers".to_string())
+                );
+            }
+            err_response!(
+                &err_format,
+                resp_headers
+                    .append("X-Cache", if debug_cache_hit { "HIT" } else { "MISS" })
+                    .map_err(|_| "Could not create headers".to_string())
+            );
+        }
+        if let Some(err) = query_error {
+            err_response!(
+                &err_format,
+                resp_headers
+                    .append("X-PeterCxy-Error-Message", &err)
+                    .map_err(|_| "Could not create headers".to_string())
+            );
+        }
         let mut resp_init = ResponseInit::new();
         resp_init.status(200).headers(&resp_headers);
         return Response::new_with_opt_buffer_source_and_init(
@@ -117,29 +739,177 @@ impl Server {
         .unwrap();
     }
 
+    // Answer the questions of a query and shape the result for the client
+    // Also returns the reason for failures, if any
+    async fn answer_questions(
+        &self,
+        questions: &[Question<Dname<Vec<u8>>>],
+        ctx: &QueryContext,
+    ) -> (QueryResult, Option<String>) {
+        // Health check and CHAOS queries are answered without touching
+        // upstream or cache
+        let local_result = self.chaos.try_answer(questions).or_else(|| {
+            self.health_check
+                .as_ref()
+                .and_then(|h| h.try_answer(questions))
+        });
+        let mut query_error = None;
+        let mut result = match local_result {
+            // The kill switch wins over everything, even the health check
+            _ if self.mode == ServerMode::Blackhole => {
+                query_error = Some("Server is in blackhole mode".to_string());
+                QueryResult::new(Rcode::ServFail, Vec::new(), Vec::new())
+            }
+            _ if Self::is_refused(&self.refused_types, questions) => {
+                QueryResult::new(self.refused_types_rcode.rcode(), Vec::new(), Vec::new())
+            }
+            Some(result) => result,
+            None => match self
+                .client
+                .query_with_retry(questions.to_vec(), self.retries, ctx)
+                .await
+            {
+                Ok(result) => result,
+                // Failing to resolve is not a problem with the request itself,
+                // so report SERVFAIL instead of an HTTP error
+                Err(err) => {
+                    let mut result = QueryResult::new(Rcode::ServFail, Vec::new(), Vec::new());
+                    result.ede = Some(if err == NO_UPSTREAM_ERROR {
+                        EDE_NO_REACHABLE_AUTHORITY
+                    } else {
+                        EDE_NETWORK_ERROR
+                    });
+                    query_error = Some(err);
+                    result
+                }
+            },
+        };
+        if self.minimal_responses {
+            result.authority.clear();
+        }
+        if self.strip_dnssec && !ctx.dnssec_ok {
+            Self::strip_dnssec(&mut result, questions);
+        }
+        if self.sort_answers {
+            result.answers.sort_by_cached_key(|r| {
+                (
+                    r.rtype().to_int(),
+                    crate::util::owned_record_data_to_buffer(r.data()).unwrap_or_default(),
+                )
+            });
+        }
+        if self.shuffle_answers {
+            // Records only trade places with those of the same type, so that
+            // e.g. CNAME chains stay in front of the addresses
+            let mut rtypes: Vec<u16> = result.answers.iter().map(|r| r.rtype().to_int()).collect();
+            rtypes.sort();
+            rtypes.dedup();
+            for rtype in rtypes {
+                let indices: Vec<usize> = (0..result.answers.len())
+                    .filter(|i| result.answers[*i].rtype().to_int() == rtype)
+                    .collect();
+                // Fisher-Yates
+                for i in (1..indices.len()).rev() {
+                    let j = crate::util::random_range(0, i + 1);
+                    result.answers.swap(indices[i], indices[j]);
+                }
+            }
+        }
+        if let Some(max) = self.max_answer_records {
+            Self::limit_answers(&mut result, max);
+        }
+        if let Some(max_ttl) = self.serve_max_ttl {
+            for r in result.answers.iter_mut().chain(result.authority.iter_mut()) {
+                r.set_ttl(r.ttl().min(max_ttl));
+            }
+        }
+        (result, query_error)
+    }
+
+    // The OPT record of the response, only for clients using EDNS
+    // DO is copied from the query (RFC 3225); signed answers are often too
+    // large for the client's UDP size, in which case the response gets
+    // truncated with TC set, just like any other large answer
+    fn response_opt(
+        &self,
+        client_udp_size: Option<u16>,
+        dnssec_ok: bool,
+        result: &QueryResult,
+        query_error: Option<&str>,
+    ) -> Option<Opt> {
+        let mut opt = client_udp_size.map(|_| {
+            let mut opt = Opt::new(self.response_udp_size);
+            opt.set_dnssec_ok(dnssec_ok);
+            opt
+        });
+        if self.extended_errors {
+            if let (Some(opt), Some(ede)) = (opt.as_mut(), result.ede) {
+                opt.push_ede(ede, query_error.unwrap_or(""));
+            }
+        }
+        opt
+    }
+
+    // RFC 8484 mandates base64url, but some clients send standard base64
+    // so try that as a fallback, with and without padding
+    fn decode_dns_param(param: &str) -> Option<Vec<u8>> {
+        if let Ok(decoded) = base64::decode_config(param, base64::URL_SAFE) {
+            return Some(decoded);
+        }
+        // An unescaped `+` in the query string is decoded as a space
+        let param = param.replace(' ', "+");
+        [
+            base64::URL_SAFE_NO_PAD,
+            base64::STANDARD,
+            base64::STANDARD_NO_PAD,
+        ]
+        .iter()
+        .find_map(|config| base64::decode_config(&param, *config).ok())
+    }
+
     async fn parse_dns_body(req: &Request) -> Result<Message<Vec<u8>>, String> {
         let method = req.method();
         if method == "GET" {
             // GET request -- DNS wireformat or JSON
-            // TODO: implement JSON
             let url = Url::new(&req.url()).map_err(|_| "Invalid url")?;
             let params = url.search_params();
             if params.has("dns") {
                 // base64-encoded DNS wireformat via GET
-                let decoded = base64::decode_config(params.get("dns").unwrap(), base64::URL_SAFE)
-                    .map_err(|_| "Failed to decode base64 DNS request")?;
+                let decoded = Self::decode_dns_param(&params.get("dns").unwrap())
+                    .ok_or("Failed to decode base64 DNS request")?;
                 return crate::util::parse_dns_wireformat(&decoded);
+            } else if params.has("name") {
+                // JSON API query via GET, e.g. ?name=example.com&type=AAAA
+                return JsonQuery {
+                    name: params.get("name").unwrap(),
+                    qtype: params.get("type").map(JsonQueryType::Mnemonic),
+                }
+                .to_message();
             } else {
                 return Err("Missing supported GET parameters".to_string());
             }
         } else if method == "POST" {
-            // POST request -- DNS wireformat
+            // POST request -- DNS wireformat or JSON
             let headers = req.headers();
             if !headers.has("Content-Type").unwrap() {
                 return Err("Missing Content-Type header".to_string());
             }
-            if headers.get("Content-Type").unwrap().unwrap() != "application/dns-message" {
-                return Err("Unsupported Content-Type".to_string());
+            match crate::util::parse_media_type(&headers.get("Content-Type").unwrap().unwrap())
+                .as_str()
+            {
+                "application/dns-message" => (),
+                "application/dns-json" => {
+                    let req_body = req
+                        .text()
+                        .map_err(|_| "Failed to read request body".to_string())?;
+                    let req_body = JsFuture::from(req_body)
+                        .await
+                        .map_err(|_| "Failed to read request body".to_string())?
+                        .as_string()
+                        .ok_or("Request body is not a string".to_string())?;
+                    return JsonQuery::from_json(&req_body)?.to_message();
+                }
+                _ => return Err("Unsupported Content-Type".to_string()),
             }
 
             let req_body = req
@@ -188,25 +958,278 @@ impl Server {
         Ok(ret)
     }
 
+    fn get_error_format(req: &Request) -> ErrorFormat {
+        let headers = req.headers();
+        if !headers.has("Accept").unwrap() {
+            return ErrorFormat::PlainText;
+        }
+
+        let accept = crate::util::parse_accept(&headers.get("Accept").unwrap().unwrap());
+        if accept.iter().any(|(media_type, q)| {
+            *q > 0f64 && (media_type == "application/json" || media_type == "application/dns-json")
+        }) {
+            ErrorFormat::Json
+        } else {
+            ErrorFormat::PlainText
+        }
+    }
+
+    // Serve the Prometheus metrics at /metrics, the same counters as JSON
+    // at /stats and the effective config at /stats?config=1, if enabled
+    // Returns None if the request is not for a monitoring endpoint
+    fn try_handle_stats(&self, req: &Request) -> Option<Response> {
+        let secret = self.stats_secret.as_ref()?;
+        let url = Url::new(&req.url()).ok()?;
+        let path = url.pathname();
+        if path != "/metrics" && path != "/stats" {
+            return None;
+        }
+
+        let headers = Headers::new().unwrap();
+        let given = req.headers().get("X-Stats-Secret").ok().flatten();
+        if !Self::secret_matches(given.as_deref(), Some(secret)) {
+            return Some(
+                Response::new_with_opt_str_and_init(
+                    Some("Forbidden"),
+                    ResponseInit::new().status(403).headers(&headers),
+                )
+                .unwrap(),
+            );
+        }
+
+        let (body, content_type) = if path == "/stats" && url.search_params().has("config") {
+            (self.config_json.clone(), "application/json")
+        } else if path == "/stats" {
+            (
+                self.metrics
+                    .render_json(self.client.cache_errors(), &self.client.upstream_errors()),
+                "application/json",
+            )
+        } else {
+            (
+                self.metrics
+                    .render_prometheus(self.client.cache_errors(), &self.client.upstream_errors()),
+                "text/plain; version=0.0.4",
+            )
+        };
+        headers.append("Content-Type", content_type).unwrap();
+        Some(
+            Response::new_with_opt_str_and_init(
+                Some(&body),
+                ResponseInit::new().status(200).headers(&headers),
+            )
+            .unwrap(),
+        )
+    }
+
+    // Dry run: report how each question would be answered as JSON, without
+    // contacting upstream; requires the stats secret as it reveals the rules
+    async fn handle_explain(
+        &self,
+        req: &Request,
+        questions: &[Question<Dname<Vec<u8>>>],
+        ctx: &QueryContext,
+    ) -> Response {
+        let headers = Headers::new().unwrap();
+        let given = req.headers().get("X-Stats-Secret").ok().flatten();
+        if !Self::secret_matches(given.as_deref(), self.stats_secret.as_ref()) {
+            return Response::new_with_opt_str_and_init(
+                Some("Forbidden"),
+                ResponseInit::new().status(403).headers(&headers),
+            )
+            .unwrap();
+        }
+
+        let explained = self.explain_questions(questions, ctx).await;
+        headers.append("Content-Type", "application/json").unwrap();
+        Response::new_with_opt_str_and_init(
+            Some(&serde_json::json!({ "questions": explained }).to_string()),
+            ResponseInit::new().status(200).headers(&headers),
+        )
+        .unwrap()
+    }
+
+    // How each question would be answered, in the same order of precedence
+    // as answer_questions
+    async fn explain_questions(
+        &self,
+        questions: &[Question<Dname<Vec<u8>>>],
+        ctx: &QueryContext,
+    ) -> Vec<serde_json::Value> {
+        let mut explained = Vec::new();
+        for q in questions {
+            let single = std::slice::from_ref(q);
+            let local_rule = if Self::is_refused(&self.refused_types, single) {
+                Some("refused")
+            } else if self.chaos.try_answer(single).is_some() {
+                Some("chaos")
+            } else if self
+                .health_check
+                .as_ref()
+                .and_then(|h| h.try_answer(single))
+                .is_some()
+            {
+                Some("health_check")
+            } else {
+                None
+            };
+            explained.push(match local_rule {
+                Some(rule) => serde_json::json!({
+                    "name": q.qname().to_string(),
+                    "type": crate::util::rtype_to_string(q.qtype()),
+                    "override": rule,
+                }),
+                None => self.client.explain(q, ctx).await,
+            });
+        }
+        explained
+    }
+
+    // The upstream forced by the X-Upstream-Override header, if any
+    // This is an error if the header is present but not allowed, instead
+    // of silently falling back to the configured upstreams
+    fn get_upstream_override(&self, req: &Request) -> Result<Option<Upstream>, String> {
+        let headers = req.headers();
+        let url = match headers.get("X-Upstream-Override").ok().flatten() {
+            Some(url) => url,
+            None => return Ok(None),
+        };
+        let given = headers.get("X-Upstream-Override-Secret").ok().flatten();
+        if !Self::secret_matches(given.as_deref(), self.upstream_override_secret.as_ref()) {
+            return Err("Upstream override not allowed".to_string());
+        }
+        Upstream::from_url(url).map(Some)
+    }
+
+    // Whether the secret given in a request header is the configured one
+    // Without a configured secret, nothing is accepted
+    fn secret_matches(given: Option<&str>, expected: Option<&String>) -> bool {
+        match (given, expected) {
+            (Some(given), Some(expected)) => {
+                crate::util::constant_time_eq(given.as_bytes(), expected.as_bytes())
+            }
+            _ => false,
+        }
+    }
+
+    // Response for when the server could not even be initialized
+    pub fn build_init_error_response(err: &str) -> Response {
+        crate::util::log(err);
+        let headers = Headers::new().unwrap();
+        headers.append("X-PeterCxy-Error-Message", err).unwrap();
+        Response::new_with_opt_str_and_init(
+            Some(err),
+            ResponseInit::new().status(500).headers(&headers),
+        )
+        .unwrap()
+    }
+
+    fn build_error_response(format: &ErrorFormat, err: &str) -> Response {
+        let headers = Headers::new().unwrap();
+        headers.append("X-PeterCxy-Error-Message", err).unwrap();
+        let body = match format {
+            ErrorFormat::PlainText => err.to_string(),
+            ErrorFormat::Json => {
+                headers.append("Content-Type", "application/json").unwrap();
+                serde_json::json!({
+                    "error": err,
+                    "code": 400,
+                })
+                .to_string()
+            }
+        };
+        Response::new_with_opt_str_and_init(
+            Some(&body),
+            ResponseInit::new().status(400).headers(&headers),
+        )
+        .unwrap()
+    }
+
     fn get_response_format(req: &Request) -> DnsResponseFormat {
         let headers = req.headers();
         if !headers.has("Accept").unwrap() {
             return DnsResponseFormat::WireFormat;
         }
 
-        match headers.get("Accept").unwrap().unwrap().borrow() {
-            "application/dns-message" => DnsResponseFormat::WireFormat,
-            "application/dns-json" => DnsResponseFormat::JsonFormat,
-            _ => DnsResponseFormat::WireFormat,
+        // Pick the supported format with the highest preference
+        // On ties, the one listed first wins
+        let accept = crate::util::parse_accept(&headers.get("Accept").unwrap().unwrap());
+        let mut best = (DnsResponseFormat::WireFormat, 0f64);
+        for (media_type, q) in accept {
+            let format = match media_type.as_str() {
+                "application/dns-message" => DnsResponseFormat::WireFormat,
+                "application/dns-json" => DnsResponseFormat::JsonFormat,
+                _ => continue,
+            };
+            if q > best.1 {
+                best = (format, q);
+            }
+        }
+        best.0
+    }
+
+    // Size limit of wireformat responses
+    // Only EDNS clients tell us how much they can take; everything else is
+    // only bounded by the DNS message size limit, as we are not really UDP
+    fn max_response_size(&self, client_udp_size: Option<u16>) -> usize {
+        match client_udp_size {
+            // Sizes below 512 are to be treated as 512 (RFC 6891)
+            Some(size) => size.max(512).min(self.response_udp_size) as usize,
+            None => u16::MAX as usize,
+        }
+    }
+
+    fn parse_refused_types(types: &[String]) -> Result<HashSet<u16>, String> {
+        types
+            .iter()
+            .map(|rtype| crate::util::parse_qtype(rtype).map(|rtype| rtype.to_int()))
+            .collect()
+    }
+
+    // Whether any of the questions is of a type answered locally with
+    // `refused_types_rcode` instead of being resolved
+    fn is_refused(refused_types: &HashSet<u16>, questions: &[Question<Dname<Vec<u8>>>]) -> bool {
+        questions
+            .iter()
+            .any(|q| refused_types.contains(&q.qtype().to_int()))
+    }
+
+    // Drop DNSSEC records the client didn't ask for, from both the answer
+    // and the authority section
+    fn strip_dnssec(result: &mut QueryResult, questions: &[Question<Dname<Vec<u8>>>]) {
+        for records in [&mut result.answers, &mut result.authority].iter_mut() {
+            records.retain(|r| {
+                !crate::util::is_dnssec_rtype(r.rtype())
+                    || questions.iter().any(|q| q.qtype() == r.rtype())
+            });
+        }
+    }
+
+    // Keep at most `max` answers, marking the result as truncated if any
+    // were dropped; clients can retry over TCP (or just live with fewer)
+    fn limit_answers(result: &mut QueryResult, max: usize) {
+        if result.answers.len() > max {
+            result.answers.truncate(max);
+            result.truncated = true;
         }
     }
 
     fn build_answer_wireformat(
         id: u16,
         questions: Vec<Question<Dname<Vec<u8>>>>,
-        records: Vec<impl AsRecord>,
+        result: QueryResult,
+        opt: Option<Opt>,
+        max_size: usize,
+        padding_block_size: Option<usize>,
     ) -> Result<Message<Vec<u8>>, String> {
-        let mut message_builder = MessageBuilder::new_vec();
+        // Enable name compression so that records sharing the same owner names
+        // take less space, which also lets more answers fit into a message
+        let mut message_builder = MessageBuilder::from_target(StaticCompressor::new(Vec::new()))
+            .map_err(|_| "Could not create message builder".to_string())?;
+        // Leave room for the OPT record when limiting the size of the
+        // sections below, so that pushing a record fails once it won't fit
+        let opt_len = opt.as_ref().map(|opt| opt.wire_len()).unwrap_or(0);
+        message_builder.set_push_limit(max_size.saturating_sub(opt_len));
         // Set up the response header
         let header = message_builder.header_mut();
         header.set_id(id);
@@ -214,10 +1237,10 @@ impl Server {
         header.set_qr(true); // Query Response = true
         header.set_aa(false); // Not Authoritative
         header.set_ra(true); // Recursion Available
-        if records.len() == 0 {
-            // Set NXDOMAIN if no record is found
-            header.set_rcode(Rcode::NXDomain);
-        }
+
+        // NXDOMAIN only when the name does not exist; an empty NOERROR
+        // response (NODATA) means the name exists without such records
+        header.set_rcode(result.rcode);
 
         // Set up the questions section
         // (the DNS response should include the original questions)
@@ -229,12 +1252,544 @@ impl Server {
         }
 
         // Set up the answer section
+        // If the response grows too large, stop adding records and set the
+        // TC (truncation) bit instead, returning whatever fits
+        let mut truncated = result.truncated;
+        let mut answer_builder = question_builder.answer();
+        for r in result.answers {
+            if answer_builder.push(r).is_err() {
+                truncated = true;
+                break;
+            }
+        }
+
+        // Set up the authority section
+        // (e.g. the SOA record for negative answers)
+        let mut authority_builder = answer_builder.authority();
+        if !truncated {
+            for r in result.authority {
+                if authority_builder.push(r).is_err() {
+                    truncated = true;
+                    break;
+                }
+            }
+        }
+        if truncated {
+            authority_builder.header_mut().set_tc(true);
+        }
+
+        // Set up the additional section
+        // We only ever include an OPT record if the client used EDNS
+        let mut additional_builder = authority_builder.additional();
+        additional_builder.set_push_limit(max_size);
+        if let Some(mut opt) = opt {
+            if let Some(block_size) = padding_block_size {
+                // Pad the message so that all responses look alike in size
+                opt.pad_to_block(additional_builder.as_slice().len(), block_size, max_size);
+            }
+            additional_builder
+                .push(opt.to_record())
+                .map_err(|_| "Max additional size exceeded".to_string())?;
+        }
+        Message::from_octets(additional_builder.finish().into_target())
+            .map_err(|_| "Could not build response message".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::testing::*;
+    use crate::client::{FetchFn, UpstreamRequest};
+    use crate::util::block_on;
+    use domain::base::serial::Serial;
+    use domain::base::{rdata::UnknownRecordData, Record};
+    use domain::rdata::{AllRecordData, Cname, Soa, A};
+    use std::net::{IpAddr, Ipv4Addr};
+
+    fn question(name: &str, rtype: Rtype) -> Question<Dname<Vec<u8>>> {
+        Question::new(name.parse().unwrap(), rtype, Class::In)
+    }
+
+    fn build(questions: Vec<Question<Dname<Vec<u8>>>>, result: QueryResult) -> Message<Vec<u8>> {
+        Server::build_answer_wireformat(0x1234, questions, result, None, 65535, None).unwrap()
+    }
+
+    // A zone with an A-only name, standing in for any source of answers
+    fn zones() -> LocalZones {
+        LocalZones::new(
+            serde_json::from_str(
+                r#"{"example.com": {
+                    "soa": {"mname": "ns.example.com", "rname": "admin.example.com"},
+                    "records": {"www": {"a": ["192.0.2.1"]}}
+                }}"#,
+            )
+            .unwrap(),
+        )
+        .unwrap()
+    }
+
+    // A server with the given options, on top of a single upstream at
+    // https://dns.example/dns-query whose requests go to `fetch`
+    // Also makes upstream selection and failure tracking deterministic
+    fn server(options: serde_json::Value, fetch: FetchFn) -> Server {
+        crate::util::set_random(|| 0.0);
+        crate::util::set_now(|| 0.0);
+        let mut config = serde_json::json!({
+            "upstream_urls": ["https://dns.example/dns-query"],
+            "retries": 0,
+        });
+        for (k, v) in options.as_object().unwrap() {
+            config[k.as_str()] = v.clone();
+        }
+        let mut server = Server::new(
+            serde_json::from_value(config).unwrap(),
+            HashMap::new(),
+            |_| false,
+        )
+        .unwrap();
+        server.client.set_fetch(fetch);
+        server
+    }
+
+    // A query as clients send it, using EDNS if `opt` is given
+    fn query(questions: Vec<Question<Dname<Vec<u8>>>>, opt: Option<Opt>) -> Message<Vec<u8>> {
+        let mut builder = MessageBuilder::new_vec();
+        builder.header_mut().set_id(0x1234);
+        builder.header_mut().set_rd(true);
+        let mut question_builder = builder.question();
+        for q in questions {
+            question_builder.push(q).unwrap();
+        }
+        let mut additional_builder = question_builder.additional();
+        if let Some(opt) = opt {
+            additional_builder.push(opt.to_record()).unwrap();
+        }
+        additional_builder.into_message()
+    }
+
+    // Answer a wireformat query the way handle_request does
+    fn resolve(
+        server: &Server,
+        query: Message<Vec<u8>>,
+        client_ip: Option<IpAddr>,
+    ) -> Message<Vec<u8>> {
+        let id = query.header().id();
+        let client_udp_size = query.opt().map(|opt| opt.udp_payload_size());
+        let dnssec_ok = query.opt().map(|opt| opt.dnssec_ok()).unwrap_or(false);
+        let questions = Server::extract_questions(query).unwrap();
+        let ctx = QueryContext {
+            client_ip,
+            upstream: None,
+            dnssec_ok,
+        };
+        let (result, query_error) = block_on(server.answer_questions(&questions, &ctx));
+        let opt = server.response_opt(client_udp_size, dnssec_ok, &result, query_error.as_deref());
+        Server::build_answer_wireformat(
+            id,
+            questions,
+            result,
+            opt,
+            server.max_response_size(client_udp_size),
+            server.padding_block_size,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn upstream_answers_reach_the_client() {
+        let server = server(serde_json::json!({}), fetch_a);
+        let msg = resolve(
+            &server,
+            query(vec![question("www.example.com", Rtype::A)], None),
+            None,
+        );
+        assert_eq!(msg.header().id(), 0x1234);
+        assert!(msg.header().qr());
+        assert!(msg.header().ra());
+        assert_eq!(msg.header().rcode(), Rcode::NoError);
+        assert_eq!(msg.header_counts().ancount(), 1);
+        assert_eq!(take_requests(), vec!["https://dns.example/dns-query"]);
+    }
+
+    #[test]
+    fn serve_max_ttl_only_caps_responses() {
+        let server = server(serde_json::json!({"serve_max_ttl": 60}), fetch_a);
+        let q = question("www.example.com", Rtype::A);
+        let msg = resolve(&server, query(vec![q.clone()], None), None);
+        let ttls: Vec<u32> = msg.answer().unwrap().map(|r| r.unwrap().ttl()).collect();
+        assert_eq!(ttls, vec![60]);
+
+        // The client, which caches what upstream returned, still sees the
+        // original TTL; the cap is only applied when shaping the response
+        let result = block_on(server.client.query(vec![q], &QueryContext::default())).unwrap();
+        assert_eq!(result.answers[0].ttl(), 300);
+    }
+
+    // A CNAME to a name with four addresses, in order
+    fn fetch_cname_chain(req: UpstreamRequest<'_>) -> FetchResult<'_> {
+        let query = query_of(&req);
+        let q = &questions_of(&query)[0];
+        let target: Dname<Vec<u8>> = "target.example.com".parse().unwrap();
+        let mut answers = vec![Record::new(
+            q.qname().clone(),
+            Class::In,
+            300,
+            AllRecordData::Cname(Cname::new(target.clone())),
+        )];
+        answers.extend(a_records("target.example.com", 4));
+        let body = response(&query, Rcode::NoError, answers, Vec::new());
+        respond(&req, 200, body)
+    }
+
+    #[test]
+    fn shuffled_answers_keep_cnames_first() {
+        let server = server(
+            serde_json::json!({"shuffle_answers": true}),
+            fetch_cname_chain,
+        );
+        let msg = resolve(
+            &server,
+            query(vec![question("www.example.com", Rtype::A)], None),
+            None,
+        );
+        let rtypes: Vec<Rtype> = msg.answer().unwrap().map(|r| r.unwrap().rtype()).collect();
+        assert_eq!(
+            rtypes,
+            vec![Rtype::Cname, Rtype::A, Rtype::A, Rtype::A, Rtype::A]
+        );
+        let addrs: Vec<String> = msg
+            .answer()
+            .unwrap()
+            .limit_to::<A>()
+            .map(|r| r.unwrap().data().to_string())
+            .collect();
+        // Rotated with the random source fixed at 0 by `server`
+        assert_eq!(
+            addrs,
+            vec!["192.0.2.1", "192.0.2.2", "192.0.2.3", "192.0.2.0"]
+        );
+    }
+
+    // NODATA for everything: the name exists, but has no such records
+    fn fetch_nodata(req: UpstreamRequest<'_>) -> FetchResult<'_> {
+        let query = query_of(&req);
+        let soa = Record::new(
+            "example.com".parse().unwrap(),
+            Class::In,
+            300,
+            AllRecordData::Soa(Soa::new(
+                "ns.example.com".parse().unwrap(),
+                "admin.example.com".parse().unwrap(),
+                Serial(1),
+                7200,
+                3600,
+                86400,
+                300,
+            )),
+        );
+        let body = response(&query, Rcode::NoError, Vec::new(), vec![soa]);
+        respond(&req, 200, body)
+    }
+
+    #[test]
+    fn upstream_nodata_is_passed_on() {
+        let server = server(serde_json::json!({}), fetch_nodata);
+        let msg = resolve(
+            &server,
+            query(vec![question("www.example.com", Rtype::Aaaa)], None),
+            None,
+        );
+        assert_eq!(msg.header().rcode(), Rcode::NoError);
+        assert_eq!(msg.header_counts().ancount(), 0);
+        assert_eq!(msg.header_counts().nscount(), 1);
+        let soa = msg.authority().unwrap().next().unwrap().unwrap();
+        assert_eq!(soa.rtype(), Rtype::Soa);
+        assert_eq!(soa.owner().to_string(), "example.com");
+    }
+
+    // A hundred addresses for every name, too many for 512 octets
+    fn fetch_many(req: UpstreamRequest<'_>) -> FetchResult<'_> {
+        let query = query_of(&req);
+        let name = questions_of(&query)[0].qname().to_string();
+        let body = response(&query, Rcode::NoError, a_records(&name, 100), Vec::new());
+        respond(&req, 200, body)
+    }
+
+    #[test]
+    fn truncated_dnssec_answers_keep_the_do_bit() {
+        let server = server(serde_json::json!({}), fetch_many);
+        let mut opt = Opt::new(512);
+        opt.set_dnssec_ok(true);
+        let msg = resolve(
+            &server,
+            query(vec![question("many.example.com", Rtype::A)], Some(opt)),
+            None,
+        );
+        assert!(msg.header().tc());
+        assert!(msg.as_slice().len() <= 512);
+        let ancount = msg.header_counts().ancount();
+        assert!(ancount > 0 && ancount < 100);
+        let opt = msg.opt().unwrap();
+        assert!(opt.dnssec_ok());
+    }
+
+    #[test]
+    fn explain_reports_refused_types() {
+        let explain = |server: &Server, rtype| {
+            let q = question("example.com", rtype);
+            let explained = block_on(server.explain_questions(&[q], &QueryContext::default()));
+            explained[0]["override"].clone()
+        };
+        let defaults = server(serde_json::json!({}), fetch_a);
+        assert_eq!(explain(&defaults, Rtype::Axfr), "refused");
+        assert_eq!(explain(&defaults, Rtype::Ixfr), "refused");
+        assert_eq!(explain(&defaults, Rtype::Txt), serde_json::Value::Null);
+
+        let configured = server(serde_json::json!({"refused_types": ["TXT"]}), fetch_a);
+        assert_eq!(explain(&configured, Rtype::Txt), "refused");
+        assert_eq!(explain(&configured, Rtype::A), serde_json::Value::Null);
+        assert!(take_requests().is_empty());
+    }
+
+    #[test]
+    fn blackhole_mode_never_goes_upstream() {
+        let server = server(serde_json::json!({"mode": "blackhole"}), fetch_a);
+        let msg = resolve(
+            &server,
+            query(vec![question("www.example.com", Rtype::A)], None),
+            None,
+        );
+        assert_eq!(msg.header().rcode(), Rcode::ServFail);
+        assert_eq!(msg.header_counts().ancount(), 0);
+        assert!(take_requests().is_empty());
+    }
+
+    fn fetch_unavailable(req: UpstreamRequest<'_>) -> FetchResult<'_> {
+        respond(&req, 503, Vec::new())
+    }
+
+    #[test]
+    fn upstream_failures_are_servfail() {
+        let server = server(serde_json::json!({}), fetch_unavailable);
+        let msg = resolve(
+            &server,
+            query(vec![question("www.example.com", Rtype::A)], None),
+            None,
+        );
+        assert_eq!(msg.header().rcode(), Rcode::ServFail);
+        assert_eq!(msg.header_counts().ancount(), 0);
+    }
+
+    #[test]
+    fn nodata_is_noerror_with_soa() {
+        let q = question("www.example.com", Rtype::Aaaa);
+        let result = zones().try_resolve(&q).unwrap();
+        let msg = build(vec![q], result);
+        assert_eq!(msg.header().rcode(), Rcode::NoError);
+        assert_eq!(msg.header_counts().ancount(), 0);
+        assert_eq!(msg.header_counts().nscount(), 1);
+    }
+
+    #[test]
+    fn nxdomain_is_not_nodata() {
+        let q = question("nope.example.com", Rtype::Aaaa);
+        let result = zones().try_resolve(&q).unwrap();
+        let msg = build(vec![q], result);
+        assert_eq!(msg.header().rcode(), Rcode::NXDomain);
+        assert_eq!(msg.header_counts().ancount(), 0);
+        assert_eq!(msg.header_counts().nscount(), 1);
+    }
+
+    fn a_records(
+        name: &str,
+        count: u8,
+    ) -> Vec<Record<Dname<Vec<u8>>, AllRecordData<Vec<u8>, Dname<Vec<u8>>>>> {
+        (0..count)
+            .map(|i| {
+                Record::new(
+                    name.parse().unwrap(),
+                    Class::In,
+                    300,
+                    AllRecordData::A(A::new(Ipv4Addr::new(192, 0, 2, i))),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn oversized_answers_are_truncated() {
+        let q = question("many.example.com", Rtype::A);
+        let result = QueryResult::new(
+            Rcode::NoError,
+            a_records("many.example.com", 100),
+            Vec::new(),
+        );
+        let msg =
+            Server::build_answer_wireformat(0x1234, vec![q], result, None, 512, None).unwrap();
+        assert!(msg.header().tc());
+        assert!(msg.as_slice().len() <= 512);
+        let ancount = msg.header_counts().ancount();
+        assert!(ancount > 0 && ancount < 100);
+        assert_eq!(msg.answer().unwrap().count(), ancount as usize);
+    }
+
+    #[test]
+    fn answers_that_fit_are_not_truncated() {
+        let q = question("few.example.com", Rtype::A);
+        let result = QueryResult::new(Rcode::NoError, a_records("few.example.com", 4), Vec::new());
+        let msg =
+            Server::build_answer_wireformat(0x1234, vec![q], result, None, 512, None).unwrap();
+        assert!(!msg.header().tc());
+        assert_eq!(msg.header_counts().ancount(), 4);
+    }
+
+    #[test]
+    fn owner_names_are_compressed() {
+        let q = question("compressed.example.com", Rtype::A);
+        let records = a_records("compressed.example.com", 10);
+        let result = QueryResult::new(Rcode::NoError, records.clone(), Vec::new());
+        let msg = build(vec![q.clone()], result);
+
+        // The same message without compression
+        let mut builder = MessageBuilder::new_vec();
+        let mut question_builder = builder.question();
+        question_builder.push(q).unwrap();
         let mut answer_builder = question_builder.answer();
         for r in records {
-            answer_builder
-                .push(r)
-                .map_err(|_| "Max answer size exceeded".to_string())?;
+            answer_builder.push(r).unwrap();
         }
-        Ok(answer_builder.into_message())
+        let uncompressed = answer_builder.into_message();
+
+        assert!(msg.as_slice().len() < uncompressed.as_slice().len());
+        // Every owner name after the question is a 2-byte pointer
+        let name_len = "compressed.example.com".len() + 2;
+        assert_eq!(
+            uncompressed.as_slice().len() - msg.as_slice().len(),
+            10 * (name_len - 2)
+        );
+        let addrs: Vec<String> = msg
+            .answer()
+            .unwrap()
+            .limit_to::<A>()
+            .map(|r| r.unwrap().data().to_string())
+            .collect();
+        assert_eq!(addrs.len(), 10);
+        assert_eq!(addrs[9], "192.0.2.9");
+    }
+
+    #[test]
+    fn max_answer_records_caps_answers_and_sets_tc() {
+        let q = question("many.example.com", Rtype::A);
+        let mut result = QueryResult::new(
+            Rcode::NoError,
+            a_records("many.example.com", 10),
+            Vec::new(),
+        );
+        Server::limit_answers(&mut result, 3);
+        let msg = build(vec![q], result);
+        assert!(msg.header().tc());
+        assert_eq!(msg.header_counts().ancount(), 3);
+    }
+
+    #[test]
+    fn max_answer_records_keeps_smaller_answers() {
+        let q = question("few.example.com", Rtype::A);
+        let mut result =
+            QueryResult::new(Rcode::NoError, a_records("few.example.com", 3), Vec::new());
+        Server::limit_answers(&mut result, 3);
+        let msg = build(vec![q], result);
+        assert!(!msg.header().tc());
+        assert_eq!(msg.header_counts().ancount(), 3);
+    }
+
+    // An RRSIG as it would come with a signed answer; only the type matters
+    fn rrsig(name: &str) -> Record<Dname<Vec<u8>>, AllRecordData<Vec<u8>, Dname<Vec<u8>>>> {
+        Record::new(
+            name.parse().unwrap(),
+            Class::In,
+            300,
+            AllRecordData::Other(UnknownRecordData::from_octets(Rtype::Rrsig, vec![0; 18])),
+        )
+    }
+
+    #[test]
+    fn strip_dnssec_drops_signatures() {
+        let q = question("signed.example.com", Rtype::A);
+        let mut answers = a_records("signed.example.com", 2);
+        answers.push(rrsig("signed.example.com"));
+        // The SOA of a negative answer, plus its signature
+        let mut authority = zones()
+            .try_resolve(&question("nope.example.com", Rtype::A))
+            .unwrap()
+            .authority;
+        authority.push(rrsig("example.com"));
+        let mut result = QueryResult::new(Rcode::NoError, answers, authority);
+        Server::strip_dnssec(&mut result, &[q.clone()]);
+        let msg = build(vec![q], result);
+        assert_eq!(msg.header_counts().ancount(), 2);
+        assert_eq!(msg.header_counts().nscount(), 1);
+        for r in msg.answer().unwrap().chain(msg.authority().unwrap()) {
+            assert_ne!(r.unwrap().rtype(), Rtype::Rrsig);
+        }
+    }
+
+    #[test]
+    fn strip_dnssec_keeps_asked_types() {
+        let q = question("signed.example.com", Rtype::Rrsig);
+        let mut result = QueryResult::new(
+            Rcode::NoError,
+            vec![rrsig("signed.example.com")],
+            Vec::new(),
+        );
+        Server::strip_dnssec(&mut result, &[q.clone()]);
+        let msg = build(vec![q], result);
+        assert_eq!(msg.header_counts().ancount(), 1);
+    }
+
+    #[test]
+    fn zone_transfers_are_refused_by_default() {
+        let refused_types = Server::parse_refused_types(&default_refused_types()).unwrap();
+        for rtype in [Rtype::Axfr, Rtype::Ixfr].iter() {
+            let q = question("example.com", *rtype);
+            assert!(Server::is_refused(&refused_types, &[q.clone()]));
+            let result =
+                QueryResult::new(RefusedTypesRcode::default().rcode(), Vec::new(), Vec::new());
+            let msg = build(vec![q], result);
+            assert_eq!(msg.header().rcode(), Rcode::Refused);
+            assert_eq!(msg.header_counts().ancount(), 0);
+        }
+        assert!(!Server::is_refused(
+            &refused_types,
+            &[question("example.com", Rtype::A)]
+        ));
+    }
+
+    #[test]
+    fn configured_types_are_refused() {
+        let refused_types =
+            Server::parse_refused_types(&["any".to_string(), "TYPE13".to_string()]).unwrap();
+        assert!(Server::is_refused(
+            &refused_types,
+            &[question("example.com", Rtype::Any)]
+        ));
+        assert!(Server::is_refused(
+            &refused_types,
+            &[question("example.com", Rtype::Hinfo)]
+        ));
+        // Zone transfers are only refused by default, not in addition
+        assert!(!Server::is_refused(
+            &refused_types,
+            &[question("example.com", Rtype::Axfr)]
+        ));
+        // One refused question is enough
+        assert!(Server::is_refused(
+            &refused_types,
+            &[
+                question("example.com", Rtype::A),
+                question("example.com", Rtype::Any)
+            ]
+        ));
+        assert!(Server::parse_refused_types(&["BOGUS".to_string()]).is_err());
     }
 }
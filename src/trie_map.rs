@@ -36,14 +36,25 @@ impl<T> TrieMapNode<T> {
     // Same as traverse_trie_mut, except all references are not mutable,
     // and an additional value -- the last non-null `value` while traversing
     // the tree -- is returned. This value is the value mapped to the
-    // longest prefix in our record that matches the given key.
+    // longest prefix in our record that matches the given key, along with
+    // the length of the key remaining after that prefix.
+    // If `boundary` is set, values are only considered at boundaries, i.e.
+    // if the prefix ends with the boundary byte, or the remaining key is
+    // empty or starts with it.
     fn traverse_trie_for_value<'a, 'b>(
         &'a self,
         key: &'b [u8],
-        mut last_value: Option<&'a T>,
-    ) -> (&'a TrieMapNode<T>, Option<&'a T>, &'b [u8]) {
+        mut last_value: Option<(&'a T, usize)>,
+        boundary: Option<u8>,
+    ) -> (&'a TrieMapNode<T>, Option<(&'a T, usize)>, &'b [u8]) {
         if self.value.is_some() {
-            last_value = self.value.as_ref();
+            let at_boundary = match boundary {
+                None => true,
+                Some(b) => self.label == b || key.len() == 0 || key[0] == b,
+            };
+            if at_boundary {
+                last_value = self.value.as_ref().map(|v| (v, key.len()));
+            }
         }
 
         if key.len() == 0 {
@@ -51,7 +62,7 @@ impl<T> TrieMapNode<T> {
         }
 
         if let Some(idx) = self.find_child(key[0]) {
-            self.children[idx].traverse_trie_for_value(&key[1..], last_value)
+            self.children[idx].traverse_trie_for_value(&key[1..], last_value, boundary)
         } else {
             (self, last_value, key)
         }
@@ -65,6 +76,12 @@ impl<T> TrieMapNode<T> {
 // one key, then the mapped value is the value of the longest prefix
 pub struct TrieMap<T> {
     root: TrieMapNode<T>,
+    // Only match prefixes ending at this separator (see with_boundary)
+    boundary: Option<u8>,
+    // Number of value-bearing nodes, maintained on insertion
+    // Maps are built once at startup and never shrink, so there is no
+    // removal to account for
+    len: usize,
 }
 
 impl<T> TrieMap<T> {
@@ -75,10 +92,22 @@ impl<T> TrieMap<T> {
                 value: None,
                 children: Vec::new(),
             },
+            len: 0,
+            boundary: None,
         }
     }
 
-    pub fn put_prefix(&mut self, prefix: impl AsRef<[u8]>, value: impl Into<T>) {
+    // Create a map where a prefix only matches a key at a boundary, e.g.
+    // with `b'.'` and reversed domain names, `moc.elpmaxe` matches
+    // `moc.elpmaxe.www` but not `moc.elpmaxeton`
+    pub fn with_boundary(boundary: u8) -> TrieMap<T> {
+        let mut ret = Self::new();
+        ret.boundary = Some(boundary);
+        ret
+    }
+
+    // Returns the value previously mapped to exactly the same prefix, if any
+    pub fn put_prefix(&mut self, prefix: impl AsRef<[u8]>, value: impl Into<T>) -> Option<T> {
         let (mut node, remaining_prefix) = self.root.traverse_trie_mut(prefix.as_ref());
         for b in remaining_prefix {
             let new_node = TrieMapNode {
@@ -89,11 +118,108 @@ impl<T> TrieMap<T> {
             node.children.push(new_node);
             node = node.children.last_mut().unwrap();
         }
-        node.value = Some(value.into());
+        let old_value = node.value.replace(value.into());
+        if old_value.is_none() {
+            // Overwriting an existing value doesn't change the count
+            self.len += 1;
+        }
+        old_value
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
     }
 
     pub fn get_by_prefix(&self, key: impl AsRef<[u8]>) -> Option<&T> {
-        let (_, value, _) = self.root.traverse_trie_for_value(key.as_ref(), None);
-        value
+        self.get_prefix_with_len(key).map(|(value, _)| value)
+    }
+
+    // Same as get_by_prefix, but also returns the length of the matched
+    // prefix, i.e. `&key[..len]` is the key the value was put with
+    pub fn get_prefix_with_len(&self, key: impl AsRef<[u8]>) -> Option<(&T, usize)> {
+        let key = key.as_ref();
+        let (_, value, _) = self.root.traverse_trie_for_value(key, None, self.boundary);
+        value.map(|(value, remaining)| (value, key.len() - remaining))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Domain names are put and looked up reversed, as everywhere else
+    fn rev(name: &str) -> String {
+        name.chars().rev().collect()
+    }
+
+    fn domains() -> TrieMap<&'static str> {
+        let mut map = TrieMap::with_boundary(b'.');
+        map.put_prefix(rev("example.com"), "example");
+        map
+    }
+
+    #[test]
+    fn boundary_matches_exact_name() {
+        assert_eq!(
+            domains().get_by_prefix(rev("example.com")),
+            Some(&"example")
+        );
+    }
+
+    #[test]
+    fn boundary_matches_subdomain() {
+        assert_eq!(
+            domains().get_by_prefix(rev("a.example.com")),
+            Some(&"example")
+        );
+    }
+
+    #[test]
+    fn boundary_rejects_partial_label() {
+        assert_eq!(domains().get_by_prefix(rev("ample.com")), None);
+        assert_eq!(domains().get_by_prefix(rev("notexample.com")), None);
+    }
+
+    #[test]
+    fn put_prefix_returns_old_value() {
+        let mut map = TrieMap::new();
+        assert_eq!(map.put_prefix("abc", 1), None);
+        assert_eq!(map.put_prefix("abc", 2), Some(1));
+        // A longer or shorter prefix is a different entry
+        assert_eq!(map.put_prefix("ab", 3), None);
+        assert_eq!(map.put_prefix("abcd", 4), None);
+        assert_eq!(map.get_by_prefix("abc"), Some(&2));
+    }
+
+    #[test]
+    fn len_counts_distinct_prefixes() {
+        let mut map: TrieMap<i32> = TrieMap::new();
+        assert!(map.is_empty());
+        assert_eq!(map.len(), 0);
+        map.put_prefix("abc", 1);
+        map.put_prefix("ab", 2);
+        assert!(!map.is_empty());
+        assert_eq!(map.len(), 2);
+        // Overwriting doesn't add an entry
+        map.put_prefix("abc", 3);
+        assert_eq!(map.len(), 2);
+    }
+
+    #[test]
+    fn get_prefix_with_len_picks_longest_match() {
+        let mut map = TrieMap::with_boundary(b'.');
+        map.put_prefix(rev("com"), "com");
+        map.put_prefix(rev("example.com"), "example");
+        let key = rev("www.example.com");
+        assert_eq!(map.get_prefix_with_len(&key), Some((&"example", 11)));
+        assert_eq!(
+            map.get_prefix_with_len(rev("www.other.com")),
+            Some((&"com", 3))
+        );
+        assert_eq!(map.get_prefix_with_len(rev("example.org")), None);
     }
 }
@@ -1,9 +1,19 @@
 use domain::base::{
-    octets::Parser, rdata::ParseRecordData, Compose, Dname, Message, ParsedDname, Rtype, ToDname,
+    octets::Parser,
+    rdata::{ParseRecordData, RecordData, UnknownRecordData},
+    Compose, Dname, Message, ParsedDname, Rtype, ToDname,
 };
 use domain::rdata::{AllRecordData, Cname, Mx, Ptr, Soa, Srv, Txt};
-use js_sys::{Math, Promise};
-use std::ops::Add;
+use js_sys::{Date, Function, Math, Promise};
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::convert::TryFrom;
+use std::future::Future;
+use std::io::Read;
+use std::net::IpAddr;
+use std::ops::{Add, Sub};
+use std::pin::Pin;
+use std::task::{Context, Poll};
 use std::{collections::hash_map::DefaultHasher, hash::Hasher};
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::JsValue;
@@ -17,6 +27,14 @@ extern "C" {
     // and unfortunately the bionding in web_sys depends
     // on Window being present.
     fn fetch(req: &Request) -> Promise;
+
+    // Logs go to the Workers console (visible in `wrangler tail`)
+    #[wasm_bindgen(js_namespace = console, js_name = log)]
+    fn console_log(s: &str);
+
+    // Same as above; setTimeout is available in global scope
+    #[wasm_bindgen(js_name = setTimeout)]
+    fn set_timeout(handler: &Function, timeout: i32) -> JsValue;
 }
 
 pub fn parse_dns_wireformat(msg: &[u8]) -> Result<Message<Vec<u8>>, String> {
@@ -32,28 +50,339 @@ pub async fn fetch_rs(req: &Request) -> Result<JsValue, JsValue> {
     JsFuture::from(unsafe { fetch(req) }).await
 }
 
+#[cfg(not(test))]
 #[allow(unused_unsafe)]
-pub fn random() -> f64 {
+pub fn log(s: &str) {
+    unsafe { console_log(s) }
+}
+
+// There is no console outside of the Workers runtime
+#[cfg(test)]
+pub fn log(s: &str) {
+    eprintln!("{}", s);
+}
+
+// Wait for `ms` milliseconds without blocking
+// Note that this relies on the timers of the Workers event loop, so the
+// returned future must be awaited within the lifetime of a request (or
+// `waitUntil`) -- otherwise the runtime may never fire the timer
+#[allow(unused_unsafe)]
+pub async fn sleep(ms: u32) {
+    let promise = Promise::new(&mut |resolve, _| {
+        // setTimeout takes a signed 32-bit delay
+        let ms = i32::try_from(ms).unwrap_or(i32::MAX);
+        unsafe { set_timeout(&resolve, ms) };
+    });
+    // The promise never rejects
+    let _ = JsFuture::from(promise).await;
+}
+
+// Run the future produced by `f` for each item, with at most `limit` of
+// them in flight at once. Everything runs on one thread, so this is just
+// a poll loop over a bounded set of boxed futures, no locking needed
+pub async fn for_each_concurrent<I, F, Fut>(items: I, limit: usize, f: F)
+where
+    I: IntoIterator,
+    I::IntoIter: Unpin,
+    F: FnMut(I::Item) -> Fut + Unpin,
+    Fut: Future<Output = ()>,
+{
+    ForEachConcurrent {
+        items: items.into_iter(),
+        f,
+        limit: limit.max(1),
+        in_flight: Vec::new(),
+    }
+    .await
+}
+
+struct ForEachConcurrent<I, F, Fut> {
+    items: I,
+    f: F,
+    limit: usize,
+    in_flight: Vec<Pin<Box<Fut>>>,
+}
+
+impl<I, F, Fut> Future for ForEachConcurrent<I, F, Fut>
+where
+    I: Iterator + Unpin,
+    F: FnMut(I::Item) -> Fut + Unpin,
+    Fut: Future<Output = ()>,
+{
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = self.get_mut();
+        loop {
+            // Fill up the free slots
+            while this.in_flight.len() < this.limit {
+                match this.items.next() {
+                    Some(item) => this.in_flight.push(Box::pin((this.f)(item))),
+                    None => break,
+                }
+            }
+            if this.in_flight.len() == 0 {
+                return Poll::Ready(());
+            }
+
+            let in_flight_before = this.in_flight.len();
+            let mut i = 0;
+            while i < this.in_flight.len() {
+                if this.in_flight[i].as_mut().poll(cx).is_ready() {
+                    this.in_flight.swap_remove(i);
+                } else {
+                    i += 1;
+                }
+            }
+            if this.in_flight.len() == in_flight_before {
+                // Nothing finished; we'll be woken up by one of them
+                return Poll::Pending;
+            }
+        }
+    }
+}
+
+// Source of random numbers in [0, 1), `math_random` by default
+// Tests replace it (with `set_random`) to make everything depending on
+// randomness deterministic, e.g. upstream selection and answer rotation
+pub type RandomFn = fn() -> f64;
+
+thread_local! {
+    static RANDOM: Cell<RandomFn> = Cell::new(math_random);
+}
+
+#[allow(unused_unsafe)]
+fn math_random() -> f64 {
     unsafe { Math::random() }
 }
 
+// Use another source of random numbers on this thread, for deterministic tests
+#[cfg(test)]
+pub fn set_random(random: RandomFn) {
+    RANDOM.with(|r| r.set(random));
+}
+
+pub fn random() -> f64 {
+    RANDOM.with(|r| r.get())()
+}
+
+// Source of the current time in ms since the epoch, `date_now` by default
+// Replaced by tests just like the source of random numbers above
+pub type NowFn = fn() -> f64;
+
+thread_local! {
+    static NOW: Cell<NowFn> = Cell::new(date_now);
+}
+
+fn date_now() -> f64 {
+    Date::now()
+}
+
+#[cfg(test)]
+pub fn set_now(now: NowFn) {
+    NOW.with(|n| n.set(now));
+}
+
+pub fn now() -> f64 {
+    NOW.with(|n| n.get())()
+}
+
+// Run a future to completion on the current thread, for tests of async code
+// Nothing here waits on real I/O, so polling until ready is good enough
+#[cfg(test)]
+pub fn block_on<F: Future>(fut: F) -> F::Output {
+    use std::task::{RawWaker, RawWakerVTable, Waker};
+
+    fn noop_raw_waker() -> RawWaker {
+        fn clone(_: *const ()) -> RawWaker {
+            noop_raw_waker()
+        }
+        fn noop(_: *const ()) {}
+        static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+        RawWaker::new(std::ptr::null(), &VTABLE)
+    }
+
+    let waker = unsafe { Waker::from_raw(noop_raw_waker()) };
+    let mut cx = Context::from_waker(&waker);
+    let mut fut = Box::pin(fut);
+    loop {
+        if let Poll::Ready(ret) = fut.as_mut().poll(&mut cx) {
+            return ret;
+        }
+    }
+}
+
+// Random value in [min, max)
 pub fn random_range<T>(min: T, max: T) -> T
 where
-    T: Ord + Into<f64> + FromFloat<f64> + Add<Output = T>,
+    T: Ord + Copy + ToFloat<f64> + FromFloat<f64> + Add<Output = T> + Sub<Output = T>,
 {
-    min + T::from_float(random() * max.into())
+    min + T::from_float(random() * (max - min).to_float())
 }
 
 pub trait FromFloat<F> {
     fn from_float(f: F) -> Self;
 }
 
+// Not all integer types implement Into<f64> (u64 and usize don't)
+pub trait ToFloat<F> {
+    fn to_float(self) -> F;
+}
+
+macro_rules! impl_to_float {
+    ($($t:ty),*) => {
+        $(
+            impl ToFloat<f64> for $t {
+                fn to_float(self) -> f64 {
+                    self as f64
+                }
+            }
+        )*
+    };
+}
+
+impl_to_float!(u16, u32, u64, usize);
+
 impl FromFloat<f64> for u16 {
     fn from_float(f: f64) -> u16 {
         f as u16
     }
 }
 
+impl FromFloat<f64> for u32 {
+    fn from_float(f: f64) -> u32 {
+        f as u32
+    }
+}
+
+// Note that f64 only has 53 bits of mantissa, so for ranges wider than
+// 2^53, random_range cannot produce every value in between; the results
+// are still within bounds, only more coarsely distributed
+impl FromFloat<f64> for u64 {
+    fn from_float(f: f64) -> u64 {
+        f as u64
+    }
+}
+
+impl FromFloat<f64> for usize {
+    fn from_float(f: f64) -> usize {
+        f as usize
+    }
+}
+
+// Percent-encode a string for use in a URL query, like encodeURIComponent
+// in JS: everything but ASCII alphanumerics and `-_.!~*'()` is encoded
+pub fn encode_uri_component(s: &str) -> String {
+    let mut ret = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z'
+            | b'a'..=b'z'
+            | b'0'..=b'9'
+            | b'-'
+            | b'_'
+            | b'.'
+            | b'!'
+            | b'~'
+            | b'*'
+            | b'\''
+            | b'('
+            | b')' => ret.push(b as char),
+            _ => ret.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    ret
+}
+
+// Extract the bare media type from a Content-Type header, i.e. without
+// parameters or surrounding whitespace, and lowercased
+pub fn parse_media_type(header: &str) -> String {
+    header
+        .split(';')
+        .next()
+        .unwrap()
+        .trim()
+        .to_ascii_lowercase()
+}
+
+// Parse an HTTP Accept header into (media type, q-value) pairs
+// Media types are lowercased with parameters other than q dropped;
+// a missing or malformed q-value counts as 1
+pub fn parse_accept(header: &str) -> Vec<(String, f64)> {
+    let mut ret = Vec::new();
+    for item in header.split(',') {
+        let mut parts = item.split(';');
+        let media_type = parse_media_type(parts.next().unwrap());
+        if media_type.is_empty() {
+            continue;
+        }
+
+        let mut q = 1f64;
+        for param in parts {
+            let mut kv = param.splitn(2, '=');
+            if kv.next().unwrap().trim().eq_ignore_ascii_case("q") {
+                q = kv
+                    .next()
+                    .and_then(|v| v.trim().parse::<f64>().ok())
+                    .unwrap_or(1f64);
+            }
+        }
+        ret.push((media_type, q));
+    }
+    ret
+}
+
+// Parse a record type given by a client, either numeric (`28`) or
+// mnemonic (`AAAA`, case-insensitive). An empty string defaults to A.
+pub fn parse_qtype(qtype: &str) -> Result<Rtype, String> {
+    let qtype = qtype.trim();
+    if let Ok(n) = qtype.parse::<u16>() {
+        return Ok(Rtype::from_int(n));
+    }
+
+    match qtype.to_ascii_uppercase().as_str() {
+        "" | "A" => Ok(Rtype::A),
+        "AAAA" => Ok(Rtype::Aaaa),
+        "CNAME" => Ok(Rtype::Cname),
+        "MX" => Ok(Rtype::Mx),
+        "TXT" => Ok(Rtype::Txt),
+        "NS" => Ok(Rtype::Ns),
+        "SOA" => Ok(Rtype::Soa),
+        "SRV" => Ok(Rtype::Srv),
+        "PTR" => Ok(Rtype::Ptr),
+        "CAA" => Ok(Rtype::from_int(257)),
+        "HTTPS" => Ok(Rtype::from_int(65)),
+        "SVCB" => Ok(Rtype::from_int(64)),
+        "ANY" => Ok(Rtype::Any),
+        "AXFR" => Ok(Rtype::Axfr),
+        "IXFR" => Ok(Rtype::Ixfr),
+        // The generic form of rtype_to_string
+        upper => upper
+            .strip_prefix("TYPE")
+            .and_then(|n| n.parse::<u16>().ok())
+            .map(Rtype::from_int)
+            .ok_or(format!("Unknown record type {}", qtype)),
+    }
+}
+
+// Canonical textual form of a record type, used wherever types are
+// rendered (e.g. cache keys) so that they are consistent everywhere:
+// the mnemonic if known, or `TYPE<n>` otherwise (RFC 3597)
+pub fn rtype_to_string(rtype: Rtype) -> String {
+    match Rtype::from_int(rtype.to_int()) {
+        Rtype::Int(n) => format!("TYPE{}", n),
+        rtype => rtype.to_string(),
+    }
+}
+
+// Record types only meaningful to validating resolvers (RFC 4035)
+pub fn is_dnssec_rtype(rtype: Rtype) -> bool {
+    matches!(
+        rtype,
+        Rtype::Rrsig | Rtype::Nsec | Rtype::Nsec3 | Rtype::Dnskey | Rtype::Ds
+    )
+}
+
 // Calculate a hash value from a u8 slice
 // used for generating answer cache keys
 pub fn hash_buf(buf: &[u8]) -> u64 {
@@ -62,6 +391,266 @@ pub fn hash_buf(buf: &[u8]) -> u64 {
     hasher.finish()
 }
 
+// Canonical textual form of a domain name, used for cache keys and
+// override lookups so that `example.com`, `Example.COM` and `example.com.`
+// all map to the same entry: lowercase, no trailing dot, "." for root
+pub fn normalize_name(name: &str) -> String {
+    let name = name.trim_end_matches('.');
+    if name.is_empty() {
+        ".".to_string()
+    } else {
+        name.to_ascii_lowercase()
+    }
+}
+
+// Compare two secrets in time independent of where they differ, so that
+// the response time doesn't tell how much of a guess was right
+// Only the length may leak, which is fine for secrets
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter()
+        .zip(b.iter())
+        .fold(0u8, |acc, (x, y)| acc | (x ^ y))
+        == 0
+}
+
+// Check a domain name in ASCII presentation form against the limits of the
+// wire format (RFC 1035): at most 63 octets per label, 255 octets in total,
+// and no empty labels other than the root
+// Names parsed from wireformat messages are already checked by the parser
+pub fn validate_name(name: &str) -> Result<(), String> {
+    let name = name.strip_suffix('.').unwrap_or(name);
+    if name.is_empty() {
+        // The root
+        return Ok(());
+    }
+    // Each label takes one length octet plus its content, and the root
+    // label at the end takes one more octet
+    let mut wire_len = 1;
+    for label in name.split('.') {
+        if label.is_empty() {
+            return Err("Empty label in domain name".to_string());
+        }
+        if label.len() > 63 {
+            return Err("Label too long in domain name".to_string());
+        }
+        wire_len += label.len() + 1;
+    }
+    if wire_len > 255 {
+        return Err("Domain name too long".to_string());
+    }
+    Ok(())
+}
+
+// Convert a domain name that may contain Unicode labels (U-labels) into its
+// ASCII form, encoding every non-ASCII label with punycode (A-labels)
+// Note that this does not perform the full IDNA mapping, only lowercasing
+pub fn idn_to_ascii(name: &str) -> Result<String, String> {
+    let labels: Result<Vec<String>, String> = name
+        .split('.')
+        .map(|label| {
+            if label.is_ascii() {
+                Ok(label.to_string())
+            } else {
+                Ok(format!("xn--{}", punycode_encode(&label.to_lowercase())?))
+            }
+        })
+        .collect();
+    Ok(labels?.join("."))
+}
+
+// Punycode parameters (RFC 3492, Section 5)
+const PUNYCODE_BASE: u32 = 36;
+const PUNYCODE_TMIN: u32 = 1;
+const PUNYCODE_TMAX: u32 = 26;
+const PUNYCODE_SKEW: u32 = 38;
+const PUNYCODE_DAMP: u32 = 700;
+const PUNYCODE_INITIAL_BIAS: u32 = 72;
+const PUNYCODE_INITIAL_N: u32 = 128;
+
+// Bias adaptation function (RFC 3492, Section 6.1)
+fn punycode_adapt(delta: u32, num_points: u32, first_time: bool) -> u32 {
+    let mut delta = if first_time {
+        delta / PUNYCODE_DAMP
+    } else {
+        delta / 2
+    };
+    delta += delta / num_points;
+    let mut k = 0;
+    while delta > ((PUNYCODE_BASE - PUNYCODE_TMIN) * PUNYCODE_TMAX) / 2 {
+        delta /= PUNYCODE_BASE - PUNYCODE_TMIN;
+        k += PUNYCODE_BASE;
+    }
+    k + (PUNYCODE_BASE - PUNYCODE_TMIN + 1) * delta / (delta + PUNYCODE_SKEW)
+}
+
+fn punycode_digit(d: u32) -> char {
+    if d < 26 {
+        (b'a' + d as u8) as char
+    } else {
+        (b'0' + (d - 26) as u8) as char
+    }
+}
+
+// Punycode encoding procedure (RFC 3492, Section 6.3)
+fn punycode_encode(input: &str) -> Result<String, String> {
+    let overflow = || "Punycode overflow".to_string();
+    let code_points: Vec<u32> = input.chars().map(|c| c as u32).collect();
+    let mut output: String = input.chars().filter(|c| c.is_ascii()).collect();
+    let basic_len = output.len() as u32;
+    let mut handled = basic_len;
+    if basic_len > 0 {
+        output.push('-');
+    }
+
+    let mut n = PUNYCODE_INITIAL_N;
+    let mut delta: u32 = 0;
+    let mut bias = PUNYCODE_INITIAL_BIAS;
+    while (handled as usize) < code_points.len() {
+        let m = *code_points.iter().filter(|c| **c >= n).min().unwrap();
+        delta = (m - n)
+            .checked_mul(handled + 1)
+            .and_then(|d| d.checked_add(delta))
+            .ok_or_else(overflow)?;
+        n = m;
+        for c in code_points.iter() {
+            if *c < n {
+                delta = delta.checked_add(1).ok_or_else(overflow)?;
+            }
+            if *c == n {
+                let mut q = delta;
+                let mut k = PUNYCODE_BASE;
+                loop {
+                    let t = if k <= bias {
+                        PUNYCODE_TMIN
+                    } else if k >= bias + PUNYCODE_TMAX {
+                        PUNYCODE_TMAX
+                    } else {
+                        k - bias
+                    };
+                    if q < t {
+                        break;
+                    }
+                    output.push(punycode_digit(t + (q - t) % (PUNYCODE_BASE - t)));
+                    q = (q - t) / (PUNYCODE_BASE - t);
+                    k += PUNYCODE_BASE;
+                }
+                output.push(punycode_digit(q));
+                bias = punycode_adapt(delta, handled + 1, handled == basic_len);
+                delta = 0;
+                handled += 1;
+            }
+        }
+        delta += 1;
+        n += 1;
+    }
+    Ok(output)
+}
+
+// Inflate gzip-compressed data, detected by the gzip magic bytes
+// Anything else is returned as-is
+pub fn maybe_gunzip(buf: Vec<u8>) -> Result<Vec<u8>, String> {
+    if !buf.starts_with(&[0x1f, 0x8b]) {
+        return Ok(buf);
+    }
+    let mut ret = Vec::new();
+    flate2::read::GzDecoder::new(buf.as_slice())
+        .read_to_end(&mut ret)
+        .map_err(|e| format!("Failed to decompress gzip data: {}", e))?;
+    Ok(ret)
+}
+
+// The name for reverse lookups of an address, without the trailing dot
+// e.g. `4.3.2.1.in-addr.arpa`, or one label per nibble in `ip6.arpa`
+pub fn reverse_name(addr: &IpAddr) -> String {
+    match addr {
+        IpAddr::V4(addr) => {
+            let o = addr.octets();
+            format!("{}.{}.{}.{}.in-addr.arpa", o[3], o[2], o[1], o[0])
+        }
+        IpAddr::V6(addr) => {
+            let mut ret = String::new();
+            for b in addr.octets().iter().rev() {
+                ret.push_str(&format!("{:x}.{:x}.", b & 0x0f, b >> 4));
+            }
+            ret + "ip6.arpa"
+        }
+    }
+}
+
+// An IP network in CIDR notation, e.g. `10.1.0.0/16` or `2001:db8::/32`
+// A bare address is a network of just that address
+#[derive(Clone, Copy)]
+pub struct Subnet {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Subnet {
+    pub fn parse(subnet: &str) -> Result<Subnet, String> {
+        let mut parts = subnet.trim().splitn(2, '/');
+        let addr: IpAddr = parts
+            .next()
+            .unwrap()
+            .parse()
+            .map_err(|_| format!("Invalid subnet address in {}", subnet))?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match parts.next() {
+            Some(len) => len
+                .parse()
+                .ok()
+                .filter(|len| *len <= max_len)
+                .ok_or(format!("Invalid subnet prefix length in {}", subnet))?,
+            None => max_len,
+        };
+        Ok(Subnet { addr, prefix_len })
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(*ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(*ip) & mask
+            }
+            // Different families never match
+            _ => false,
+        }
+    }
+}
+
+// Lower and upper bounds for TTLs of cached and synthesized records
+#[derive(Deserialize, Serialize, Clone, Copy, Default)]
+pub struct TtlClamp {
+    #[serde(default)]
+    pub min: u32,
+    #[serde(default)]
+    pub max: Option<u32>,
+}
+
+impl TtlClamp {
+    pub fn apply(&self, ttl: u32) -> u32 {
+        let ttl = u32::max(ttl, self.min);
+        match self.max {
+            Some(max) => u32::min(ttl, max),
+            None => ttl,
+        }
+    }
+}
+
 // Shorthand for a fully-owned AllRecordData variant
 pub type OwnedRecordData = AllRecordData<Vec<u8>, Dname<Vec<u8>>>;
 
@@ -100,11 +689,35 @@ pub fn to_owned_record_data<T: AsRef<[u8]>, U: AsRef<[u8]>>(
             data.port(),
             data.target().to_vec(),
         ))),
-        // Unimplemented / Unrecognized records
-        _ => Err("Unsupported record type".to_string()),
+        // Keep everything else as generic record data (RFC 3597), so that
+        // records we don't handle specially are still passed through
+        _ => {
+            let mut buf: Vec<u8> = Vec::new();
+            data.compose(&mut buf)
+                .map_err(|_| "Cannot convert record data".to_string())?;
+            Ok(AllRecordData::Other(UnknownRecordData::from_octets(
+                data.rtype(),
+                buf,
+            )))
+        }
     }
 }
 
+// Build TXT record data out of an arbitrary string
+// splitting it into character-strings of at most 255 octets each
+pub fn text_to_txt(text: &str) -> Result<Txt<Vec<u8>>, String> {
+    let mut buf: Vec<u8> = Vec::new();
+    for chunk in text.as_bytes().chunks(255) {
+        buf.push(chunk.len() as u8);
+        buf.extend_from_slice(chunk);
+    }
+    if buf.is_empty() {
+        // A TXT record must contain at least one (empty) character-string
+        buf.push(0);
+    }
+    Txt::from_slice(&buf).map_err(|_| "Cannot build TXT record".to_string())
+}
+
 // Convert owned record data to Vec buffer
 pub fn owned_record_data_to_buffer(data: &OwnedRecordData) -> Result<Vec<u8>, String> {
     let mut ret: Vec<u8> = Vec::new();
@@ -121,3 +734,170 @@ pub fn octets_to_owned_record_data(rtype: Rtype, octets: &[u8]) -> Result<OwnedR
             .ok_or("Given record data parsed to nothing".to_string())?;
     to_owned_record_data(&parsed)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_qtype_numeric() {
+        assert_eq!(parse_qtype("1").unwrap(), Rtype::A);
+        assert_eq!(parse_qtype("28").unwrap(), Rtype::Aaaa);
+        assert_eq!(parse_qtype("65").unwrap().to_int(), 65);
+    }
+
+    #[test]
+    fn parse_qtype_mnemonic() {
+        assert_eq!(parse_qtype("AAAA").unwrap(), Rtype::Aaaa);
+        assert_eq!(parse_qtype("mx").unwrap(), Rtype::Mx);
+        assert_eq!(parse_qtype(" Txt ").unwrap(), Rtype::Txt);
+        assert_eq!(parse_qtype("").unwrap(), Rtype::A);
+        assert_eq!(parse_qtype("HTTPS").unwrap().to_int(), 65);
+    }
+
+    #[test]
+    fn parse_qtype_generic() {
+        assert_eq!(parse_qtype("TYPE28").unwrap(), Rtype::Aaaa);
+        assert_eq!(parse_qtype("type65280").unwrap().to_int(), 65280);
+    }
+
+    #[test]
+    fn parse_qtype_invalid() {
+        assert!(parse_qtype("BOGUS").is_err());
+        assert!(parse_qtype("TYPE").is_err());
+        assert!(parse_qtype("TYPE65536").is_err());
+        assert!(parse_qtype("-1").is_err());
+    }
+
+    #[test]
+    fn rtype_to_string_round_trips() {
+        for rtype in [
+            Rtype::A,
+            Rtype::Aaaa,
+            Rtype::Mx,
+            Rtype::Txt,
+            Rtype::Any,
+            Rtype::Axfr,
+            Rtype::from_int(65),
+            Rtype::from_int(65280),
+        ]
+        .iter()
+        {
+            assert_eq!(parse_qtype(&rtype_to_string(*rtype)).unwrap(), *rtype);
+        }
+        // Known types get their mnemonic even when built from the number,
+        // unknown ones the generic form
+        assert_eq!(rtype_to_string(Rtype::Int(28)), "AAAA");
+        assert_eq!(rtype_to_string(Rtype::from_int(65280)), "TYPE65280");
+    }
+
+    fn lowest() -> f64 {
+        0.0
+    }
+
+    fn highest() -> f64 {
+        1.0 - f64::EPSILON
+    }
+
+    #[test]
+    fn random_range_stays_within_bounds() {
+        set_random(lowest);
+        assert_eq!(random_range(10u16, 20u16), 10);
+        assert_eq!(random_range(10u32, 20u32), 10);
+        assert_eq!(random_range(10u64, 20u64), 10);
+        assert_eq!(random_range(10usize, 20usize), 10);
+
+        set_random(highest);
+        assert_eq!(random_range(10u16, 20u16), 19);
+        assert_eq!(random_range(10u32, 20u32), 19);
+        assert_eq!(random_range(10u64, 20u64), 19);
+        assert_eq!(random_range(10usize, 20usize), 19);
+        assert_eq!(random_range(0u32, u32::MAX), u32::MAX - 1);
+    }
+
+    #[test]
+    fn constant_time_eq_compares_whole_secrets() {
+        assert!(constant_time_eq(b"hunter2", b"hunter2"));
+        assert!(!constant_time_eq(b"hunter2", b"hunter3"));
+        assert!(!constant_time_eq(b"hunter2", b"Hunter2"));
+        assert!(!constant_time_eq(b"hunter2", b"hunter"));
+        assert!(constant_time_eq(b"", b""));
+    }
+
+    #[test]
+    fn validate_name_enforces_wire_limits() {
+        assert!(validate_name("www.example.com").is_ok());
+        assert!(validate_name("www.example.com.").is_ok());
+        assert!(validate_name(".").is_ok());
+
+        let label63 = "a".repeat(63);
+        assert!(validate_name(&format!("{}.example", label63)).is_ok());
+        assert_eq!(
+            validate_name(&format!("{}a.example", label63)),
+            Err("Label too long in domain name".to_string())
+        );
+
+        // 4 labels of 63 octets take 4 * 64 + 1 = 257 octets on the wire,
+        // while 3 of them plus one of 61 take exactly 255
+        let name = vec![label63.as_str(); 3].join(".");
+        assert!(validate_name(&format!("{}.{}", name, "a".repeat(61))).is_ok());
+        assert_eq!(
+            validate_name(&format!("{}.{}", name, "a".repeat(62))),
+            Err("Domain name too long".to_string())
+        );
+
+        assert_eq!(
+            validate_name("www..example.com"),
+            Err("Empty label in domain name".to_string())
+        );
+        assert_eq!(
+            validate_name(".example.com"),
+            Err("Empty label in domain name".to_string())
+        );
+    }
+
+    #[test]
+    fn idn_to_ascii_encodes_unicode_labels() {
+        // RFC 3492 and well-known IDN vectors
+        assert_eq!(idn_to_ascii("bücher").unwrap(), "xn--bcher-kva");
+        assert_eq!(idn_to_ascii("münchen.de").unwrap(), "xn--mnchen-3ya.de");
+        assert_eq!(idn_to_ascii("MÜNCHEN.de").unwrap(), "xn--mnchen-3ya.de");
+        assert_eq!(idn_to_ascii("例え.jp").unwrap(), "xn--r8jz45g.jp");
+        // ASCII labels are passed through untouched
+        assert_eq!(idn_to_ascii("www.Example.com").unwrap(), "www.Example.com");
+    }
+
+    // Pending on the first poll, like a real subrequest would be
+    struct YieldOnce(bool);
+
+    impl Future for YieldOnce {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            if self.0 {
+                return Poll::Ready(());
+            }
+            self.0 = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    #[test]
+    fn for_each_concurrent_bounds_in_flight_futures() {
+        let in_flight = &Cell::new(0usize);
+        let max_in_flight = &Cell::new(0usize);
+        let done = &Cell::new(0usize);
+        block_on(for_each_concurrent(0..10, 3, |_| async move {
+            in_flight.set(in_flight.get() + 1);
+            max_in_flight.set(max_in_flight.get().max(in_flight.get()));
+            YieldOnce(false).await;
+            YieldOnce(false).await;
+            in_flight.set(in_flight.get() - 1);
+            done.set(done.get() + 1);
+        }));
+        assert_eq!(max_in_flight.get(), 3);
+        assert_eq!(in_flight.get(), 0);
+        assert_eq!(done.get(), 10);
+    }
+}
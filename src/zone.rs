@@ -0,0 +1,285 @@
+use crate::client::QueryResult;
+use crate::util::OwnedRecordData;
+use domain::base::rdata::RecordData;
+use domain::base::{
+    iana::{Class, Rcode},
+    serial::Serial,
+    Dname, Question, Record, Rtype,
+};
+use domain::rdata::{Aaaa, AllRecordData, Mx, Ns, Soa, A};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+fn default_zone_ttl() -> u32 {
+    300
+}
+
+fn default_refresh() -> u32 {
+    3600
+}
+
+fn default_retry() -> u32 {
+    600
+}
+
+fn default_expire() -> u32 {
+    86400
+}
+
+fn default_minimum() -> u32 {
+    300
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct ZoneSoaOptions {
+    mname: String,
+    rname: String,
+    #[serde(default)]
+    serial: u32,
+    #[serde(default = "default_refresh")]
+    refresh: u32,
+    #[serde(default = "default_retry")]
+    retry: u32,
+    #[serde(default = "default_expire")]
+    expire: u32,
+    // TTL of negative answers (RFC 2308)
+    #[serde(default = "default_minimum")]
+    minimum: u32,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct ZoneMxOptions {
+    preference: u16,
+    exchange: String,
+}
+
+// All records of one name in a zone
+#[derive(Deserialize, Serialize, Default)]
+pub struct ZoneNodeOptions {
+    #[serde(default)]
+    a: Vec<Ipv4Addr>,
+    #[serde(default)]
+    aaaa: Vec<Ipv6Addr>,
+    #[serde(default)]
+    mx: Vec<ZoneMxOptions>,
+    #[serde(default)]
+    txt: Vec<String>,
+}
+
+// A zone answered authoritatively, e.g.
+// `"corp.internal": {"soa": {"mname": "ns.corp.internal", "rname": "admin.corp.internal"},
+//   "ns": ["ns.corp.internal"], "records": {"@": {"a": ["10.0.0.1"]}, "www": {"a": ["10.0.0.2"]}}}`
+// Names in `records` are relative to the zone, with "@" being the apex
+#[derive(Deserialize, Serialize)]
+pub struct LocalZoneOptions {
+    soa: ZoneSoaOptions,
+    #[serde(default)]
+    ns: Vec<String>,
+    #[serde(default = "default_zone_ttl")]
+    ttl: u32,
+    #[serde(default)]
+    records: HashMap<String, ZoneNodeOptions>,
+}
+
+struct Zone {
+    // Normalized, see util::normalize_name
+    origin: String,
+    soa: Record<Dname<Vec<u8>>, OwnedRecordData>,
+    ttl: u32,
+    // Records by normalized owner name, including SOA / NS at the apex
+    nodes: HashMap<String, Vec<OwnedRecordData>>,
+}
+
+// Answers names within the configured zones fully locally, with the same
+// semantics as an authoritative server: names without records of the
+// asked type are NODATA and names not in the zone at all are NXDOMAIN,
+// both with the SOA of the zone in the authority section
+pub struct LocalZones {
+    zones: Vec<Zone>,
+}
+
+impl LocalZones {
+    pub fn new(zones: HashMap<String, LocalZoneOptions>) -> Result<LocalZones, String> {
+        let mut ret = Vec::new();
+        for (origin, options) in zones.into_iter() {
+            ret.push(
+                Self::build_zone(&origin, options)
+                    .map_err(|e| format!("Invalid local zone {}: {}", origin, e))?,
+            );
+        }
+        Ok(LocalZones { zones: ret })
+    }
+
+    fn build_zone(origin: &str, options: LocalZoneOptions) -> Result<Zone, String> {
+        let origin = crate::util::normalize_name(origin);
+        let apex: Dname<Vec<u8>> = origin.parse().map_err(|_| "Invalid origin".to_string())?;
+        let parse_name = |name: &str| -> Result<Dname<Vec<u8>>, String> {
+            name.parse().map_err(|_| format!("Invalid name {}", name))
+        };
+
+        let soa = &options.soa;
+        let soa_rdata = AllRecordData::Soa(Soa::new(
+            parse_name(&soa.mname)?,
+            parse_name(&soa.rname)?,
+            Serial(soa.serial),
+            soa.refresh,
+            soa.retry,
+            soa.expire,
+            soa.minimum,
+        ));
+        let mut apex_records = vec![soa_rdata.clone()];
+        for ns in options.ns.iter() {
+            apex_records.push(AllRecordData::Ns(Ns::new(parse_name(ns)?)));
+        }
+
+        let mut nodes = HashMap::new();
+        nodes.insert(origin.clone(), apex_records);
+        for (name, node) in options.records.into_iter() {
+            let name = crate::util::normalize_name(&name);
+            let name = if name == "@" {
+                origin.clone()
+            } else {
+                format!("{}.{}", name, origin)
+            };
+            parse_name(&name)?;
+            let records = nodes.entry(name).or_insert_with(Vec::new);
+            for addr in node.a {
+                records.push(AllRecordData::A(A::new(addr)));
+            }
+            for addr in node.aaaa {
+                records.push(AllRecordData::Aaaa(Aaaa::new(addr)));
+            }
+            for mx in node.mx {
+                records.push(AllRecordData::Mx(Mx::new(
+                    mx.preference,
+                    parse_name(&mx.exchange)?,
+                )));
+            }
+            for txt in node.txt {
+                records.push(AllRecordData::Txt(crate::util::text_to_txt(&txt)?));
+            }
+        }
+
+        Ok(Zone {
+            origin,
+            // The negative caching TTL is the minimum of the SOA TTL and
+            // its minimum field, so just use the latter for both
+            soa: Record::new(apex, Class::In, options.soa.minimum, soa_rdata),
+            ttl: options.ttl,
+            nodes,
+        })
+    }
+
+    // Returns None if the name is not within any of the zones
+    pub fn try_resolve(&self, question: &Question<Dname<Vec<u8>>>) -> Option<QueryResult> {
+        let name = crate::util::normalize_name(&question.qname().to_string());
+        let zone = self.find_zone(&name)?;
+
+        let (rcode, answers) = match zone.nodes.get(&name) {
+            Some(records) => (
+                Rcode::NoError,
+                records
+                    .iter()
+                    .filter(|r| question.qtype() == Rtype::Any || r.rtype() == question.qtype())
+                    .map(|r| {
+                        Record::new(
+                            question.qname().clone(),
+                            question.qclass(),
+                            zone.ttl,
+                            r.clone(),
+                        )
+                    })
+                    .collect(),
+            ),
+            // Names with records somewhere below them exist, just without
+            // records of their own (empty non-terminals)
+            None if zone
+                .nodes
+                .keys()
+                .any(|k| k.ends_with(&format!(".{}", name))) =>
+            {
+                (Rcode::NoError, Vec::new())
+            }
+            None => (Rcode::NXDomain, Vec::new()),
+        };
+        let authority = if answers.len() == 0 {
+            vec![zone.soa.clone()]
+        } else {
+            Vec::new()
+        };
+        Some(QueryResult::new(rcode, answers, authority))
+    }
+
+    // The innermost zone containing the name
+    fn find_zone(&self, name: &str) -> Option<&Zone> {
+        self.zones
+            .iter()
+            .filter(|z| z.origin == name || name.ends_with(&format!(".{}", z.origin)))
+            .max_by_key(|z| z.origin.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn zones() -> LocalZones {
+        LocalZones::new(
+            serde_json::from_str(
+                r#"{"corp.internal": {
+                    "soa": {"mname": "ns.corp.internal", "rname": "admin.corp.internal"},
+                    "records": {"www": {"a": ["10.0.0.2"]}, "a.b": {"a": ["10.0.0.3"]}}
+                }}"#,
+            )
+            .unwrap(),
+        )
+        .unwrap()
+    }
+
+    fn resolve(name: &str, rtype: Rtype) -> Option<QueryResult> {
+        zones().try_resolve(&Question::new(name.parse().unwrap(), rtype, Class::In))
+    }
+
+    fn assert_soa_authority(result: &QueryResult) {
+        assert_eq!(result.authority.len(), 1);
+        assert_eq!(result.authority[0].rtype(), Rtype::Soa);
+        assert_eq!(result.authority[0].owner().to_string(), "corp.internal");
+    }
+
+    #[test]
+    fn existing_name_is_answered() {
+        let result = resolve("WWW.corp.internal", Rtype::A).unwrap();
+        assert_eq!(result.rcode, Rcode::NoError);
+        assert_eq!(result.answers.len(), 1);
+        assert_eq!(result.answers[0].rtype(), Rtype::A);
+        assert!(result.authority.is_empty());
+    }
+
+    #[test]
+    fn missing_type_is_nodata() {
+        let result = resolve("www.corp.internal", Rtype::Aaaa).unwrap();
+        assert_eq!(result.rcode, Rcode::NoError);
+        assert!(result.answers.is_empty());
+        assert_soa_authority(&result);
+
+        // Empty non-terminals exist as well
+        let result = resolve("b.corp.internal", Rtype::A).unwrap();
+        assert_eq!(result.rcode, Rcode::NoError);
+        assert_soa_authority(&result);
+    }
+
+    #[test]
+    fn missing_name_is_nxdomain() {
+        let result = resolve("nope.corp.internal", Rtype::A).unwrap();
+        assert_eq!(result.rcode, Rcode::NXDomain);
+        assert!(result.answers.is_empty());
+        assert_soa_authority(&result);
+    }
+
+    #[test]
+    fn names_outside_zones_are_not_resolved() {
+        assert!(resolve("www.example.com", Rtype::A).is_none());
+        assert!(resolve("notcorp.internal", Rtype::A).is_none());
+    }
+}